@@ -2,6 +2,7 @@ use futures_util::future::TryFutureExt;
 use std::collections::HashSet;
 use std::str::FromStr;
 
+use tiny_cash::chain_spec::ChainSpec;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tower::{Service, ServiceExt};
@@ -33,6 +34,7 @@ pub struct CompactTxStreamerImpl<R> {
     pub signer_pk: String,
     pub inputbox_contract_address: String,
     pub dapp_address: String,
+    pub chain_spec: ChainSpec,
 }
 
 impl<R> CompactTxStreamerImpl<R> {
@@ -43,6 +45,7 @@ impl<R> CompactTxStreamerImpl<R> {
         signer_pk: String,
         inputbox_contract_address: String,
         dapp_address: String,
+        chain_spec: ChainSpec,
     ) -> Self {
         Self {
             state_read_service,
@@ -51,6 +54,7 @@ impl<R> CompactTxStreamerImpl<R> {
             signer_pk,
             inputbox_contract_address,
             dapp_address,
+            chain_spec,
         }
     }
 }
@@ -97,7 +101,9 @@ where
         contract
             .add_input(
                 Address::from_str(&self.dapp_address).unwrap(),
-                Bytes::from(request.get_ref().data.clone()),
+                Bytes::from(tiny_cash::envelope::encode_transaction(
+                    &request.get_ref().data,
+                )),
             )
             .send()
             .await
@@ -409,9 +415,9 @@ where
             version: env!("CARGO_PKG_VERSION").to_string(),
             vendor: "Wollum".to_string(),
             taddr_support: true,
-            chain_name: "mainnet".to_string(),
+            chain_name: self.chain_spec.chain_name().to_string(),
             sapling_activation_height: 0,
-            consensus_branch_id: String::new(),
+            consensus_branch_id: self.chain_spec.consensus_branch_id().to_string(),
             block_height,
             git_commit: String::new(),
             branch: String::new(),
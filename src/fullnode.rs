@@ -0,0 +1,170 @@
+//! The zebra-state the wallet gRPC server reads from.
+//!
+//! zebra-state has no way to remove blocks from its best chain. A replacement block at the same height never
+//! has more work than the one it replaces, so committing it as a fork does not switch chains. When TinyCash is
+//! rolled back the state is therefore rebuilt from genesis up to the rollback height and swapped in for the
+//! old one. The gRPC server keeps a [`ReadState`] which always reads from the current state.
+
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use futures_util::future::{BoxFuture, FutureExt};
+use tiny_cash::block::{Block, Height};
+use tiny_cash::parameters::Network;
+use tower::{buffer::Buffer, util::BoxService, BoxError, Service, ServiceExt};
+use zebra_state::{HashOrHeight, ReadRequest, ReadResponse};
+
+type StateService = Buffer<
+    BoxService<zebra_state::Request, zebra_state::Response, zebra_state::BoxError>,
+    zebra_state::Request,
+>;
+
+type ReadStateService =
+    Buffer<BoxService<ReadRequest, ReadResponse, zebra_state::BoxError>, ReadRequest>;
+
+#[derive(Clone)]
+struct Services {
+    state: StateService,
+    read: ReadStateService,
+}
+
+impl Services {
+    fn ephemeral(network: Network) -> Self {
+        let (state, read, _, _) =
+            zebra_state::init(zebra_state::Config::ephemeral(), network, Height::MAX, 0);
+        Self {
+            state: Buffer::new(state, 30),
+            read: Buffer::new(read.boxed(), 30),
+        }
+    }
+
+    /// Genesis is committed as checkpoint verified as it has no parent
+    async fn commit_genesis(&mut self, block: Arc<Block>) -> Result<(), BoxError> {
+        self.state
+            .ready()
+            .await?
+            .call(zebra_state::Request::CommitCheckpointVerifiedBlock(
+                zebra_state::CheckpointVerifiedBlock::from(block),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn commit(
+        &mut self,
+        block: zebra_state::SemanticallyVerifiedBlock,
+    ) -> Result<(), BoxError> {
+        self.state
+            .ready()
+            .await?
+            .call(zebra_state::Request::CommitSemanticallyVerifiedBlock(block))
+            .await?;
+        Ok(())
+    }
+}
+
+/// The fullnode state. Clones share the same state
+#[derive(Clone)]
+pub struct FullnodeState {
+    network: Network,
+    services: Arc<RwLock<Services>>,
+}
+
+impl FullnodeState {
+    /// An empty in-memory state
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            services: Arc::new(RwLock::new(Services::ephemeral(network))),
+        }
+    }
+
+    /// A read service that follows the state across rollbacks
+    pub fn read_service(&self) -> ReadState {
+        ReadState(self.services.clone())
+    }
+
+    fn services(&self) -> Services {
+        self.services.read().unwrap().clone()
+    }
+
+    pub async fn commit_genesis(&self, block: Arc<Block>) -> Result<(), BoxError> {
+        tracing::info!(
+            "committing GENESIS block {} to the fullnode state",
+            block.hash()
+        );
+        self.services().commit_genesis(block).await
+    }
+
+    /// Commit a block produced by TinyCash
+    pub async fn commit(
+        &self,
+        block: zebra_state::SemanticallyVerifiedBlock,
+    ) -> Result<(), BoxError> {
+        tracing::info!(
+            "committing block {} at height {:?}",
+            block.hash,
+            block.height
+        );
+        self.services().commit(block).await
+    }
+
+    /// Replace the state with one holding only the blocks up to and including `height`.
+    /// Must be called after TinyCash has been rolled back to the same height
+    pub async fn rollback_to(&self, height: Height) -> Result<(), BoxError> {
+        tracing::info!("rebuilding the fullnode state up to height {:?}", height);
+        let old = self.services();
+        let mut new = Services::ephemeral(self.network);
+        for block_height in (0..=height.0).map(Height) {
+            let block = match old
+                .read
+                .clone()
+                .oneshot(ReadRequest::Block(HashOrHeight::Height(block_height)))
+                .await?
+            {
+                ReadResponse::Block(Some(block)) => block,
+                _ => {
+                    return Err(format!("fullnode state has no block at {:?}", block_height).into())
+                }
+            };
+            if block_height == Height(0) {
+                new.commit_genesis(block).await?;
+                continue;
+            }
+            let transaction_hashes: Arc<[_]> =
+                block.transactions.iter().map(|t| t.hash()).collect();
+            // the same outputs TinyCash committed the block with
+            let new_outputs =
+                tiny_cash::spendable_outputs(&block, block_height, &transaction_hashes);
+            new.commit(zebra_state::SemanticallyVerifiedBlock {
+                hash: block.hash(),
+                block,
+                height: block_height,
+                new_outputs,
+                transaction_hashes,
+            })
+            .await?;
+        }
+        *self.services.write().unwrap() = new;
+        Ok(())
+    }
+}
+
+/// Reads from whichever state is current. See [`FullnodeState::read_service`]
+#[derive(Clone)]
+pub struct ReadState(Arc<RwLock<Services>>);
+
+impl Service<ReadRequest> for ReadState {
+    type Response = ReadResponse;
+    type Error = zebra_state::BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ReadRequest) -> Self::Future {
+        let read = self.0.read().unwrap().read.clone();
+        read.oneshot(req).boxed()
+    }
+}
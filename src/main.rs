@@ -1,19 +1,24 @@
-use service::{CarteZcashService, Request};
+use service::{
+    CarteZcashService, DeploymentConfig, DepositError, InputConfig, InputHistory, Payouts,
+    RejectedBurns, Request,
+};
+use tiny_cash::chain_spec::ChainSpec;
+use tiny_cash::envelope::{AdminCommand, EnvelopeError};
+use tiny_cash::parameters::Network;
 use zcash_keys::address::UnifiedAddress;
-use zcash_primitives::consensus::MAIN_NETWORK;
+use zcash_primitives::consensus::{MAIN_NETWORK, TEST_NETWORK};
 
 use std::env;
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use tower::{buffer::Buffer, util::BoxService, BoxError, Service, ServiceExt};
 use tower_cartesi::{Request as RollAppRequest, Response};
 
 use futures_util::future::FutureExt;
 
-const DAPP_RELAY_CONTRACT_ADDRESS: &str = "f5de34d6bbc0446e2a45719e718efebaae179dae";
-
 #[cfg(feature = "lightwalletd")]
 use cartezcash_lightwalletd::{
     proto::service::compact_tx_streamer_server::CompactTxStreamerServer,
@@ -21,11 +26,15 @@ use cartezcash_lightwalletd::{
 };
 
 #[cfg(feature = "lightwalletd")]
-type StateService = Buffer<
-    BoxService<zebra_state::Request, zebra_state::Response, zebra_state::BoxError>,
-    zebra_state::Request,
+use fullnode::FullnodeState;
+
+type TinyCashService = Buffer<
+    BoxService<tiny_cash::service::Request, tiny_cash::service::Response, BoxError>,
+    tiny_cash::service::Request,
 >;
 
+#[cfg(feature = "lightwalletd")]
+mod fullnode;
 mod service;
 
 #[tokio::main]
@@ -38,11 +47,21 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let server_addr = env::var("ROLLUP_HTTP_SERVER_URL")?;
 
+    let chain_spec = chain_spec_from_env();
+    tracing::info!(
+        "Chain name is {} with instance id {}",
+        chain_spec.chain_name(),
+        chain_spec.instance_id()
+    );
+
+    let withdraw_address =
+        UnifiedAddress::from_receivers(Some(tiny_cash::mt_doom_address()), None).unwrap();
     println!(
         "Withdraw address is: {}",
-        UnifiedAddress::from_receivers(Some(tiny_cash::mt_doom_address()), None)
-            .unwrap()
-            .encode(&MAIN_NETWORK)
+        match chain_spec.network() {
+            Network::Testnet => withdraw_address.encode(&TEST_NETWORK),
+            _ => withdraw_address.encode(&MAIN_NETWORK),
+        }
     );
 
     // TODO: Enable this when not debugging
@@ -50,34 +69,46 @@ async fn main() -> Result<(), anyhow::Error> {
     {
         tracing::info!("Initializing Halo2 verifier key");
         tiny_cash::initialize_halo2();
+        check_halo2_verifying_key()?;
         tracing::info!("Initializing Halo2 verifier key complete");
     }
 
     #[cfg(feature = "lightwalletd")]
-    let (state_service, state_read_service, _, _) = zebra_state::init(
-        zebra_state::Config::ephemeral(),
-        tiny_cash::parameters::Network::Mainnet,
-        tiny_cash::block::Height::MAX,
-        0,
+    let fullnode_state = FullnodeState::new(chain_spec.network());
+
+    let deployment = DeploymentConfig::load()?;
+    tracing::info!("Deployment config is {:?}", deployment);
+
+    let input_config = input_config_from_env(chain_spec.network(), &deployment)?;
+    tracing::info!(
+        "Bridging {} with {} decimals",
+        input_config.bridge,
+        input_config.decimals.decimals()
     );
 
     let mut cartezcash_app = CarteZcashApp::new(
+        tinycash_config_from_env(chain_spec.clone(), &deployment)?,
+        input_config,
+        deployment.dapp_address_relay,
         #[cfg(feature = "lightwalletd")]
-        Buffer::new(state_service, 30),
+        fullnode_state.clone(),
     )
     .await;
 
     #[cfg(feature = "lightwalletd")]
     {
         let grpc_addr = env::var("GRPC_SERVER_URL")?;
-        let state_read_service = Buffer::new(state_read_service.boxed(), 30);
         let svc = CompactTxStreamerServer::new(CompactTxStreamerImpl::new(
-            state_read_service,
+            fullnode_state.read_service(),
             env::var("ETH_RPC_URL")?,
-            env::var("ETH_CHAIN_ID").map(|s| s.parse::<u64>().unwrap())?,
+            deployment.chain_id.expect("validated for the fullnode"),
             env::var("SIGNER_PK")?,
-            env::var("INPUTBOX_CONTRACT_ADDRESS")?,
-            env::var("DAPP_ADDRESS")?,
+            format!("{:?}", deployment.input_box),
+            format!(
+                "{:?}",
+                deployment.dapp_address.expect("validated for the fullnode")
+            ),
+            chain_spec,
         ));
         let addr = grpc_addr.parse()?;
         let grpc_server = tonic::transport::Server::builder()
@@ -98,6 +129,7 @@ async fn main() -> Result<(), anyhow::Error> {
         &mut cartezcash_app,
         &server_addr,
         10,
+        tiny_cash::service::DEFAULT_ROLLBACK_DEPTH as usize,
         std::time::Duration::from_secs(5),
     )
     .await
@@ -106,33 +138,227 @@ async fn main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Check the Orchard verifying key built at startup against the hex digest pinned by ORCHARD_VK_DIGEST.
+/// The node refuses to start if they differ. If not set the digest is only logged so it can be pinned
+#[cfg(feature = "preinitialize-halo2")]
+fn check_halo2_verifying_key() -> Result<(), anyhow::Error> {
+    let digest = hex::encode(tiny_cash::halo2_verifying_key_digest());
+    match env::var("ORCHARD_VK_DIGEST") {
+        Ok(pinned) if pinned.trim_start_matches("0x").to_lowercase() != digest => {
+            Err(anyhow::anyhow!(
+                "Orchard verifying key digest {} does not match ORCHARD_VK_DIGEST {}",
+                digest,
+                pinned
+            ))
+        }
+        Ok(_) => {
+            tracing::info!(
+                "Orchard verifying key digest {} matches ORCHARD_VK_DIGEST",
+                digest
+            );
+            Ok(())
+        }
+        Err(_) => {
+            tracing::warn!(
+                "ORCHARD_VK_DIGEST is not set. The Orchard verifying key digest is {}",
+                digest
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Read the chain name of this instance from CHAIN_NAME (see docs/chainspec.md).
+/// If not set a local development chain is used
+fn chain_spec_from_env() -> ChainSpec {
+    match env::var("CHAIN_NAME") {
+        Ok(chain_name) => ChainSpec::new(chain_name),
+        Err(_) => ChainSpec::default(),
+    }
+}
+
+/// Read how inputs are interpreted from the deployment config and the environment.
+/// DEPOSIT_CAP is the largest deposit in zatoshis that will be minted, larger deposits are refunded.
+/// ADMIN_ADDRESS is the L1 address admin commands are accepted from.
+/// If the deployment has a chain id withdrawals with a burn memo for another chain are not paid out
+fn input_config_from_env(
+    network: Network,
+    deployment: &DeploymentConfig,
+) -> Result<InputConfig, anyhow::Error> {
+    let deposit_cap = env::var("DEPOSIT_CAP")
+        .ok()
+        .map(|cap| cap.parse::<u64>())
+        .transpose()?
+        .map(tiny_cash::Amount::try_from)
+        .transpose()?;
+
+    Ok(InputConfig {
+        network,
+        bridge: deployment.bridge(),
+        ether_portal: deployment.ether_portal,
+        erc20_portal: deployment.erc20_portal,
+        deposit_cap,
+        decimals: deployment.decimals,
+        chain_id: deployment.chain_id,
+        admin: env::var("ADMIN_ADDRESS")
+            .ok()
+            .map(|admin| admin.parse())
+            .transpose()?,
+    })
+}
+
+/// Read the TinyCash settings from the environment.
+/// FEE_POLICY is one of `none` (default), `zip317` or `flat:<zatoshis>` and FEE_RECIPIENT is a transparent address.
+/// The dApp address of the deployment, if known, is committed to in the genesis block.
+/// Undo records are only kept when listening to the graphql API, the only listener that can see reverted inputs
+fn tinycash_config_from_env(
+    chain_spec: ChainSpec,
+    deployment: &DeploymentConfig,
+) -> Result<tiny_cash::service::Config, anyhow::Error> {
+    let fee_policy = env::var("FEE_POLICY")
+        .ok()
+        .map(|policy| policy.parse())
+        .transpose()
+        .map_err(anyhow::Error::msg)?
+        .unwrap_or_default();
+    let fee_recipient = env::var("FEE_RECIPIENT")
+        .ok()
+        .map(|address| address.parse::<tiny_cash::transparent::Address>())
+        .transpose()?
+        .map(|address| address.create_script_from_address());
+
+    Ok(tiny_cash::service::Config {
+        fee_policy,
+        fee_recipient,
+        chain_spec,
+        dapp_address: deployment.dapp_address.map(|address| address.0),
+        #[cfg(feature = "listen-graphql")]
+        rollback_depth: tiny_cash::service::DEFAULT_ROLLBACK_DEPTH,
+        ..Default::default()
+    })
+}
+
 struct CarteZcashApp {
     cartezcash:
         Buffer<BoxService<Request, service::Response, Box<dyn Error + Sync + Send>>, Request>,
+    tinycash: TinyCashService,
     #[cfg(feature = "lightwalletd")]
-    state_service: StateService,
-    dapp_address: Option<ethereum_types::Address>,
+    fullnode_state: FullnodeState,
+    payouts: Payouts,
+    /// Shared so a rollback can restore it once the chain state has been rolled back
+    input_config: Arc<Mutex<InputConfig>>,
+    /// The state before each recent input, to roll back to if the node reverts it
+    history: Arc<Mutex<InputHistory>>,
+    /// Inputs from this address relay the dApp address
+    dapp_address_relay: ethereum_types::Address,
 }
 
 impl CarteZcashApp {
-    pub async fn new(#[cfg(feature = "lightwalletd")] mut state_service: StateService) -> Self {
+    pub async fn new(
+        config: tiny_cash::service::Config,
+        input_config: InputConfig,
+        dapp_address_relay: ethereum_types::Address,
+        #[cfg(feature = "lightwalletd")] fullnode_state: FullnodeState,
+    ) -> Self {
+        // the relay input can still set or replace this later
+        let dapp_address = config.dapp_address.map(ethereum_types::Address::from);
+        // each input adds at most one block, so the app can undo as many inputs as TinyCash can undo blocks
+        let history = InputHistory::new(config.rollback_depth as usize);
+
         // set up the services needed to run the rollup
-        let mut tinycash = Buffer::new(BoxService::new(tiny_cash::service::TinyCash::new()), 10);
+        let mut tinycash: TinyCashService = Buffer::new(
+            BoxService::new(tiny_cash::service::TinyCash::with_config(config)),
+            10,
+        );
 
         initialize_network(
             &mut tinycash,
             #[cfg(feature = "lightwalletd")]
-            &mut state_service,
+            &fullnode_state,
         )
         .await
         .unwrap();
 
         Self {
-            cartezcash: Buffer::new(BoxService::new(CarteZcashService::new(tinycash)), 10),
+            cartezcash: Buffer::new(
+                BoxService::new(CarteZcashService::new(tinycash.clone(), input_config)),
+                10,
+            ),
+            tinycash,
             #[cfg(feature = "lightwalletd")]
-            state_service: state_service,
-            dapp_address: None,
+            fullnode_state,
+            payouts: Payouts::new(input_config.bridge, dapp_address),
+            input_config: Arc::new(Mutex::new(input_config)),
+            history: Arc::new(Mutex::new(history)),
+            dapp_address_relay,
+        }
+    }
+}
+
+impl CarteZcashApp {
+    /// Apply a command sent by the admin address
+    fn apply_admin_command(&mut self, command: AdminCommand) -> Response {
+        tracing::info!("Applying admin command {:?}", command);
+        match command {
+            AdminCommand::SetDepositCap(cap) => self.input_config.lock().unwrap().deposit_cap = cap,
+        }
+        let mut resp = tower_cartesi::Response::empty_accept();
+        resp.add_report(format!("applied admin command {:?}", command).as_bytes());
+        resp
+    }
+
+    /// Restore the state from before the input with this index, which the node reverted
+    fn rollback(
+        &mut self,
+        input_index: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Response, Box<dyn Error + Send + Sync>>> + Send>> {
+        let (tip_height, saved) = {
+            let history = self.history.lock().unwrap();
+            (
+                history.tip_height(),
+                history
+                    .saved_before(input_index)
+                    .map(|saved| saved.cloned()),
+            )
+        };
+        let saved = match saved {
+            Ok(Some(saved)) => saved,
+            Ok(None) => {
+                tracing::info!("No inputs were handled since input {}", input_index);
+                return async { Ok(tower_cartesi::Response::empty_accept()) }.boxed();
+            }
+            Err(e) => return async move { Err(e.into()) }.boxed(),
+        };
+        tracing::info!(
+            "Rolling back to before input {} at height {:?}",
+            input_index,
+            saved.tip_height
+        );
+
+        let mut tinycash = self.tinycash.clone();
+        #[cfg(feature = "lightwalletd")]
+        let fullnode_state = self.fullnode_state.clone();
+        let input_config = self.input_config.clone();
+        let history = self.history.clone();
+        let payouts = self.payouts.clone();
+        async move {
+            // inputs that didn't add a block leave TinyCash unchanged
+            if saved.tip_height != tip_height {
+                tinycash
+                    .ready()
+                    .await?
+                    .call(tiny_cash::service::Request::RollbackTo(saved.tip_height))
+                    .await?;
+                #[cfg(feature = "lightwalletd")]
+                fullnode_state.rollback_to(saved.tip_height).await?;
+            }
+            // the app state is only restored once the chain state has been
+            history.lock().unwrap().rewind(input_index)?;
+            *input_config.lock().unwrap() = saved.input_config;
+            payouts.restore(saved.payouts);
+            Ok(tower_cartesi::Response::empty_accept())
         }
+        .boxed()
     }
 }
 
@@ -148,60 +374,86 @@ impl Service<RollAppRequest> for CarteZcashApp {
     fn call(&mut self, req: RollAppRequest) -> Self::Future {
         match req {
             RollAppRequest::AdvanceState { metadata, payload } => {
+                let input_config = *self.input_config.lock().unwrap();
+                self.history.lock().unwrap().record(
+                    metadata.input_index,
+                    input_config,
+                    self.payouts.save(),
+                );
+
                 // if sent by this address the message is relaying the dApp address. Handle accordingly
-                if metadata.msg_sender
-                    == ethereum_types::Address::from_slice(
-                        &hex::decode(DAPP_RELAY_CONTRACT_ADDRESS).unwrap(),
-                    )
-                {
+                if metadata.msg_sender == self.dapp_address_relay {
                     let dapp_address = ethereum_types::Address::from_slice(&payload);
-                    tracing::info!("Received dapp address: {:?}", dapp_address);
-                    self.dapp_address = Some(dapp_address);
-                    return async { Ok(tower_cartesi::Response::empty_accept()) }.boxed();
+                    tracing::info!(
+                        "Received dapp address: {:?}. Paying {} queued payments",
+                        dapp_address,
+                        self.payouts.pending()
+                    );
+                    // pay everything that was waiting for the address
+                    let mut resp = tower_cartesi::Response::empty_accept();
+                    self.payouts.set_dapp_address(&mut resp, dapp_address);
+                    return async { Ok(resp) }.boxed();
                 }
 
+                let czk_request = match Request::try_from((metadata, payload, &input_config)) {
+                    Ok(Request::Admin(command)) => {
+                        let resp = self.apply_admin_command(command);
+                        return async { Ok(resp) }.boxed();
+                    }
+                    Ok(request) => request,
+                    Err(e) => {
+                        let resp = invalid_input_response(e, &self.payouts);
+                        return async { resp }.boxed();
+                    }
+                };
+
                 let mut cartezcash_service = self.cartezcash.clone();
 
                 #[cfg(feature = "lightwalletd")]
-                let mut state_service = self.state_service.clone();
-                let dapp_address = self.dapp_address.clone();
+                let fullnode_state = self.fullnode_state.clone();
+                let history = self.history.clone();
+                let payouts = self.payouts.clone();
+                let bridge = input_config.bridge;
                 async move {
-                    let czk_request = Request::try_from((metadata, payload))?;
-
-                    let response = cartezcash_service
+                    let response = match cartezcash_service
                         .ready()
                         .await?
                         .call(czk_request.clone())
-                        .await?;
+                        .await
+                    {
+                        Ok(response) => response,
+                        Err(e) => {
+                            return failed_request_response(&payouts, bridge, &czk_request, e)
+                        }
+                    };
+
+                    history.lock().unwrap().set_tip(response.block.height);
 
                     #[cfg(feature = "lightwalletd")]
-                    {
-                        tracing::info!(
-                            "committing block {} at height {:?}",
-                            response.block.hash,
-                            response.block.height
+                    fullnode_state.commit(response.block.clone()).await?;
+                    let mut resp = tower_cartesi::Response::empty_accept();
+                    resp.add_notice(&encode_block_notice(
+                        response.block.height,
+                        response.block.hash,
+                        response.state_root,
+                        response.nullifier_root,
+                    ));
+                    if let Request::Deposit { sender, dust, .. } = czk_request {
+                        if !dust.is_zero() {
+                            tracing::info!("Refunding deposit dust of {} to {:?}", dust, sender);
+                            payouts.pay(&mut resp, sender, dust);
+                        }
+                    }
+                    add_rejected_burn_reports(&mut resp, &response.rejected_burns);
+                    for (zatoshis, e) in response.invalid_withdrawals {
+                        resp.add_report(
+                            format!("burn of {} zatoshis can't be withdrawn: {}", zatoshis, e)
+                                .as_bytes(),
                         );
-                        state_service
-                            .ready()
-                            .await?
-                            .call(zebra_state::Request::CommitSemanticallyVerifiedBlock(
-                                response.block,
-                            ))
-                            .await?;
                     }
-                    let mut resp = tower_cartesi::Response::empty_accept();
                     for (recipient, amount) in response.withdrawals {
                         tracing::info!("Withdrawal: to {:?} with amount: {:?}", recipient, amount);
-                        if let Some(dapp_address) = dapp_address {
-                            resp.add_voucher(
-                                dapp_address,
-                                &encode_withdraw_call(recipient, amount),
-                            );
-                        } else {
-                            tracing::error!(
-                                "Withdrawal made before dapp address set. Funds are lost."
-                            );
-                        }
+                        payouts.pay(&mut resp, recipient, amount);
                     }
                     Ok(resp)
                 }
@@ -211,13 +463,123 @@ impl Service<RollAppRequest> for CarteZcashApp {
                 println!("Received inspect state request {:?}", payload);
                 async { Ok(tower_cartesi::Response::empty_accept()) }.boxed()
             }
+            RollAppRequest::Rollback { input_index } => self.rollback(input_index),
+        }
+    }
+}
+
+/// The response to an input that couldn't be decoded.
+/// Deposits that can't be minted are refunded, other invalid deposits and envelopes are rejected with a report
+fn invalid_input_response(
+    e: anyhow::Error,
+    payouts: &Payouts,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let e = match e.downcast::<DepositError>() {
+        Ok(DepositError::Refund {
+            sender,
+            asset,
+            value,
+            reason,
+        }) => return Ok(refund_deposit(payouts, sender, asset, value, &reason)),
+        Ok(e) => {
+            tracing::info!("Rejecting deposit: {}", e);
+            let mut resp = tower_cartesi::Response::empty_reject();
+            resp.add_report(e.to_string().as_bytes());
+            return Ok(resp);
+        }
+        Err(e) => e,
+    };
+    match e.downcast::<EnvelopeError>() {
+        Ok(e) => {
+            tracing::info!("Rejecting input: {}", e);
+            let mut resp = tower_cartesi::Response::empty_reject();
+            resp.add_report(format!("invalid input: {}", e).as_bytes());
+            Ok(resp)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Refund a deposit that can't be minted to its sender, in the asset that was deposited.
+/// The input is accepted so the voucher can be executed, with a report explaining why it was refunded
+fn refund_deposit(
+    payouts: &Payouts,
+    sender: ethereum_types::Address,
+    asset: service::Bridge,
+    value: ethereum_types::U256,
+    reason: &service::RefundReason,
+) -> Response {
+    tracing::info!(
+        "Refunding deposit of {} {} to {:?}: {}",
+        value,
+        asset,
+        sender,
+        reason
+    );
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.pay_asset(&mut resp, asset, sender, value);
+    resp.add_report(
+        format!(
+            "deposit of {} {} refunded to {:?}: {}",
+            value, asset, sender, reason
+        )
+        .as_bytes(),
+    );
+    resp
+}
+
+/// The response to a request CarteZcash failed to handle.
+/// Transactions burning coins that can't be withdrawn are rejected with a report for each, and a deposit that
+/// TinyCash failed to mint into the shielded pool is refunded so it isn't stuck in the dApp.
+/// Any other error is returned
+fn failed_request_response(
+    payouts: &Payouts,
+    bridge: service::Bridge,
+    request: &Request,
+    e: BoxError,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let e = match e.downcast::<RejectedBurns>() {
+        Ok(rejected) => {
+            let mut resp = tower_cartesi::Response::empty_reject();
+            add_rejected_burn_reports(&mut resp, &rejected.0);
+            return Ok(resp);
         }
+        Err(e) => e,
+    };
+    let Request::Deposit { sender, value, .. } = request else {
+        return Err(e);
+    };
+    match e.downcast::<tiny_cash::service::Error>() {
+        Ok(e) if matches!(*e, tiny_cash::service::Error::ShieldedMint(_)) => Ok(refund_deposit(
+            payouts,
+            *sender,
+            bridge,
+            *value,
+            &service::RefundReason::MintFailed(*e),
+        )),
+        Ok(e) => Err(e),
+        Err(e) => Err(e),
+    }
+}
+
+fn add_rejected_burn_reports(
+    resp: &mut Response,
+    rejected: &[(tiny_cash::transaction::Hash, service::WithdrawalError)],
+) {
+    for (hash, e) in rejected {
+        resp.add_report(
+            format!(
+                "transaction {} rejected as its burn can't be withdrawn: {}",
+                hash, e
+            )
+            .as_bytes(),
+        );
     }
 }
 
 async fn initialize_network<S>(
     tinycash: &mut S,
-    #[cfg(feature = "lightwalletd")] state_service: &mut StateService,
+    #[cfg(feature = "lightwalletd")] fullnode_state: &FullnodeState,
 ) -> Result<(), BoxError>
 where
     S: Service<
@@ -243,28 +605,23 @@ where
     );
 
     #[cfg(feature = "lightwalletd")]
-    state_service
-        .ready()
-        .await?
-        .call(zebra_state::Request::CommitCheckpointVerifiedBlock(
-            zebra_state::CheckpointVerifiedBlock::from(response.block.block),
-        ))
-        .await?;
+    fullnode_state.commit_genesis(response.block.block).await?;
 
     Ok(())
 }
 
-fn encode_withdraw_call(
-    recipient: ethereum_types::Address,
-    amount: ethereum_types::U256,
+/// Notice published for every block so the state an input produced can be proven on L1.
+/// ABI encoded as `(uint256 height, bytes32 blockHash, bytes32 stateRoot, bytes32 nullifierRoot)`
+fn encode_block_notice(
+    height: tiny_cash::block::Height,
+    hash: tiny_cash::block::Hash,
+    state_root: tiny_cash::state_root::StateRoot,
+    nullifier_root: [u8; 32],
 ) -> Vec<u8> {
-    let function = alloy_json_abi::Function::parse("withdrawEther(address,uint256)").unwrap();
-    let encoded_params = ethabi::encode(&[
-        ethabi::Token::Address(recipient),
-        ethabi::Token::Uint(amount),
-    ]);
-    let mut encoded = Vec::new();
-    encoded.extend_from_slice(&function.selector().as_slice());
-    encoded.extend_from_slice(&encoded_params);
-    encoded
+    ethabi::encode(&[
+        ethabi::Token::Uint(height.0.into()),
+        ethabi::Token::FixedBytes(hash.bytes_in_display_order().to_vec()),
+        ethabi::Token::FixedBytes(state_root.0.to_vec()),
+        ethabi::Token::FixedBytes(nullifier_root.to_vec()),
+    ])
 }
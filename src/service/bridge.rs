@@ -0,0 +1,62 @@
+//! The L1 asset an instance bridges and how it is deposited and withdrawn.
+//!
+//! Deposits arrive as inputs sent by the Cartesi portal for the asset. Withdrawals are paid out by vouchers
+//! executed by the dApp contract, which holds all of the deposited assets.
+
+use ethereum_types::{Address, U256};
+
+/// The L1 asset bridged into this instance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bridge {
+    /// Ether deposited through the EtherPortal and withdrawn with `withdrawEther(address,uint256)` vouchers
+    /// to the dApp contract
+    Ether,
+    /// An ERC-20 token deposited through the ERC20Portal and withdrawn with `transfer(address,uint256)` vouchers
+    /// to the token contract
+    Erc20 { token: Address },
+}
+
+impl Bridge {
+    /// The voucher destination and payload that pays `amount` of the asset to `recipient`.
+    /// None if the voucher must be sent to the dApp contract and its address isn't known yet
+    pub fn withdrawal_voucher(
+        &self,
+        dapp_address: Option<Address>,
+        recipient: Address,
+        amount: U256,
+    ) -> Option<(Address, Vec<u8>)> {
+        match self {
+            Self::Ether => dapp_address.map(|dapp_address| {
+                (
+                    dapp_address,
+                    encode_call("withdrawEther(address,uint256)", recipient, amount),
+                )
+            }),
+            Self::Erc20 { token } => Some((
+                *token,
+                encode_call("transfer(address,uint256)", recipient, amount),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Bridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ether => write!(f, "Ether"),
+            Self::Erc20 { token } => write!(f, "ERC-20 token {:?}", token),
+        }
+    }
+}
+
+fn encode_call(signature: &str, recipient: Address, amount: U256) -> Vec<u8> {
+    let function = alloy_json_abi::Function::parse(signature).unwrap();
+    let encoded_params = ethabi::encode(&[
+        ethabi::Token::Address(recipient),
+        ethabi::Token::Uint(amount),
+    ]);
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(function.selector().as_slice());
+    encoded.extend_from_slice(&encoded_params);
+    encoded
+}
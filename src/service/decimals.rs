@@ -0,0 +1,69 @@
+//! Conversion between amounts of the bridged asset and zatoshis.
+//!
+//! One whole unit of the asset (e.g. 1 ETH = 10^18 wei) is minted as one whole CarteZcash coin (10^8 zatoshis).
+//! Amounts that can't be represented exactly on the other side are returned as dust. For assets with more
+//! than 8 decimals this is the part of a deposit smaller than one zatoshi, which is refunded. For assets with
+//! fewer it is the part of a withdrawal smaller than the smallest unit of the asset, so such burns are rejected
+//! before they are included.
+
+use ethereum_types::U256;
+
+/// Decimals of a zatoshi amount
+pub const ZATOSHI_DECIMALS: u8 = 8;
+
+/// The most decimals an asset can have. This keeps every conversion of a valid amount from overflowing
+pub const MAX_DECIMALS: u8 = 36;
+
+#[derive(thiserror::Error, Debug)]
+#[error("assets with {0} decimals are not supported, the maximum is {MAX_DECIMALS}")]
+pub struct UnsupportedDecimals(pub u8);
+
+/// The number of decimals of the bridged asset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssetDecimals(u8);
+
+impl AssetDecimals {
+    pub fn new(decimals: u8) -> Result<Self, UnsupportedDecimals> {
+        if decimals > MAX_DECIMALS {
+            return Err(UnsupportedDecimals(decimals));
+        }
+        Ok(Self(decimals))
+    }
+
+    pub fn decimals(&self) -> u8 {
+        self.0
+    }
+
+    /// Convert an amount of the asset to zatoshis, returning the zatoshis and the dust in units of the asset
+    pub fn to_zatoshis(&self, value: U256) -> (U256, U256) {
+        if self.0 >= ZATOSHI_DECIMALS {
+            value.div_mod(scale(self.0 - ZATOSHI_DECIMALS))
+        } else {
+            // deposits too large to convert are larger than MAX_MONEY anyway
+            (
+                value.saturating_mul(scale(ZATOSHI_DECIMALS - self.0)),
+                U256::zero(),
+            )
+        }
+    }
+
+    /// Convert zatoshis to an amount of the asset, returning the amount and the dust in zatoshis
+    pub fn to_asset(&self, zatoshis: U256) -> (U256, U256) {
+        if self.0 >= ZATOSHI_DECIMALS {
+            (zatoshis * scale(self.0 - ZATOSHI_DECIMALS), U256::zero())
+        } else {
+            zatoshis.div_mod(scale(ZATOSHI_DECIMALS - self.0))
+        }
+    }
+}
+
+/// Ether and most ERC-20 tokens have 18 decimals
+impl Default for AssetDecimals {
+    fn default() -> Self {
+        Self(18)
+    }
+}
+
+fn scale(decimals: u8) -> U256 {
+    U256::exp10(decimals.into())
+}
@@ -0,0 +1,179 @@
+//! The L1 contracts and bridged asset of a deployment.
+//!
+//! If `DEPLOYMENT_CONFIG` is set the config is read from the JSON file it names, using the same keys as
+//! `bridge-frontend/src/config.json` so a chain's entry there can be used as is (other keys are ignored).
+//! Otherwise each setting is read from its environment variable.
+//!
+//! ```text
+//! JSON key             environment variable        default
+//! EtherPortalAddress   ETH_DEPOSIT_ADDR            0xFfdbe43d4c855BF7e0f105c400A50857f53AB044
+//! Erc20PortalAddress   ERC20_DEPOSIT_ADDR          0x9C21AEb2093C32DDbC53eEF24B873BDCd1aDa1DB
+//! DAppRelayAddress     DAPP_RELAY_ADDR             0xF5DE34d6BbC0446E2a45719E718efEbaaE179daE
+//! InputBoxAddress      INPUTBOX_CONTRACT_ADDRESS   0x59b22D57D4f067708AB0c00552767405926dc768
+//! DAppAddress          DAPP_ADDRESS                relayed by the DAppAddressRelay
+//! Erc20TokenAddress    ERC20_TOKEN_ADDRESS         Ether is bridged
+//! AssetDecimals        ASSET_DECIMALS              18
+//! ChainId              ETH_CHAIN_ID                any chain
+//! ```
+//!
+//! The defaults are the addresses of the Cartesi rollups contracts on a local devnet.
+
+use std::env;
+
+use ethereum_types::Address;
+
+use super::{AssetDecimals, Bridge};
+
+// (JSON key, environment variable) of each setting
+type Key = (&'static str, &'static str);
+
+const ETHER_PORTAL: Key = ("EtherPortalAddress", "ETH_DEPOSIT_ADDR");
+const ERC20_PORTAL: Key = ("Erc20PortalAddress", "ERC20_DEPOSIT_ADDR");
+const DAPP_RELAY: Key = ("DAppRelayAddress", "DAPP_RELAY_ADDR");
+const INPUT_BOX: Key = ("InputBoxAddress", "INPUTBOX_CONTRACT_ADDRESS");
+const DAPP: Key = ("DAppAddress", "DAPP_ADDRESS");
+const ERC20_TOKEN: Key = ("Erc20TokenAddress", "ERC20_TOKEN_ADDRESS");
+const DECIMALS: Key = ("AssetDecimals", "ASSET_DECIMALS");
+const CHAIN_ID: Key = ("ChainId", "ETH_CHAIN_ID");
+
+const DEFAULT_ETHER_PORTAL: &str = "ffdbe43d4c855bf7e0f105c400a50857f53ab044";
+const DEFAULT_ERC20_PORTAL: &str = "9c21aeb2093c32ddbc53eef24b873bdcd1ada1db";
+const DEFAULT_DAPP_RELAY: &str = "f5de34d6bbc0446e2a45719e718efebaae179dae";
+const DEFAULT_INPUT_BOX: &str = "59b22d57d4f067708ab0c00552767405926dc768";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum DeploymentConfigError {
+    #[error("{0} is the zero address")]
+    ZeroAddress(&'static str),
+    #[error("{0} and {1} are the same address")]
+    DuplicateAddress(&'static str, &'static str),
+    #[error("the fullnode needs {0} to be set")]
+    MissingForFullnode(&'static str),
+}
+
+/// Where the contracts this instance talks to are deployed and the asset it bridges
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeploymentConfig {
+    /// Deposits of Ether are sent by this portal
+    pub ether_portal: Address,
+    /// Deposits of ERC-20 tokens are sent by this portal
+    pub erc20_portal: Address,
+    /// Inputs from this contract relay the dApp address
+    pub dapp_address_relay: Address,
+    /// The fullnode submits wallet transactions to this contract
+    pub input_box: Address,
+    /// The dApp contract, if known before it is relayed
+    pub dapp_address: Option<Address>,
+    /// The ERC-20 token bridged, Ether if not set
+    pub erc20_token: Option<Address>,
+    pub decimals: AssetDecimals,
+    /// EVM chain id of the L1
+    pub chain_id: Option<u64>,
+}
+
+impl DeploymentConfig {
+    /// Load the config from the file named by `DEPLOYMENT_CONFIG` or from the environment, and validate it
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let config = match env::var("DEPLOYMENT_CONFIG") {
+            Ok(path) => Self::from_json(&std::fs::read_to_string(&path).map_err(|e| {
+                anyhow::anyhow!("failed to read deployment config {}: {}", path, e)
+            })?)?,
+            Err(_) => Self::from_env()?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read the config from a JSON object. Numbers may be given as JSON numbers or strings
+    pub fn from_json(config: &str) -> Result<Self, anyhow::Error> {
+        let config = json::parse(config)?;
+        if !config.is_object() {
+            anyhow::bail!("deployment config must be a JSON object");
+        }
+        Self::from_settings(|(key, _)| match &config[key] {
+            json::JsonValue::Null => None,
+            value => Some(value.to_string()),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        Self::from_settings(|(_, var)| env::var(var).ok())
+    }
+
+    fn from_settings(setting: impl Fn(Key) -> Option<String>) -> Result<Self, anyhow::Error> {
+        let address = |key: Key| {
+            setting(key)
+                .map(|address| {
+                    address
+                        .parse::<Address>()
+                        .map_err(|e| anyhow::anyhow!("invalid {} ({}): {}", key.0, key.1, e))
+                })
+                .transpose()
+        };
+        let with_default = |key: Key, default: &str| {
+            address(key).map(|address| address.unwrap_or_else(|| default.parse().unwrap()))
+        };
+
+        Ok(Self {
+            ether_portal: with_default(ETHER_PORTAL, DEFAULT_ETHER_PORTAL)?,
+            erc20_portal: with_default(ERC20_PORTAL, DEFAULT_ERC20_PORTAL)?,
+            dapp_address_relay: with_default(DAPP_RELAY, DEFAULT_DAPP_RELAY)?,
+            input_box: with_default(INPUT_BOX, DEFAULT_INPUT_BOX)?,
+            dapp_address: address(DAPP)?,
+            erc20_token: address(ERC20_TOKEN)?,
+            decimals: match setting(DECIMALS) {
+                Some(decimals) => AssetDecimals::new(decimals.parse()?)?,
+                None => AssetDecimals::default(),
+            },
+            chain_id: setting(CHAIN_ID)
+                .map(|chain_id| chain_id.parse())
+                .transpose()?,
+        })
+    }
+
+    /// Check every address is set and that no two settings name the same contract
+    pub fn validate(&self) -> Result<(), DeploymentConfigError> {
+        let addresses = [
+            (ETHER_PORTAL.0, Some(self.ether_portal)),
+            (ERC20_PORTAL.0, Some(self.erc20_portal)),
+            (DAPP_RELAY.0, Some(self.dapp_address_relay)),
+            (INPUT_BOX.0, Some(self.input_box)),
+            (DAPP.0, self.dapp_address),
+            (ERC20_TOKEN.0, self.erc20_token),
+        ];
+        let addresses: Vec<_> = addresses
+            .into_iter()
+            .filter_map(|(name, address)| address.map(|address| (name, address)))
+            .collect();
+
+        for (i, (name, address)) in addresses.iter().enumerate() {
+            if address.is_zero() {
+                return Err(DeploymentConfigError::ZeroAddress(name));
+            }
+            if let Some((other, _)) = addresses[..i].iter().find(|(_, other)| other == address) {
+                return Err(DeploymentConfigError::DuplicateAddress(other, name));
+            }
+        }
+
+        // the fullnode sends transactions to the dApp through the InputBox on this chain
+        #[cfg(feature = "lightwalletd")]
+        {
+            if self.dapp_address.is_none() {
+                return Err(DeploymentConfigError::MissingForFullnode(DAPP.0));
+            }
+            if self.chain_id.is_none() {
+                return Err(DeploymentConfigError::MissingForFullnode(CHAIN_ID.0));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The asset this deployment bridges
+    pub fn bridge(&self) -> Bridge {
+        match self.erc20_token {
+            Some(token) => Bridge::Erc20 { token },
+            None => Bridge::Ether,
+        }
+    }
+}
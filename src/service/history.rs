@@ -0,0 +1,109 @@
+//! What the app looked like before each recent input.
+//!
+//! When the node reverts inputs the app must be put back exactly as it was before the first of them.
+//! TinyCash keeps its own undo records so only its height is needed, but the input config and the payout
+//! queue are changed by the app itself and are saved here before every input.
+
+use std::collections::VecDeque;
+
+use tiny_cash::block::Height;
+
+use super::{InputConfig, SavedPayouts};
+
+/// The app state from before an input was handled
+#[derive(Clone, Debug)]
+pub struct SavedInput {
+    /// The TinyCash tip
+    pub tip_height: Height,
+    pub input_config: InputConfig,
+    pub payouts: SavedPayouts,
+}
+
+/// Why the app can't be rolled back
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum RewindError {
+    #[error("input {0} is too old to roll back to")]
+    TooOld(usize),
+}
+
+/// The app state from before each of the most recent `depth` inputs
+pub struct InputHistory {
+    depth: usize,
+    tip_height: Height,
+    // oldest first, with the index of the input
+    records: VecDeque<(usize, SavedInput)>,
+    // index of the newest input whose record was dropped
+    forgotten: Option<usize>,
+}
+
+impl InputHistory {
+    /// Nothing is recorded if `depth` is zero
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            tip_height: Height(0),
+            records: VecDeque::new(),
+            forgotten: None,
+        }
+    }
+
+    /// The TinyCash tip after the last block
+    pub fn tip_height(&self) -> Height {
+        self.tip_height
+    }
+
+    /// Record the TinyCash tip after a block was added
+    pub fn set_tip(&mut self, height: Height) {
+        self.tip_height = height;
+    }
+
+    /// Save the state from before handling the input with this index
+    pub fn record(&mut self, input_index: usize, input_config: InputConfig, payouts: SavedPayouts) {
+        if self.depth == 0 {
+            return;
+        }
+        self.records.push_back((
+            input_index,
+            SavedInput {
+                tip_height: self.tip_height,
+                input_config,
+                payouts,
+            },
+        ));
+        while self.records.len() > self.depth {
+            self.forgotten = self.records.pop_front().map(|(index, _)| index);
+        }
+    }
+
+    /// The state from before the first input handled with this index or a later one.
+    /// Returns None if none of them were handled, so there is nothing to undo
+    pub fn saved_before(&self, input_index: usize) -> Result<Option<&SavedInput>, RewindError> {
+        Ok(self
+            .records
+            .get(self.kept(input_index)?)
+            .map(|(_, saved)| saved))
+    }
+
+    /// Forget the input with this index and every later one once the state from before them,
+    /// see [`InputHistory::saved_before`], has been restored
+    pub fn rewind(&mut self, input_index: usize) -> Result<(), RewindError> {
+        let kept = self.kept(input_index)?;
+        if let Some((_, saved)) = self.records.get(kept) {
+            self.tip_height = saved.tip_height;
+        }
+        self.records.truncate(kept);
+        Ok(())
+    }
+
+    // the number of records from before the input with this index
+    fn kept(&self, input_index: usize) -> Result<usize, RewindError> {
+        if self.depth == 0 || self.forgotten.is_some_and(|index| index >= input_index) {
+            return Err(RewindError::TooOld(input_index));
+        }
+        Ok(self
+            .records
+            .iter()
+            .take_while(|(index, _)| *index < input_index)
+            .count())
+    }
+}
@@ -1,49 +1,168 @@
 use futures_util::future::FutureExt;
 use std::future::Future;
 use std::pin::Pin;
+use tiny_cash::burn_memo::{self, BurnMemoError, DecodedBurnMemo};
+use tiny_cash::transaction::{self, Transaction};
 use tower::{BoxError, Service, ServiceExt};
 
-pub use request::Request;
+pub use bridge::Bridge;
+pub use decimals::AssetDecimals;
+pub use deployment::{DeploymentConfig, DeploymentConfigError};
+pub use history::{InputHistory, RewindError, SavedInput};
+pub use payouts::{Payouts, SavedPayouts};
+pub use request::{DepositError, DepositRecipient, InputConfig, RefundReason, Request};
 
+mod bridge;
+mod decimals;
+mod deployment;
+mod history;
+mod payouts;
 mod request;
+#[cfg(test)]
+mod test;
+
 pub struct CarteZcashService<S> {
     tiny_cash: S,
+    config: InputConfig,
 }
 
 pub struct Response {
     pub withdrawals: Vec<(ethereum_types::Address, ethereum_types::U256)>,
+    /// Transactions left out of a batch because one of their burns can't be paid out
+    pub rejected_burns: Vec<(transaction::Hash, WithdrawalError)>,
+    /// Burns that can't be paid out, with the zatoshis burned. Transactions are checked before they are
+    /// included and deposits to the Mt Doom address are refunded, so this is only ever expected to be empty
+    pub invalid_withdrawals: Vec<(ethereum_types::U256, WithdrawalError)>,
     pub block: tiny_cash::SemanticallyVerifiedBlock,
+    pub state_root: tiny_cash::state_root::StateRoot,
+    pub nullifier_root: [u8; 32],
 }
 
 impl<S> CarteZcashService<S> {
-    pub fn new(tiny_cash: S) -> Self {
-        Self { tiny_cash }
+    pub fn new(tiny_cash: S, config: InputConfig) -> Self {
+        Self { tiny_cash, config }
     }
 }
 
-impl From<tiny_cash::service::Response> for Response {
-    fn from(res: tiny_cash::service::Response) -> Self {
-        Self {
-            withdrawals: res
-                .burns
+/// Why a burn can't be paid out
+#[derive(thiserror::Error, Debug)]
+pub enum WithdrawalError {
+    #[error(transparent)]
+    Memo(#[from] BurnMemoError),
+    #[error("burn memo is for chain {0}")]
+    WrongChain(u64),
+    #[error("withdrawal type {0} is not supported")]
+    UnsupportedType(u8),
+    #[error("this instance does not bridge token {0:?}")]
+    WrongToken(ethereum_types::Address),
+    #[error("burn of {0} zatoshis is not a whole number of units of the asset")]
+    NotWholeUnits(ethereum_types::U256),
+}
+
+// The L1 recipient of a burn given its memo
+fn withdrawal_recipient(
+    memo: &tiny_cash::Memo,
+    config: &InputConfig,
+) -> Result<ethereum_types::Address, WithdrawalError> {
+    let memo = match burn_memo::decode(memo)? {
+        DecodedBurnMemo::Binary(memo) => memo,
+        DecodedBurnMemo::LegacyHex { recipient } => return Ok(recipient.into()),
+    };
+    if config
+        .chain_id
+        .is_some_and(|chain_id| chain_id != memo.chain_id)
+    {
+        return Err(WithdrawalError::WrongChain(memo.chain_id));
+    }
+    match memo.withdrawal_type {
+        None | Some(0) => {}
+        Some(withdrawal_type) => return Err(WithdrawalError::UnsupportedType(withdrawal_type)),
+    }
+    if let Some(token) = memo.token.map(ethereum_types::Address::from) {
+        if config.bridge != (Bridge::Erc20 { token }) {
+            return Err(WithdrawalError::WrongToken(token));
+        }
+    }
+    Ok(memo.recipient.into())
+}
+
+/// Transactions rejected because one of their burns can't be paid out.
+/// Nothing was included so the coins they would have burned are still spendable
+#[derive(thiserror::Error, Debug)]
+#[error("{} transactions burn coins that can't be withdrawn", .0.len())]
+pub struct RejectedBurns(pub Vec<(transaction::Hash, WithdrawalError)>);
+
+// Check every burn of the transaction can be paid out, before it is included and the coins are destroyed
+fn check_burns(transaction: &Transaction, config: &InputConfig) -> Result<(), WithdrawalError> {
+    transaction
+        .orchard_actions()
+        .filter_map(tiny_cash::extract_burn_info)
+        .chain(
+            transaction
+                .outputs()
                 .iter()
-                .filter_map(|(amount, memo)| {
-                    if let Ok(address_bytes) = hex::decode(&memo.0[0..40]) {
-                        // expect unicode hex no 0x prefix (inefficient). skip the version byte at the start
-                        Some((
-                            ethereum_types::Address::from_slice(&address_bytes),
-                            ethereum_types::U256::from(amount.zatoshis()),
-                        ))
-                    } else {
-                        tracing::debug!(
-                            "failed to decode address from burn memo {:?}. Skipping withdrawal.",
-                            memo.0
-                        );
-                        None
-                    }
-                })
-                .collect(),
+                .filter_map(tiny_cash::extract_transparent_burn_info),
+        )
+        .try_for_each(|(amount, memo)| {
+            withdrawal_recipient(&memo, config)?;
+            // the part smaller than one unit of the asset couldn't be paid out
+            let zatoshis = ethereum_types::U256::from(amount.zatoshis());
+            if !config.decimals.to_asset(zatoshis).1.is_zero() {
+                return Err(WithdrawalError::NotWholeUnits(zatoshis));
+            }
+            Ok(())
+        })
+}
+
+// Split transactions into those whose burns can all be paid out and those that can't
+fn partition_burns(
+    transactions: Vec<Transaction>,
+    config: &InputConfig,
+) -> (Vec<Transaction>, Vec<(transaction::Hash, WithdrawalError)>) {
+    let mut valid = Vec::new();
+    let mut rejected = Vec::new();
+    for transaction in transactions {
+        match check_burns(&transaction, config) {
+            Ok(()) => valid.push(transaction),
+            Err(e) => {
+                tracing::info!("rejecting transaction {}: {}", transaction.hash(), e);
+                rejected.push((transaction.hash(), e));
+            }
+        }
+    }
+    (valid, rejected)
+}
+
+impl Response {
+    /// Build the response to a TinyCash response, converting withdrawals to amounts of the asset
+    fn new(res: tiny_cash::service::Response, config: &InputConfig) -> Self {
+        let mut withdrawals = Vec::new();
+        let mut invalid_withdrawals = Vec::new();
+        for (amount, memo) in res.burns.iter() {
+            let zatoshis = ethereum_types::U256::from(amount.zatoshis());
+            match withdrawal_recipient(memo, config) {
+                Ok(recipient) => {
+                    let (value, dust) = config.decimals.to_asset(zatoshis);
+                    assert!(
+                        dust.is_zero(),
+                        "burns are checked to be whole units of the asset before they are included"
+                    );
+                    withdrawals.push((recipient, value));
+                }
+                Err(e) => {
+                    tracing::info!("burn of {} can't be withdrawn: {}", zatoshis, e);
+                    invalid_withdrawals.push((zatoshis, e));
+                }
+            }
+        }
+
+        Self {
+            withdrawals,
+            rejected_burns: Vec::new(),
+            invalid_withdrawals,
             block: res.block,
+            state_root: res.state_root,
+            nullifier_root: res.nullifier_root,
         }
     }
 }
@@ -73,33 +192,75 @@ where
 
     fn call(&mut self, req: Request) -> Self::Future {
         let mut tiny_cash = self.tiny_cash.clone();
+        let config = self.config;
         async move {
             match req {
-                Request::Deposit { amount, to } => {
+                Request::Deposit {
+                    amount,
+                    to,
+                    context,
+                    ..
+                } => {
                     tracing::debug!("handling reposit request for amount {} to {}", amount, to);
+                    let request = match to {
+                        DepositRecipient::Transparent(to) => tiny_cash::service::Request::Mint {
+                            amount,
+                            to: to.create_script_from_address(),
+                            context,
+                        },
+                        DepositRecipient::Orchard(to) => {
+                            tiny_cash::service::Request::MintShielded {
+                                amount,
+                                to,
+                                context,
+                            }
+                        }
+                    };
+                    tiny_cash.ready().await?.call(request).await.map(|res| {
+                        tracing::info!("detected burns: {:?}", res.burns);
+                        Response::new(res, &config)
+                    })
+                }
+                Request::TransactBatch { txns, context } => {
+                    tracing::debug!("handling batch of {} transactions", txns.len());
+                    let (txns, rejected_burns) = partition_burns(txns, &config);
+                    if txns.is_empty() {
+                        return Err(RejectedBurns(rejected_burns).into());
+                    }
                     tiny_cash
                         .ready()
                         .await?
-                        .call(tiny_cash::service::Request::Mint {
-                            amount,
-                            to: to.create_script_from_address(),
-                        })
+                        .call(tiny_cash::service::Request::IncludeTransactions(
+                            txns, context,
+                        ))
                         .await
                         .map(|res| {
                             tracing::info!("detected burns: {:?}", res.burns);
-                            res.into()
+                            Response {
+                                rejected_burns,
+                                ..Response::new(res, &config)
+                            }
                         })
                 }
-                Request::Transact { txn, .. } => {
+                Request::Admin(command) => {
+                    Err(format!("admin command {:?} must be handled by the app", command).into())
+                }
+                Request::Transact { txn, context } => {
                     tracing::debug!("handling transact request for txn {:?}", txn);
+                    if let Err(e) = check_burns(&txn, &config) {
+                        return Err(RejectedBurns(vec![(txn.hash(), e)]).into());
+                    }
                     tiny_cash
                         .ready()
                         .await?
-                        .call(tiny_cash::service::Request::IncludeTransaction { transaction: txn })
+                        .call(tiny_cash::service::Request::IncludeTransaction {
+                            transaction: txn,
+                            context,
+                        })
                         .await
                         .map(|res| {
                             tracing::info!("detected burns: {:?}", res.burns);
-                            res.into()
+                            Response::new(res, &config)
                         })
                 }
             }
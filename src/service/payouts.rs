@@ -0,0 +1,104 @@
+//! Vouchers paying the bridged asset out to L1.
+//!
+//! Ether vouchers must be sent to the dApp contract, so they can't be made before its address has been
+//! relayed. Payments made before then are queued and their vouchers are emitted by the relay input.
+
+use std::sync::{Arc, Mutex};
+
+use ethereum_types::{Address, U256};
+
+use super::Bridge;
+
+/// Pays out withdrawals and refunds. Clones share the same dApp address and queue
+#[derive(Clone)]
+pub struct Payouts {
+    bridge: Bridge,
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// The dApp address and queued payments at some point, see [`Payouts::save`]
+#[derive(Clone, Debug)]
+pub struct SavedPayouts(Inner);
+
+#[derive(Clone, Debug)]
+struct Inner {
+    dapp_address: Option<Address>,
+    pending: Vec<(Bridge, Address, U256)>,
+}
+
+impl Payouts {
+    pub fn new(bridge: Bridge, dapp_address: Option<Address>) -> Self {
+        Self {
+            bridge,
+            inner: Arc::new(Mutex::new(Inner {
+                dapp_address,
+                pending: Vec::new(),
+            })),
+        }
+    }
+
+    /// Add a voucher paying `amount` to `recipient` to the response, or queue it with a report saying so
+    /// if the dApp address is needed but not yet known
+    pub fn pay(&self, resp: &mut tower_cartesi::Response, recipient: Address, amount: U256) {
+        self.pay_asset(resp, self.bridge, recipient, amount)
+    }
+
+    /// Like [`Payouts::pay`] but in an asset other than the bridged one, for refunding deposits of it
+    pub fn pay_asset(
+        &self,
+        resp: &mut tower_cartesi::Response,
+        asset: Bridge,
+        recipient: Address,
+        amount: U256,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        match asset.withdrawal_voucher(inner.dapp_address, recipient, amount) {
+            Some((destination, payload)) => {
+                resp.add_voucher(destination, &payload);
+            }
+            None => {
+                tracing::info!(
+                    "Queueing payment of {} to {:?} until the dApp address is relayed",
+                    amount,
+                    recipient
+                );
+                resp.add_report(
+                    format!(
+                        "payment of {} to {:?} is queued until the dApp address is relayed",
+                        amount, recipient
+                    )
+                    .as_bytes(),
+                );
+                inner.pending.push((asset, recipient, amount));
+            }
+        }
+    }
+
+    /// Set the dApp address and add vouchers for all queued payments to the response
+    pub fn set_dapp_address(&self, resp: &mut tower_cartesi::Response, dapp_address: Address) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dapp_address = Some(dapp_address);
+        for (asset, recipient, amount) in std::mem::take(&mut inner.pending) {
+            tracing::info!("Paying queued payment of {} to {:?}", amount, recipient);
+            let (destination, payload) = asset
+                .withdrawal_voucher(Some(dapp_address), recipient, amount)
+                .expect("dApp address is known");
+            resp.add_voucher(destination, &payload);
+        }
+    }
+
+    /// The number of payments waiting for the dApp address
+    pub fn pending(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    /// Copy the dApp address and queue so they can be restored if later inputs are reverted
+    pub fn save(&self) -> SavedPayouts {
+        SavedPayouts(self.inner.lock().unwrap().clone())
+    }
+
+    /// Put back the dApp address and queue from when `saved` was made
+    pub fn restore(&self, saved: SavedPayouts) {
+        *self.inner.lock().unwrap() = saved.0;
+    }
+}
@@ -1,34 +1,143 @@
-use ethereum_types::U256;
+use chrono::DateTime;
+use ethereum_types::{Address as EthAddress, U256};
 
-use tiny_cash::amount::{Amount, NonNegative};
-use tiny_cash::serialization::ZcashDeserialize;
+use tiny_cash::amount::{Amount, NonNegative, MAX_MONEY};
+use tiny_cash::envelope::{self, AdminCommand, Envelope};
+use tiny_cash::parameters::Network;
+use tiny_cash::service::BlockContext;
 use tiny_cash::transaction::Transaction;
 use tiny_cash::transparent::Address;
 
+use zcash_keys::address::Address as ZcashAddress;
+use zcash_primitives::consensus::{MAIN_NETWORK, TEST_NETWORK};
+
+use super::{AssetDecimals, Bridge};
+
 /// Requests that can be received from the L1
 /// will be either EtherTransfer {"request_type":"advance_state","data":{"metadata":{"msg_sender":"0xffdbe43d4c855bf7e0f105c400a50857f53ab044","epoch_index":0,"input_index":0,"block_number":11,"timestamp":1710913093},"payload":"0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266000000000000000000000000000000000000000000000001314fb37062980000"}}
-///      or generic data message {"request_type":"advance_state","data":{"metadata":{"msg_sender":"0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266","epoch_index":0,"input_index":1,"block_number":122,"timestamp":1710913648},"payload":"0xffff"}}
+///      or InputBox message {"request_type":"advance_state","data":{"metadata":{"msg_sender":"0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266","epoch_index":0,"input_index":1,"block_number":122,"timestamp":1710913648},"payload":"0xffff"}}
+///      whose payload is an input envelope (see `tiny_cash::envelope`)
 #[derive(Clone)]
 pub enum Request {
     Deposit {
         amount: Amount<NonNegative>,
-        to: Address,
+        to: DepositRecipient,
+        context: BlockContext,
+        /// The depositor, who is refunded the whole deposit if it can't be minted
+        sender: EthAddress,
+        /// The amount of the asset deposited
+        value: U256,
+        /// Part of the deposit smaller than one zatoshi to refund to the depositor
+        dust: U256,
     },
     Transact {
         txn: Transaction,
+        context: BlockContext,
+    },
+    TransactBatch {
+        txns: Vec<Transaction>,
+        context: BlockContext,
+    },
+    /// Handled by the app rather than TinyCash
+    Admin(AdminCommand),
+}
+
+/// Where a deposit is minted to
+#[derive(Clone)]
+pub enum DepositRecipient {
+    /// A transparent P2PKH address
+    Transparent(Address),
+    /// The Orchard receiver of a unified address. The deposit is minted straight into the shielded pool
+    Orchard(orchard::Address),
+}
+
+impl std::fmt::Display for DepositRecipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transparent(address) => write!(f, "{}", address),
+            Self::Orchard(address) => write!(
+                f,
+                "Orchard address {}",
+                hex::encode(address.to_raw_address_bytes())
+            ),
+        }
+    }
+}
+
+/// How this instance interprets inputs
+#[derive(Clone, Copy, Debug)]
+pub struct InputConfig {
+    /// The network whose address encodings this instance uses
+    pub network: Network,
+    /// The asset this instance bridges
+    pub bridge: Bridge,
+    /// Inputs from this address are Ether deposits
+    pub ether_portal: EthAddress,
+    /// Inputs from this address are ERC-20 deposits
+    pub erc20_portal: EthAddress,
+    /// Deposits minting more than this are refunded
+    pub deposit_cap: Option<Amount<NonNegative>>,
+    /// Decimals of the bridged asset, used to convert deposits and withdrawals
+    pub decimals: AssetDecimals,
+    /// EVM chain id of the L1. Withdrawals with a burn memo for a different chain are not paid out
+    pub chain_id: Option<u64>,
+    /// The L1 address admin commands are accepted from
+    pub admin: Option<EthAddress>,
+}
+
+/// Deposits this instance will not accept.
+/// A `Refund` is accepted with a voucher returning the deposit to the sender and a report explaining why.
+/// The input is rejected with a report for all others
+#[derive(thiserror::Error, Debug)]
+pub enum DepositError {
+    #[error("the ERC-20 transfer into the portal failed")]
+    TransferFailed,
+    #[error("deposit payload is too short ({0} bytes)")]
+    PayloadTooShort(usize),
+    #[error("deposit of {value} {asset} refunded to {sender:?}: {reason}")]
+    Refund {
+        sender: EthAddress,
+        /// The asset that was deposited, which may not be the one this instance bridges
+        asset: Bridge,
+        value: U256,
+        reason: RefundReason,
     },
 }
 
-const ETH_DEPOSIT_ADDR: &str = "ffdbe43d4c855bf7e0f105c400a50857f53ab044";
+/// Why a deposit was refunded
+#[derive(thiserror::Error, Debug)]
+pub enum RefundReason {
+    #[error("this instance only accepts deposits of {0}")]
+    WrongPortal(Bridge),
+    #[error("this instance only accepts deposits of {0}")]
+    WrongToken(Bridge),
+    #[error("invalid recipient ({0})")]
+    InvalidRecipient(anyhow::Error),
+    #[error("the amount exceeds the maximum supply")]
+    ExceedsMaxMoney,
+    #[error("the amount exceeds the deposit cap of {0}")]
+    ExceedsCap(Amount<NonNegative>),
+    #[error(transparent)]
+    MintFailed(tiny_cash::service::Error),
+}
 
-impl TryFrom<(tower_cartesi::AdvanceStateMetadata, Vec<u8>)> for Request {
+/// Parse an input given its metadata, payload and the configuration of this instance
+impl TryFrom<(tower_cartesi::AdvanceStateMetadata, Vec<u8>, &InputConfig)> for Request {
     type Error = anyhow::Error;
 
     fn try_from(
-        (metadata, payload): (tower_cartesi::AdvanceStateMetadata, Vec<u8>),
+        (metadata, payload, config): (tower_cartesi::AdvanceStateMetadata, Vec<u8>, &InputConfig),
     ) -> Result<Self, Self::Error> {
-        match hex::encode(metadata.msg_sender.as_bytes()).as_str() {
-            ETH_DEPOSIT_ADDR => {
+        let bridge = config.bridge;
+        // the L1 block the input was included in determines the time and L1 block number of the L2 block
+        let context = BlockContext {
+            time: DateTime::from_timestamp(metadata.timestamp.try_into()?, 0)
+                .ok_or_else(|| anyhow::anyhow!("invalid input timestamp {}", metadata.timestamp))?,
+            l1_block_number: metadata.block_number.try_into()?,
+        };
+
+        match metadata.msg_sender {
+            portal if portal == config.ether_portal => {
                 /*  encoding as determined by the Cartesi Eth deposit contract
                 abi.encodePacked(
                     sender, //              20B
@@ -36,56 +145,177 @@ impl TryFrom<(tower_cartesi::AdvanceStateMetadata, Vec<u8>)> for Request {
                     execLayerData //        arbitrary size
                 );
                 */
+                if payload.len() < 52 {
+                    return Err(DepositError::PayloadTooShort(payload.len()).into());
+                }
 
-                let _sender = &payload[0..20];
-                let value = U256::from_big_endian(&payload[20..52])
-                    .checked_div(U256::from(10_000_000_000_u64))
-                    .unwrap(); // 1 ZEC is 100_000_000 units while 1 ETH is 10^18. So we divide by 10^10 so that 1 ETH is 1 ZEC
-
-                let dest_t_address = Address::from_pub_key_hash(
-                    tiny_cash::parameters::Network::Mainnet,
-                    payload[52..].try_into()?,
-                );
-                let amount = Amount::try_from(value.as_u64())?; // FIX: This is going to panic if too much eth is sent
-
-                tracing::info!(
-                    "Received deposit request for {} to {}",
-                    value,
-                    dest_t_address
+                let sender = EthAddress::from_slice(&payload[0..20]);
+                let value = U256::from_big_endian(&payload[20..52]);
+                if bridge != Bridge::Ether {
+                    return Err(DepositError::Refund {
+                        sender,
+                        asset: Bridge::Ether,
+                        value,
+                        reason: RefundReason::WrongPortal(bridge),
+                    }
+                    .into());
+                }
+                deposit(sender, value, &payload[52..], config, context)
+            }
+            portal if portal == config.erc20_portal => {
+                /*  encoding as determined by the Cartesi ERC20 deposit contract
+                abi.encodePacked(
+                    success, //             1B
+                    token, //               20B
+                    sender, //              20B
+                    amount, //              32B
+                    execLayerData //        arbitrary size
                 );
+                */
+                if payload.len() < 73 {
+                    return Err(DepositError::PayloadTooShort(payload.len()).into());
+                }
 
-                Ok(Request::Deposit {
-                    amount,
-                    to: dest_t_address,
-                })
+                if payload[0] != 1 {
+                    return Err(DepositError::TransferFailed.into());
+                }
+                let deposited = Bridge::Erc20 {
+                    token: EthAddress::from_slice(&payload[1..21]),
+                };
+                let sender = EthAddress::from_slice(&payload[21..41]);
+                let value = U256::from_big_endian(&payload[41..73]);
+                // the deposit is returned in the token that was sent
+                let reason = match bridge {
+                    Bridge::Ether => Some(RefundReason::WrongPortal(bridge)),
+                    Bridge::Erc20 { .. } if deposited != bridge => {
+                        Some(RefundReason::WrongToken(bridge))
+                    }
+                    Bridge::Erc20 { .. } => None,
+                };
+                if let Some(reason) = reason {
+                    return Err(DepositError::Refund {
+                        sender,
+                        asset: deposited,
+                        value,
+                        reason,
+                    }
+                    .into());
+                }
+                deposit(sender, value, &payload[73..], config, context)
             }
             _ => {
-                // If it is unrecognised then assume it is an inputBox message. This gets around a suspected bug
-                /* Encoding
-                abi.encodePacked(
-                    transaction bytes // arbitrary size
-                 */
-
-                let txn =
-                    tiny_cash::transaction::Transaction::zcash_deserialize(payload.as_slice())?;
+                // anything else was sent directly to the InputBox
+                match envelope::decode(
+                    &payload,
+                    metadata.msg_sender.0,
+                    config.admin.map(|admin| admin.0),
+                )? {
+                    Envelope::Transaction(txn) => {
+                        tracing::info!("Received transaction request {}", txn.hash());
+                        Ok(Request::Transact { txn, context })
+                    }
+                    Envelope::Batch(txns) => {
+                        tracing::info!("Received batch of {} transactions", txns.len());
+                        Ok(Request::TransactBatch { txns, context })
+                    }
+                    Envelope::Admin(command) => Ok(Request::Admin(command)),
+                }
+            }
+        }
+    }
+}
 
-                tracing::info!("Received transaction request {}", txn.hash());
+// Build a deposit of the given amount of the bridged asset to the recipient given by the exec layer data.
+// This is either the 20 byte pubkey hash of a t-address or a unified address with an Orchard receiver.
+// Deposits that can't be minted are refunded to the sender
+fn deposit(
+    sender: EthAddress,
+    value: U256,
+    exec_layer_data: &[u8],
+    config: &InputConfig,
+    context: BlockContext,
+) -> Result<Request, anyhow::Error> {
+    let refund = |reason| DepositError::Refund {
+        sender,
+        asset: config.bridge,
+        value,
+        reason,
+    };
 
-                Ok(Request::Transact { txn })
+    let to = match exec_layer_data.try_into() {
+        Ok(pub_key_hash) => {
+            DepositRecipient::Transparent(Address::from_pub_key_hash(config.network, pub_key_hash))
+        }
+        Err(_) => {
+            let to = decode_orchard_receiver(exec_layer_data, config.network)
+                .map_err(|e| refund(RefundReason::InvalidRecipient(e)))?;
+            // minting to the burn address would burn the deposit without a memo to pay it out to
+            if to == tiny_cash::mt_doom_address() {
+                return Err(refund(RefundReason::InvalidRecipient(anyhow::anyhow!(
+                    "deposits can't be made to the Mt Doom address"
+                )))
+                .into());
             }
+            DepositRecipient::Orchard(to)
         }
+    };
+
+    // one whole unit of the asset mints one whole coin. Anything smaller than a zatoshi is refunded
+    let (zatoshis, dust) = config.decimals.to_zatoshis(value);
+    if zatoshis > U256::from(MAX_MONEY as u64) {
+        return Err(refund(RefundReason::ExceedsMaxMoney).into());
+    }
+    let amount = Amount::try_from(zatoshis.as_u64())?;
+    if let Some(cap) = config.deposit_cap {
+        if amount > cap {
+            return Err(refund(RefundReason::ExceedsCap(cap)).into());
+        }
+    }
+
+    tracing::info!("Received deposit request for {} to {}", amount, to);
+
+    Ok(Request::Deposit {
+        amount,
+        to,
+        context,
+        sender,
+        value,
+        dust,
+    })
+}
+
+// Decode the Orchard receiver of a unified address given as its string encoding
+fn decode_orchard_receiver(
+    encoded: &[u8],
+    network: Network,
+) -> Result<orchard::Address, anyhow::Error> {
+    let encoded = std::str::from_utf8(encoded)?;
+    let address = match network {
+        Network::Testnet => ZcashAddress::decode(&TEST_NETWORK, encoded),
+        _ => ZcashAddress::decode(&MAIN_NETWORK, encoded),
+    };
+    match address {
+        Some(ZcashAddress::Unified(address)) => address
+            .orchard()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unified address has no Orchard receiver")),
+        _ => Err(anyhow::anyhow!("invalid deposit recipient {:?}", encoded)),
     }
 }
 
 impl std::fmt::Debug for Request {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Request::Deposit { amount, to } => {
+            Request::Deposit { amount, to, .. } => {
                 write!(f, "Deposit {} to {}", amount, to)
             }
-            Request::Transact { txn } => {
+            Request::Transact { txn, .. } => {
                 write!(f, "Transact hash {}", txn.hash(),)
             }
+            Request::TransactBatch { txns, .. } => {
+                write!(f, "TransactBatch of {} transactions", txns.len())
+            }
+            Request::Admin(command) => write!(f, "Admin {:?}", command),
         }
     }
 }
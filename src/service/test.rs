@@ -0,0 +1,485 @@
+use ethereum_types::U256;
+use proptest::prelude::*;
+
+use super::decimals::{MAX_DECIMALS, ZATOSHI_DECIMALS};
+use super::{check_burns, partition_burns};
+use super::{
+    AssetDecimals, Bridge, DeploymentConfig, DeploymentConfigError, DepositError, InputConfig,
+    InputHistory, Payouts, RefundReason, Request, RewindError, WithdrawalError,
+};
+use tiny_cash::block::Height;
+use tiny_cash::burn_memo::BurnMemo;
+use tower_cartesi::Output;
+
+const MAX_MONEY: u64 = tiny_cash::amount::MAX_MONEY as u64;
+
+#[test]
+fn test_one_ether_is_one_coin() {
+    let decimals = AssetDecimals::default();
+    let one_ether = U256::exp10(18);
+
+    assert_eq!(
+        decimals.to_zatoshis(one_ether),
+        (U256::from(100_000_000), U256::zero())
+    );
+    assert_eq!(
+        decimals.to_asset(U256::from(100_000_000)),
+        (one_ether, U256::zero())
+    );
+    // anything below a zatoshi is dust
+    assert_eq!(
+        decimals.to_zatoshis(one_ether + 9_999_999_999_u64),
+        (U256::from(100_000_000), U256::from(9_999_999_999_u64))
+    );
+}
+
+#[test]
+fn test_unsupported_decimals() {
+    assert!(AssetDecimals::new(MAX_DECIMALS).is_ok());
+    assert!(AssetDecimals::new(MAX_DECIMALS + 1).is_err());
+}
+
+#[test]
+fn test_payments_are_queued_until_dapp_address_is_relayed() {
+    let payouts = Payouts::new(Bridge::Ether, None);
+    let recipient = ethereum_types::Address::repeat_byte(1);
+
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.clone().pay(&mut resp, recipient, U256::from(100));
+    assert!(matches!(resp.outputs[..], [Output::Report { .. }]));
+    assert_eq!(payouts.pending(), 1);
+
+    let dapp_address = ethereum_types::Address::repeat_byte(2);
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.set_dapp_address(&mut resp, dapp_address);
+    assert!(matches!(
+        resp.outputs[..],
+        [Output::Voucher { destination, .. }] if destination == dapp_address
+    ));
+    assert_eq!(payouts.pending(), 0);
+
+    // once the address is known payments are made straight away
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.pay(&mut resp, recipient, U256::from(100));
+    assert!(matches!(resp.outputs[..], [Output::Voucher { .. }]));
+}
+
+#[test]
+fn test_erc20_payments_are_never_queued() {
+    let token = ethereum_types::Address::repeat_byte(3);
+    let payouts = Payouts::new(Bridge::Erc20 { token }, None);
+
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.pay(
+        &mut resp,
+        ethereum_types::Address::repeat_byte(1),
+        U256::from(100),
+    );
+    assert!(matches!(
+        resp.outputs[..],
+        [Output::Voucher { destination, .. }] if destination == token
+    ));
+    assert_eq!(payouts.pending(), 0);
+}
+
+#[test]
+fn test_deployment_config_from_frontend_config() {
+    // an entry of bridge-frontend/src/config.json, whose other keys are ignored
+    let config = DeploymentConfig::from_json(
+        r#"{
+            "token": "SepETH",
+            "DAppRelayAddress": "0xF5DE34d6BbC0446E2a45719E718efEbaaE179daE",
+            "InputBoxAddress": "0x59b22D57D4f067708AB0c00552767405926dc768",
+            "EtherPortalAddress": "0xFfdbe43d4c855BF7e0f105c400A50857f53AB044",
+            "Erc20PortalAddress": "0x9C21AEb2093C32DDbC53eEF24B873BDCd1aDa1DB",
+            "DAppAddress": "0xD9b811D7e96C7e712E610Dec263a8DcEd9C3175d",
+            "AssetDecimals": 6,
+            "ChainId": "11155111"
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(
+        config.dapp_address,
+        Some("d9b811d7e96c7e712e610dec263a8dced9c3175d".parse().unwrap())
+    );
+    assert_eq!(config.decimals.decimals(), 6);
+    assert_eq!(config.chain_id, Some(11155111));
+    assert_eq!(config.bridge(), Bridge::Ether);
+}
+
+#[test]
+fn test_invalid_deployment_config() {
+    let config = DeploymentConfig::from_json(
+        r#"{"DAppAddress": "0xD9b811D7e96C7e712E610Dec263a8DcEd9C3175d", "ChainId": 31337}"#,
+    )
+    .unwrap();
+    assert_eq!(config.validate(), Ok(()));
+
+    let zero_input_box = DeploymentConfig {
+        input_box: ethereum_types::Address::zero(),
+        ..config
+    };
+    assert_eq!(
+        zero_input_box.validate(),
+        Err(DeploymentConfigError::ZeroAddress("InputBoxAddress"))
+    );
+    // a token can't be bridged through its own portal
+    let token_is_portal = DeploymentConfig {
+        erc20_token: Some(config.erc20_portal),
+        ..config
+    };
+    assert_eq!(
+        token_is_portal.validate(),
+        Err(DeploymentConfigError::DuplicateAddress(
+            "Erc20PortalAddress",
+            "Erc20TokenAddress"
+        ))
+    );
+
+    assert!(DeploymentConfig::from_json(r#"{"AssetDecimals": 37}"#).is_err());
+    assert!(DeploymentConfig::from_json(r#"{"InputBoxAddress": "0x1234"}"#).is_err());
+    assert!(DeploymentConfig::from_json(r#"["0x1234"]"#).is_err());
+}
+
+fn input_config(deposit_cap: u64) -> InputConfig {
+    InputConfig {
+        network: tiny_cash::parameters::Network::Mainnet,
+        bridge: Bridge::Ether,
+        ether_portal: ethereum_types::Address::repeat_byte(1),
+        erc20_portal: ethereum_types::Address::repeat_byte(2),
+        deposit_cap: Some(tiny_cash::Amount::try_from(deposit_cap).unwrap()),
+        decimals: AssetDecimals::default(),
+        chain_id: None,
+        admin: None,
+    }
+}
+
+#[test]
+fn test_input_history_restores_the_state_before_an_input() {
+    let payouts = Payouts::new(Bridge::Ether, None);
+    let mut history = InputHistory::new(3);
+
+    // inputs 0 and 2 add blocks, input 3 queues a payment and input 4 changes the config
+    history.record(0, input_config(1), payouts.save());
+    history.set_tip(Height(1));
+    history.record(2, input_config(1), payouts.save());
+    history.set_tip(Height(2));
+    history.record(3, input_config(1), payouts.save());
+    payouts.pay(
+        &mut tower_cartesi::Response::empty_accept(),
+        ethereum_types::Address::repeat_byte(9),
+        U256::from(100),
+    );
+    history.record(4, input_config(2), payouts.save());
+
+    // input 1 was never handled so rolling back to it restores the state from before input 2
+    let saved = history.saved_before(1).unwrap().unwrap().clone();
+    assert_eq!(saved.tip_height, Height(1));
+    // nothing is forgotten until the state has been restored
+    assert_eq!(history.tip_height(), Height(2));
+    history.rewind(1).unwrap();
+    assert_eq!(history.tip_height(), Height(1));
+    payouts.restore(saved.payouts);
+    assert_eq!(payouts.pending(), 0);
+    // so there is nothing more to undo
+    assert!(history.saved_before(1).unwrap().is_none());
+
+    // input 0 was the oldest of 4 inputs so it was forgotten
+    let mut history = InputHistory::new(3);
+    for index in 0..4 {
+        history.record(index, input_config(index as u64), payouts.save());
+    }
+    assert_eq!(history.rewind(0).unwrap_err(), RewindError::TooOld(0));
+    let saved = history.saved_before(3).unwrap().unwrap();
+    assert_eq!(saved.input_config.deposit_cap, input_config(3).deposit_cap);
+
+    // nothing is recorded by default
+    let mut history = InputHistory::new(0);
+    history.record(0, input_config(1), payouts.save());
+    assert_eq!(history.rewind(0).unwrap_err(), RewindError::TooOld(0));
+}
+
+#[tokio::test]
+async fn test_oldest_input_in_the_history_can_be_rolled_back() {
+    use tiny_cash::service::{BlockContext, Config, Request, TinyCash};
+    use tower::{Service, ServiceExt};
+
+    let depth = 3;
+    let mut tinycash = TinyCash::with_config(Config {
+        rollback_depth: depth,
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    // every input adds a block
+    let payouts = Payouts::new(Bridge::Ether, None);
+    let mut history = InputHistory::new(depth as usize);
+    for index in 0..5 {
+        history.record(index, input_config(1), payouts.save());
+        let response = tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Mint {
+                amount: tiny_cash::Amount::try_from(100).unwrap(),
+                to: tiny_cash::transparent::Script::new(&[0]),
+                context: BlockContext::default(),
+            })
+            .await
+            .unwrap();
+        history.set_tip(response.block.height);
+    }
+
+    // input 2 is the oldest still recorded. Reverting it undoes as many blocks as TinyCash can
+    assert!(history.saved_before(1).is_err());
+    let saved = history.saved_before(2).unwrap().unwrap();
+    assert_eq!(saved.tip_height, Height(2));
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(saved.tip_height))
+        .await
+        .unwrap();
+    assert_eq!(response.block.height, Height(2));
+}
+
+// An ERC20Portal input depositing `value` of `token` from `sender`
+fn erc20_deposit(
+    config: &InputConfig,
+    token: ethereum_types::Address,
+    sender: ethereum_types::Address,
+    value: U256,
+) -> Result<Request, anyhow::Error> {
+    let mut payload = vec![1];
+    payload.extend(token.as_bytes());
+    payload.extend(sender.as_bytes());
+    payload.extend([0; 32]);
+    value.to_big_endian(&mut payload[41..73]);
+    let metadata = tower_cartesi::AdvanceStateMetadata {
+        msg_sender: config.erc20_portal,
+        epoch_index: 0,
+        input_index: 0,
+        block_number: 1,
+        timestamp: 1710913648,
+    };
+    Request::try_from((metadata, payload, config))
+}
+
+// An EtherPortal input depositing `value` from `sender` to the unified address with the Orchard receiver `to`
+fn ether_deposit_to_orchard(
+    config: &InputConfig,
+    sender: ethereum_types::Address,
+    value: U256,
+    to: orchard::Address,
+) -> Result<Request, anyhow::Error> {
+    let address =
+        zcash_keys::address::UnifiedAddress::from_receivers(Some(to), None, None).unwrap();
+    let mut payload = sender.as_bytes().to_vec();
+    payload.extend([0; 32]);
+    value.to_big_endian(&mut payload[20..52]);
+    payload.extend(
+        zcash_keys::address::Address::Unified(address)
+            .encode(&zcash_primitives::consensus::MAIN_NETWORK)
+            .as_bytes(),
+    );
+    let metadata = tower_cartesi::AdvanceStateMetadata {
+        msg_sender: config.ether_portal,
+        epoch_index: 0,
+        input_index: 0,
+        block_number: 1,
+        timestamp: 1710913648,
+    };
+    Request::try_from((metadata, payload, config))
+}
+
+#[test]
+fn test_deposits_to_the_mt_doom_address_are_refunded() {
+    let config = input_config(1_000);
+    let sender = ethereum_types::Address::repeat_byte(5);
+    let value = U256::exp10(10);
+
+    let refund = ether_deposit_to_orchard(&config, sender, value, tiny_cash::mt_doom_address())
+        .unwrap_err()
+        .downcast::<DepositError>()
+        .unwrap();
+    assert!(matches!(
+        refund,
+        DepositError::Refund {
+            asset: Bridge::Ether,
+            reason: RefundReason::InvalidRecipient(_),
+            ..
+        }
+    ));
+
+    let to = orchard::keys::FullViewingKey::from(
+        &orchard::keys::SpendingKey::from_bytes([7; 32]).unwrap(),
+    )
+    .address_at(0_usize, orchard::keys::Scope::External);
+    assert!(matches!(
+        ether_deposit_to_orchard(&config, sender, value, to),
+        Ok(Request::Deposit { .. })
+    ));
+}
+
+#[test]
+fn test_deposits_of_other_assets_are_refunded_in_the_asset_deposited() {
+    let sender = ethereum_types::Address::repeat_byte(5);
+    let sent = ethereum_types::Address::repeat_byte(6);
+    let value = U256::from(100);
+
+    // a token sent to an Ether instance
+    let ether = input_config(1_000);
+    let refund = erc20_deposit(&ether, sent, sender, value)
+        .unwrap_err()
+        .downcast::<DepositError>()
+        .unwrap();
+    assert!(matches!(
+        refund,
+        DepositError::Refund {
+            asset: Bridge::Erc20 { token },
+            reason: RefundReason::WrongPortal(Bridge::Ether),
+            ..
+        } if token == sent
+    ));
+
+    // a different token sent to an ERC-20 instance
+    let bridged = ethereum_types::Address::repeat_byte(7);
+    let erc20 = InputConfig {
+        bridge: Bridge::Erc20 { token: bridged },
+        ..ether
+    };
+    let refund = erc20_deposit(&erc20, sent, sender, value)
+        .unwrap_err()
+        .downcast::<DepositError>()
+        .unwrap();
+    assert!(matches!(
+        refund,
+        DepositError::Refund {
+            sender: s,
+            asset: Bridge::Erc20 { token },
+            value: v,
+            reason: RefundReason::WrongToken(_),
+        } if token == sent && s == sender && v == value
+    ));
+
+    // the refund is a transfer on the token that was sent, not the bridged one
+    let payouts = Payouts::new(erc20.bridge, None);
+    let mut resp = tower_cartesi::Response::empty_accept();
+    payouts.pay_asset(&mut resp, Bridge::Erc20 { token: sent }, sender, value);
+    assert!(matches!(
+        resp.outputs[..],
+        [Output::Voucher { destination, .. }] if destination == sent
+    ));
+}
+
+// A transaction whose only output burns 100 zatoshis with the given memo
+fn transparent_burn(memo: &tiny_cash::Memo) -> tiny_cash::transaction::Transaction {
+    tiny_cash::transaction::Transaction::V5 {
+        network_upgrade: tiny_cash::parameters::NetworkUpgrade::Nu5,
+        lock_time: tiny_cash::transaction::LockTime::unlocked(),
+        expiry_height: Height(0),
+        inputs: Vec::new(),
+        outputs: vec![tiny_cash::transparent::Output {
+            value: tiny_cash::Amount::try_from(100).unwrap(),
+            lock_script: tiny_cash::mt_doom_script(memo),
+        }],
+        sapling_shielded_data: None,
+        orchard_shielded_data: None,
+    }
+}
+
+#[test]
+fn test_burns_that_cant_be_withdrawn_are_rejected_before_inclusion() {
+    let config = InputConfig {
+        chain_id: Some(1),
+        ..input_config(1_000)
+    };
+    let valid = transparent_burn(&BurnMemo::new(1, [0xab; 20]).encode());
+    let wrong_chain = transparent_burn(&BurnMemo::new(2, [0xab; 20]).encode());
+    let mut corrupt = [0; 512];
+    corrupt[0] = b'z';
+    let corrupt = transparent_burn(&tiny_cash::Memo(Box::new(corrupt)));
+
+    assert!(check_burns(&valid, &config).is_ok());
+    assert!(matches!(
+        check_burns(&wrong_chain, &config),
+        Err(WithdrawalError::WrongChain(2))
+    ));
+    assert!(matches!(
+        check_burns(&corrupt, &config),
+        Err(WithdrawalError::Memo(_))
+    ));
+
+    // the 100 zatoshis burned are one unit of an asset with 6 decimals but a fraction of one with 5
+    let six_decimals = InputConfig {
+        decimals: AssetDecimals::new(6).unwrap(),
+        ..config
+    };
+    assert!(check_burns(&valid, &six_decimals).is_ok());
+    let five_decimals = InputConfig {
+        decimals: AssetDecimals::new(5).unwrap(),
+        ..config
+    };
+    assert!(matches!(
+        check_burns(&valid, &five_decimals),
+        Err(WithdrawalError::NotWholeUnits(zatoshis)) if zatoshis == U256::from(100)
+    ));
+
+    // only the bad transactions are left out of a batch
+    let (included, rejected) = partition_burns(
+        vec![wrong_chain.clone(), valid.clone(), corrupt.clone()],
+        &config,
+    );
+    assert_eq!(included, vec![valid]);
+    assert_eq!(
+        rejected.iter().map(|(hash, _)| *hash).collect::<Vec<_>>(),
+        vec![wrong_chain.hash(), corrupt.hash()]
+    );
+}
+
+proptest! {
+    // a deposit followed by withdrawing everything it minted pays out the deposit minus the dust refunded
+    #[test]
+    fn prop_deposit_then_withdrawal_round_trips(decimals in 0..=MAX_DECIMALS, value in any::<u128>()) {
+        let decimals = AssetDecimals::new(decimals).unwrap();
+        let value = U256::from(value);
+
+        let (zatoshis, deposit_dust) = decimals.to_zatoshis(value);
+        let (withdrawn, withdrawal_dust) = decimals.to_asset(zatoshis);
+
+        prop_assert_eq!(withdrawn + deposit_dust, value);
+        prop_assert!(withdrawal_dust.is_zero());
+        if decimals.decimals() >= ZATOSHI_DECIMALS {
+            prop_assert!(deposit_dust < U256::exp10((decimals.decimals() - ZATOSHI_DECIMALS).into()));
+        } else {
+            prop_assert!(deposit_dust.is_zero());
+        }
+    }
+
+    // a withdrawal that is deposited again mints what was burned minus the dust that couldn't be paid out
+    #[test]
+    fn prop_withdrawal_then_deposit_round_trips(decimals in 0..=MAX_DECIMALS, zatoshis in 0..=MAX_MONEY) {
+        let decimals = AssetDecimals::new(decimals).unwrap();
+        let zatoshis = U256::from(zatoshis);
+
+        let (value, withdrawal_dust) = decimals.to_asset(zatoshis);
+        let (minted, deposit_dust) = decimals.to_zatoshis(value);
+
+        prop_assert_eq!(minted + withdrawal_dust, zatoshis);
+        prop_assert!(deposit_dust.is_zero());
+        if decimals.decimals() < ZATOSHI_DECIMALS {
+            prop_assert!(withdrawal_dust < U256::exp10((ZATOSHI_DECIMALS - decimals.decimals()).into()));
+        } else {
+            prop_assert!(withdrawal_dust.is_zero());
+        }
+    }
+}
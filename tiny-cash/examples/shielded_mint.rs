@@ -0,0 +1,66 @@
+//! Measure what the `shielded-mint` feature costs a node.
+//!
+//! The first shielded mint builds the Halo2 proving key and every mint proves an Orchard output. This is
+//! compared to building the verifying key, which every node already does on boot.
+//!
+//!     cargo run --release -p tiny-cash --features shielded-mint --example shielded_mint [mints]
+//!
+//! Run it inside the Cartesi machine as well before enabling the feature for a deployment, the times there
+//! are far longer than on the host.
+
+use std::time::{Duration, Instant};
+
+use orchard::keys::{FullViewingKey, Scope, SpendingKey};
+use tiny_cash::service::{BlockContext, Request, TinyCash};
+use tiny_cash::Amount;
+use tower::{Service, ServiceExt};
+
+fn main() {
+    let mints: u32 = std::env::args()
+        .nth(1)
+        .map(|mints| mints.parse().expect("number of mints"))
+        .unwrap_or(5);
+
+    let start = Instant::now();
+    tiny_cash::initialize_halo2();
+    println!("verifying key: {:?}", start.elapsed());
+
+    let to = FullViewingKey::from(&SpendingKey::from_bytes([7; 32]).unwrap())
+        .address_at(0_usize, Scope::External);
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let mut tinycash = TinyCash::new();
+        tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Genesis)
+            .await
+            .unwrap();
+
+        let mut times = Vec::new();
+        for _ in 0..=mints {
+            let start = Instant::now();
+            tinycash
+                .ready()
+                .await
+                .unwrap()
+                .call(Request::MintShielded {
+                    amount: Amount::try_from(1_000).unwrap(),
+                    to,
+                    context: BlockContext::default(),
+                })
+                .await
+                .unwrap();
+            times.push(start.elapsed());
+        }
+
+        println!("proving key and first mint: {:?}", times[0]);
+        if mints > 0 {
+            println!(
+                "mean of {} more mints: {:?}",
+                mints,
+                times[1..].iter().sum::<Duration>() / mints
+            );
+        }
+    });
+}
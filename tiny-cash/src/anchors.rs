@@ -0,0 +1,207 @@
+//! The window of recent Orchard commitment tree roots that transactions may use as an anchor.
+//!
+//! A root is only recorded when it changes so blocks without any Orchard actions do not use up space.
+//! Roots that fall out of the configured [`AnchorWindow`] are evicted, which keeps the state a fixed size
+//! at the cost of requiring wallets to keep their witnesses reasonably up to date.
+//! The most recently evicted roots are remembered so a transaction using one can be told how old its anchor
+//! is rather than that it is unknown. Only [`EVICTED_CAPACITY`] of them are kept, older roots are reported as
+//! unknown.
+
+use std::collections::VecDeque;
+
+use zebra_chain::block::Height;
+use zebra_chain::orchard::tree::Root;
+
+use crate::service::Error;
+use crate::set_hash::SetHash;
+
+const ANCHORS_DOMAIN: &str = "TinyCash-Anchors";
+
+/// The number of evicted roots remembered for error reporting
+pub const EVICTED_CAPACITY: usize = 1000;
+
+/// How long a commitment tree root remains a valid anchor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnchorWindow {
+    /// A root is valid if it was the tree root at any of the most recent `n` blocks
+    Blocks(u32),
+    /// Only the `n` most recent distinct roots are valid
+    Roots(u32),
+}
+
+impl Default for AnchorWindow {
+    fn default() -> Self {
+        Self::Blocks(1000)
+    }
+}
+
+impl AnchorWindow {
+    pub(crate) fn to_bytes(self) -> [u8; 5] {
+        let (kind, n) = match self {
+            Self::Blocks(n) => (0, n),
+            Self::Roots(n) => (1, n),
+        };
+        let mut bytes = [kind; 5];
+        bytes[1..].copy_from_slice(&n.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 5]) -> Option<Self> {
+        let n = u32::from_le_bytes(bytes[1..].try_into().unwrap());
+        match bytes[0] {
+            0 => Some(Self::Blocks(n)),
+            1 => Some(Self::Roots(n)),
+            _ => None,
+        }
+    }
+}
+
+/// The distinct tree roots inside the anchor window, oldest first.
+///
+/// Each root is stored with the height of the block that first produced it.
+/// It remains the current root until the height of the following entry.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Anchors {
+    window: AnchorWindow,
+    roots: VecDeque<(Height, Root)>,
+    // the most recent roots to leave the window, oldest first and with the height they were first seen.
+    // Not part of the state root as it only affects error reporting
+    evicted: VecDeque<(Height, Root)>,
+    // multiset hash of the entries in `roots`, updated as they are pushed and evicted
+    digest: SetHash,
+}
+
+/// The changes made by a single [`Anchors::push`]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AnchorsUndo {
+    pushed: bool,
+    evicted: Vec<(Height, Root)>,
+    // evicted roots dropped to make room for the ones evicted by the push, oldest first
+    forgotten: Vec<(Height, Root)>,
+}
+
+impl Anchors {
+    pub fn new(window: AnchorWindow) -> Self {
+        Self {
+            window,
+            roots: VecDeque::new(),
+            evicted: VecDeque::new(),
+            digest: SetHash::new(ANCHORS_DOMAIN),
+        }
+    }
+
+    pub fn window(&self) -> AnchorWindow {
+        self.window
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Height, Root)> {
+        self.roots.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// The most recent roots to have left the window, oldest first
+    pub fn evicted(&self) -> impl Iterator<Item = &(Height, Root)> {
+        self.evicted.iter()
+    }
+
+    /// Remember a root that left the window before this instance was restored from a snapshot
+    pub(crate) fn insert_evicted(&mut self, height: Height, root: Root) {
+        self.remember_evicted(height, root);
+    }
+
+    pub fn digest(&self) -> &SetHash {
+        &self.digest
+    }
+
+    /// Record the tree root after the block at `height` then evict anything outside the window.
+    /// Returns what changed so it can be reverted with [`Anchors::undo`]
+    pub fn push(&mut self, height: Height, root: Root) -> AnchorsUndo {
+        let mut undo = AnchorsUndo::default();
+        if self.roots.back().map(|(_, last)| *last) != Some(root) {
+            self.digest.insert(&entry(height, root));
+            self.roots.push_back((height, root));
+            undo.pushed = true;
+        }
+
+        match self.window {
+            AnchorWindow::Blocks(n) => {
+                // drop the oldest root once the root after it was already current at the start of the window
+                let window_start = (height.0 + 1).saturating_sub(n);
+                while self.roots.len() > 1 && self.roots[1].0 .0 <= window_start {
+                    self.evict_oldest(&mut undo);
+                }
+            }
+            AnchorWindow::Roots(n) => {
+                while self.roots.len() > (n as usize).max(1) {
+                    self.evict_oldest(&mut undo);
+                }
+            }
+        }
+        undo
+    }
+
+    /// Revert the most recent push
+    pub fn undo(&mut self, undo: AnchorsUndo) {
+        if undo.pushed {
+            if let Some((height, root)) = self.roots.pop_back() {
+                self.digest.remove(&entry(height, root));
+            }
+        }
+        for (height, root) in undo.evicted.into_iter().rev() {
+            self.evicted.pop_back();
+            self.digest.insert(&entry(height, root));
+            self.roots.push_front((height, root));
+        }
+        for forgotten in undo.forgotten.into_iter().rev() {
+            self.evicted.push_front(forgotten);
+        }
+    }
+
+    fn evict_oldest(&mut self, undo: &mut AnchorsUndo) {
+        if let Some((height, root)) = self.roots.pop_front() {
+            self.digest.remove(&entry(height, root));
+            undo.forgotten.extend(self.remember_evicted(height, root));
+            undo.evicted.push((height, root));
+        }
+    }
+
+    // returns the evicted root dropped to make room, if any
+    fn remember_evicted(&mut self, height: Height, root: Root) -> Option<(Height, Root)> {
+        self.evicted.push_back((height, root));
+        if self.evicted.len() > EVICTED_CAPACITY {
+            self.evicted.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Ensure the root is a valid anchor at the given tip
+    pub fn check(&self, root: &Root, tip_height: Height) -> Result<(), Error> {
+        if self.roots.iter().any(|(_, r)| r == root) {
+            return Ok(());
+        }
+        let evicted = self.evicted.iter().rev().find(|(_, r)| r == root);
+        match (self.roots.front(), evicted) {
+            (Some((oldest_anchor_height, _)), Some((anchor_height, _))) => {
+                Err(Error::AnchorTooOld {
+                    anchor_height: *anchor_height,
+                    oldest_anchor_height: *oldest_anchor_height,
+                    tip_height,
+                })
+            }
+            // this was never the root of the commitment tree, or left the window long ago
+            _ => Err(Error::UnknownAnchor(*root)),
+        }
+    }
+}
+
+// The bytes of a window entry hashed into the digest. The same as its snapshot encoding
+fn entry(height: Height, root: Root) -> [u8; 36] {
+    let mut bytes = [0; 36];
+    bytes[..4].copy_from_slice(&height.0.to_le_bytes());
+    bytes[4..].copy_from_slice(&<[u8; 32]>::from(root));
+    bytes
+}
@@ -0,0 +1,186 @@
+//! The memo of a note burned by sending it to Mt Doom, which tells the bridge where to pay the withdrawal.
+//!
+//! A memo starts with `0xFF` so ZIP-302 wallets treat it as arbitrary data rather than text, followed by
+//!
+//! ```text
+//! version          1 byte (1)
+//! chain id         8 bytes, big-endian. The EVM chain id of the L1 the withdrawal is paid on
+//! recipient        20 bytes
+//! fields length    1 byte
+//! fields           tag (1 byte) length (1 byte) value, each tag at most once
+//! checksum         4 bytes, the first bytes of BLAKE2b-256(personalization = "CarteZcashBrnMmo", all preceding bytes)
+//! ```
+//!
+//! and the rest of the memo is zero. The optional fields are
+//!
+//! - `0x01` withdrawal type (1 byte). Only `0`, paying the asset to the recipient, is defined and is the default
+//! - `0x02` token (20 bytes). The ERC-20 token the burned coins represent
+//!
+//! Memos that don't start with `0xFF` are decoded as the legacy format: the recipient as 40 hex characters.
+
+use zebra_chain::transaction::Memo;
+
+const MEMO_SIZE: usize = 512;
+
+const BINARY_MARKER: u8 = 0xff;
+const CHECKSUM_PERSONALIZATION: &[u8; 16] = b"CarteZcashBrnMmo";
+const CHECKSUM_SIZE: usize = 4;
+
+/// The current memo version
+pub const VERSION: u8 = 1;
+
+const TAG_WITHDRAWAL_TYPE: u8 = 0x01;
+const TAG_TOKEN: u8 = 0x02;
+
+// marker, version, chain id, recipient and fields length
+const HEADER_SIZE: usize = 1 + 1 + 8 + 20 + 1;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum BurnMemoError {
+    #[error("unsupported burn memo version {0}")]
+    UnsupportedVersion(u8),
+    #[error("burn memo checksum does not match")]
+    BadChecksum,
+    #[error("burn memo field {0:#04x} is unknown")]
+    UnknownField(u8),
+    #[error("burn memo field {0:#04x} is repeated or has the wrong length")]
+    InvalidField(u8),
+    #[error("burn memo is truncated or has trailing data")]
+    Malformed,
+    #[error("legacy burn memo does not start with a hex encoded address")]
+    InvalidLegacyHex,
+}
+
+/// A versioned, binary burn memo
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BurnMemo {
+    pub chain_id: u64,
+    pub recipient: [u8; 20],
+    pub withdrawal_type: Option<u8>,
+    pub token: Option<[u8; 20]>,
+}
+
+/// A burn memo in either format
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedBurnMemo {
+    Binary(BurnMemo),
+    /// The legacy hex format, which has no chain id or checksum
+    LegacyHex {
+        recipient: [u8; 20],
+    },
+}
+
+impl DecodedBurnMemo {
+    pub fn recipient(&self) -> [u8; 20] {
+        match self {
+            Self::Binary(memo) => memo.recipient,
+            Self::LegacyHex { recipient } => *recipient,
+        }
+    }
+}
+
+impl BurnMemo {
+    pub fn new(chain_id: u64, recipient: [u8; 20]) -> Self {
+        Self {
+            chain_id,
+            recipient,
+            withdrawal_type: None,
+            token: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: [u8; 20]) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_withdrawal_type(mut self, withdrawal_type: u8) -> Self {
+        self.withdrawal_type = Some(withdrawal_type);
+        self
+    }
+
+    pub fn encode(&self) -> Memo {
+        let mut fields = Vec::new();
+        if let Some(withdrawal_type) = self.withdrawal_type {
+            fields.extend([TAG_WITHDRAWAL_TYPE, 1, withdrawal_type]);
+        }
+        if let Some(token) = self.token {
+            fields.extend([TAG_TOKEN, 20]);
+            fields.extend(token);
+        }
+
+        let mut bytes = vec![BINARY_MARKER, VERSION];
+        bytes.extend(self.chain_id.to_be_bytes());
+        bytes.extend(self.recipient);
+        bytes.push(fields.len() as u8);
+        bytes.extend(fields);
+        bytes.extend(checksum(&bytes));
+        bytes.resize(MEMO_SIZE, 0);
+
+        Memo(Box::new(bytes.try_into().expect("memo is 512 bytes")))
+    }
+
+    fn decode_binary(bytes: &[u8; MEMO_SIZE]) -> Result<Self, BurnMemoError> {
+        if bytes[1] != VERSION {
+            return Err(BurnMemoError::UnsupportedVersion(bytes[1]));
+        }
+        let fields_end = HEADER_SIZE + bytes[HEADER_SIZE - 1] as usize;
+        let checksum_end = fields_end + CHECKSUM_SIZE;
+        if checksum_end > MEMO_SIZE || bytes[checksum_end..].iter().any(|b| *b != 0) {
+            return Err(BurnMemoError::Malformed);
+        }
+        if bytes[fields_end..checksum_end] != checksum(&bytes[..fields_end]) {
+            return Err(BurnMemoError::BadChecksum);
+        }
+
+        let mut memo = Self::new(
+            u64::from_be_bytes(bytes[2..10].try_into().unwrap()),
+            bytes[10..30].try_into().unwrap(),
+        );
+        let mut fields = &bytes[HEADER_SIZE..fields_end];
+        while let [tag, len, rest @ ..] = fields {
+            let value = rest.get(..*len as usize).ok_or(BurnMemoError::Malformed)?;
+            match (*tag, value) {
+                (TAG_WITHDRAWAL_TYPE, [withdrawal_type]) if memo.withdrawal_type.is_none() => {
+                    memo.withdrawal_type = Some(*withdrawal_type)
+                }
+                (TAG_TOKEN, token) if token.len() == 20 && memo.token.is_none() => {
+                    memo.token = Some(token.try_into().unwrap())
+                }
+                (TAG_WITHDRAWAL_TYPE | TAG_TOKEN, _) => {
+                    return Err(BurnMemoError::InvalidField(*tag))
+                }
+                _ => return Err(BurnMemoError::UnknownField(*tag)),
+            }
+            fields = &rest[*len as usize..];
+        }
+        if !fields.is_empty() {
+            return Err(BurnMemoError::Malformed);
+        }
+
+        Ok(memo)
+    }
+}
+
+/// Decode a burn memo in either the binary or the legacy hex format
+pub fn decode(memo: &Memo) -> Result<DecodedBurnMemo, BurnMemoError> {
+    if memo.0[0] == BINARY_MARKER {
+        BurnMemo::decode_binary(&memo.0).map(DecodedBurnMemo::Binary)
+    } else {
+        // expect unicode hex no 0x prefix
+        let recipient = hex::decode(&memo.0[0..40]).map_err(|_| BurnMemoError::InvalidLegacyHex)?;
+        Ok(DecodedBurnMemo::LegacyHex {
+            recipient: recipient.try_into().unwrap(),
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(CHECKSUM_PERSONALIZATION)
+        .hash(bytes)
+        .as_bytes()[..CHECKSUM_SIZE]
+        .try_into()
+        .unwrap()
+}
@@ -0,0 +1,135 @@
+//! The identity of a CarteZcash instance.
+//!
+//! There can be many CarteZcash instances (e.g. one per asset and base chain) so transactions made for one
+//! instance must not be valid on any other. Each instance has a unique chain name (see docs/chainspec.md)
+//! from which its address encodings, instance id and consensus branch id are derived.
+//!
+//! Every instance follows the NU5 rules and transactions carry the NU5 branch id in their header.
+//!
+//! - Orchard signatures are made over the ZIP-244 sighash computed with the consensus branch id of the
+//!   instance rather than that of NU5 (see [`crate::sighash`]), so they are only valid on that instance.
+//! - Every coinbase includes the instance id so all transparent outputs, and therefore all transparent
+//!   signatures which commit to the outputs they spend, are unique to the instance.
+
+use std::fmt;
+
+use zebra_chain::parameters::{Network, NetworkUpgrade};
+use zebra_chain::transaction::{SigHash, Transaction};
+use zebra_chain::transparent;
+
+const INSTANCE_ID_PERSONALIZATION: &[u8; 16] = b"CarteZcashInstId";
+const BRANCH_ID_PERSONALIZATION: &[u8; 16] = b"CarteZcashBrchId";
+
+const TESTNET_PREFIX: &str = "testnet.";
+
+/// The network upgrade whose rules every instance follows from the block after genesis
+const NETWORK_UPGRADE: NetworkUpgrade = NetworkUpgrade::Nu5;
+
+/// Chain name, address encodings, instance id and consensus branch id of a CarteZcash instance
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainSpec {
+    chain_name: String,
+    network: Network,
+    instance_id: InstanceId,
+    consensus_branch_id: ConsensusBranchId,
+}
+
+/// A Zcash consensus branch id, displayed as 8 hex characters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConsensusBranchId(pub u32);
+
+impl fmt::Display for ConsensusBranchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// An id unique to a CarteZcash instance, included in the coinbase of every block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceId(pub u32);
+
+impl fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl ChainSpec {
+    /// Create the spec for the instance with the given chain name.
+    /// Instances with a `testnet.` chain name use the Zcash testnet address encodings
+    pub fn new(chain_name: impl Into<String>) -> Self {
+        let chain_name = chain_name.into();
+        let network = if chain_name.starts_with(TESTNET_PREFIX) {
+            Network::Testnet
+        } else {
+            Network::Mainnet
+        };
+        Self {
+            network,
+            instance_id: InstanceId(derive_u32(INSTANCE_ID_PERSONALIZATION, &chain_name)),
+            consensus_branch_id: ConsensusBranchId(derive_u32(
+                BRANCH_ID_PERSONALIZATION,
+                &chain_name,
+            )),
+            chain_name,
+        }
+    }
+
+    /// The unique name of this instance, e.g. `testnet.eth.421614`
+    pub fn chain_name(&self) -> &str {
+        &self.chain_name
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// The network upgrade transactions must be made for, whose branch id is in their header
+    pub fn network_upgrade(&self) -> NetworkUpgrade {
+        NETWORK_UPGRADE
+    }
+
+    /// The consensus branch id of this instance, as reported to wallets. Orchard signatures commit to it
+    pub fn consensus_branch_id(&self) -> ConsensusBranchId {
+        self.consensus_branch_id
+    }
+
+    /// The sighash the Orchard signatures of `tx` must be made over on this instance
+    pub fn shielded_sighash(
+        &self,
+        tx: &Transaction,
+        all_previous_outputs: &[transparent::Output],
+    ) -> SigHash {
+        crate::sighash::shielded_sighash(tx, self.consensus_branch_id, all_previous_outputs)
+    }
+
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance_id
+    }
+
+    /// Whether the chain name marks this instance as a testnet
+    pub fn is_testnet(&self) -> bool {
+        self.chain_name.starts_with(TESTNET_PREFIX)
+    }
+
+    // extra data included in the coinbase of every block
+    pub(crate) fn coinbase_data(&self) -> [u8; 4] {
+        self.instance_id.0.to_le_bytes()
+    }
+}
+
+impl Default for ChainSpec {
+    /// A local development instance
+    fn default() -> Self {
+        Self::new("testnet.eth.31337")
+    }
+}
+
+// the first 4 bytes of the personalized hash of the chain name, little-endian
+fn derive_u32(personalization: &[u8; 16], chain_name: &str) -> u32 {
+    let hash = blake2b_simd::Params::new()
+        .hash_length(4)
+        .personal(personalization)
+        .hash(chain_name.as_bytes());
+    u32::from_le_bytes(hash.as_bytes().try_into().expect("hash length is 4 bytes"))
+}
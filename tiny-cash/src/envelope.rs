@@ -0,0 +1,217 @@
+//! The envelope of inputs sent to the InputBox, shared by the rollup and clients submitting transactions.
+//!
+//! ```text
+//! version     1 byte (1)
+//! type        1 byte
+//! body        the rest of the input
+//! ```
+//!
+//! | type   | body                                                                  |
+//! |--------|-----------------------------------------------------------------------|
+//! | `0x00` | a single serialized transaction                                       |
+//! | `0x01` | serialized transactions back to back, included in one block           |
+//! | `0x02` | a zstd compressed batch (see below), included in one block            |
+//! | `0x03` | an admin command, only accepted from the configured admin address     |
+//!
+//! The decompressed body of a compressed batch is a list of serialized transactions, each prefixed by its
+//! length as a big-endian u32. Batches can be at most [`MAX_DECOMPRESSED_BATCH_SIZE`] bytes once decompressed.
+//!
+//! During the migration to the envelope an input that is a raw serialized v5 transaction is still accepted.
+//! These start with the first byte of the v5 header (`0x05`), which is never a valid envelope version.
+
+use std::io::Read;
+
+use zebra_chain::{
+    amount::{Amount, NonNegative},
+    serialization::{SerializationError, ZcashDeserialize},
+    transaction::Transaction,
+};
+
+/// The current envelope version
+pub const VERSION: u8 = 1;
+
+/// The largest a compressed batch can be once decompressed
+pub const MAX_DECOMPRESSED_BATCH_SIZE: usize = 32 * 1024 * 1024;
+
+// zstd level used by the encoder. Calldata costs far more than the time spent compressing
+const COMPRESSION_LEVEL: i32 = 19;
+
+// the first byte of a serialized v5 transaction
+const LEGACY_V5_TRANSACTION: u8 = 0x05;
+
+const TYPE_TRANSACTION: u8 = 0x00;
+const TYPE_BATCH: u8 = 0x01;
+const TYPE_COMPRESSED_BATCH: u8 = 0x02;
+const TYPE_ADMIN: u8 = 0x03;
+
+const ADMIN_SET_DEPOSIT_CAP: u8 = 0x00;
+
+/// Why an input couldn't be decoded. The input is rejected with a report of the error
+#[derive(thiserror::Error, Debug)]
+pub enum EnvelopeError {
+    #[error("input is empty")]
+    Empty,
+    #[error("unsupported input envelope version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown input type {0:#04x}")]
+    UnknownType(u8),
+    #[error("invalid transaction: {0}")]
+    InvalidTransaction(#[from] SerializationError),
+    #[error("input has {0} bytes of trailing data")]
+    TrailingData(usize),
+    #[error("transaction batch is empty")]
+    EmptyBatch,
+    #[error("invalid compressed batch: {0}")]
+    InvalidCompressedBatch(std::io::Error),
+    #[error("compressed batch is larger than {MAX_DECOMPRESSED_BATCH_SIZE} bytes")]
+    CompressedBatchTooLarge,
+    #[error("compressed batch has a truncated transaction")]
+    TruncatedTransaction,
+    #[error("admin commands are not accepted from 0x{}", hex::encode(.0))]
+    UnauthorizedAdmin([u8; 20]),
+    #[error("invalid admin command")]
+    InvalidAdminCommand,
+}
+
+/// The decoded body of an input
+#[derive(Debug)]
+pub enum Envelope {
+    Transaction(Transaction),
+    Batch(Vec<Transaction>),
+    Admin(AdminCommand),
+}
+
+/// Commands that change how the instance handles inputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Set or remove the cap on deposits.
+    /// Body: `0x00`, then `0x00` to remove the cap or `0x01` followed by the cap in zatoshis (u64 big-endian)
+    SetDepositCap(Option<Amount<NonNegative>>),
+}
+
+/// Decode an input sent to the InputBox by the L1 address `sender`.
+/// `admin` is the L1 address admin commands are accepted from
+pub fn decode(
+    payload: &[u8],
+    sender: [u8; 20],
+    admin: Option<[u8; 20]>,
+) -> Result<Envelope, EnvelopeError> {
+    match payload {
+        [] => Err(EnvelopeError::Empty),
+        [LEGACY_V5_TRANSACTION, ..] => decode_transaction(payload).map(Envelope::Transaction),
+        [VERSION, TYPE_TRANSACTION, body @ ..] => {
+            decode_transaction(body).map(Envelope::Transaction)
+        }
+        [VERSION, TYPE_BATCH, body @ ..] => decode_batch(body).map(Envelope::Batch),
+        [VERSION, TYPE_COMPRESSED_BATCH, body @ ..] => {
+            decode_compressed_batch(body).map(Envelope::Batch)
+        }
+        [VERSION, TYPE_ADMIN, body @ ..] => {
+            if admin != Some(sender) {
+                return Err(EnvelopeError::UnauthorizedAdmin(sender));
+            }
+            decode_admin_command(body).map(Envelope::Admin)
+        }
+        [VERSION, ty, ..] => Err(EnvelopeError::UnknownType(*ty)),
+        [VERSION] => Err(EnvelopeError::Empty),
+        [version, ..] => Err(EnvelopeError::UnsupportedVersion(*version)),
+    }
+}
+
+/// Encode an envelope holding a single serialized transaction
+pub fn encode_transaction(transaction: &[u8]) -> Vec<u8> {
+    [&[VERSION, TYPE_TRANSACTION], transaction].concat()
+}
+
+/// Encode an envelope holding a batch of serialized transactions
+pub fn encode_batch<'a>(transactions: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut payload = vec![VERSION, TYPE_BATCH];
+    for transaction in transactions {
+        payload.extend_from_slice(transaction);
+    }
+    payload
+}
+
+/// Encode an envelope holding a compressed batch of serialized transactions.
+/// Relayers can use this to submit the transactions of many users in one input
+pub fn encode_compressed_batch<'a>(
+    transactions: impl IntoIterator<Item = &'a [u8]>,
+) -> std::io::Result<Vec<u8>> {
+    let mut batch = Vec::new();
+    for transaction in transactions {
+        let len = u32::try_from(transaction.len())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        batch.extend(len.to_be_bytes());
+        batch.extend_from_slice(transaction);
+    }
+    let compressed = zstd::encode_all(batch.as_slice(), COMPRESSION_LEVEL)?;
+    Ok([&[VERSION, TYPE_COMPRESSED_BATCH], compressed.as_slice()].concat())
+}
+
+fn decode_transaction(mut body: &[u8]) -> Result<Transaction, EnvelopeError> {
+    let transaction = Transaction::zcash_deserialize(&mut body)?;
+    if !body.is_empty() {
+        return Err(EnvelopeError::TrailingData(body.len()));
+    }
+    Ok(transaction)
+}
+
+fn decode_batch(mut body: &[u8]) -> Result<Vec<Transaction>, EnvelopeError> {
+    let mut transactions = Vec::new();
+    while !body.is_empty() {
+        transactions.push(Transaction::zcash_deserialize(&mut body)?);
+    }
+    if transactions.is_empty() {
+        return Err(EnvelopeError::EmptyBatch);
+    }
+    Ok(transactions)
+}
+
+fn decode_compressed_batch(body: &[u8]) -> Result<Vec<Transaction>, EnvelopeError> {
+    // read one byte past the limit to detect batches that are too large
+    let mut batch = Vec::new();
+    zstd::Decoder::new(body)
+        .map_err(EnvelopeError::InvalidCompressedBatch)?
+        .take(MAX_DECOMPRESSED_BATCH_SIZE as u64 + 1)
+        .read_to_end(&mut batch)
+        .map_err(EnvelopeError::InvalidCompressedBatch)?;
+    if batch.len() > MAX_DECOMPRESSED_BATCH_SIZE {
+        return Err(EnvelopeError::CompressedBatchTooLarge);
+    }
+
+    let mut transactions = Vec::new();
+    let mut rest = batch.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(EnvelopeError::TruncatedTransaction);
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if tail.len() < len {
+            return Err(EnvelopeError::TruncatedTransaction);
+        }
+        let (transaction, tail) = tail.split_at(len);
+        transactions.push(decode_transaction(transaction)?);
+        rest = tail;
+    }
+    if transactions.is_empty() {
+        return Err(EnvelopeError::EmptyBatch);
+    }
+    Ok(transactions)
+}
+
+fn decode_admin_command(body: &[u8]) -> Result<AdminCommand, EnvelopeError> {
+    match body {
+        [ADMIN_SET_DEPOSIT_CAP, 0] => Ok(AdminCommand::SetDepositCap(None)),
+        [ADMIN_SET_DEPOSIT_CAP, 1, cap @ ..] => {
+            let cap = u64::from_be_bytes(
+                cap.try_into()
+                    .map_err(|_| EnvelopeError::InvalidAdminCommand)?,
+            );
+            Amount::try_from(cap)
+                .map(|cap| AdminCommand::SetDepositCap(Some(cap)))
+                .map_err(|_| EnvelopeError::InvalidAdminCommand)
+        }
+        _ => Err(EnvelopeError::InvalidAdminCommand),
+    }
+}
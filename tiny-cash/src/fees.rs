@@ -0,0 +1,147 @@
+//! Minimum transaction fees and fee collection.
+//!
+//! The fee paid by a transaction is the value of the transparent outputs it spends, minus the value of the
+//! transparent outputs it creates, plus the Orchard value balance (value leaving the shielded pool).
+//! Every transaction must pay at least the fee required by the configured [`FeePolicy`] and the fees of all
+//! transactions in a block are paid out by its coinbase to the configured fee recipient.
+
+use std::str::FromStr;
+
+use zebra_chain::{
+    amount::{self, Amount, NegativeAllowed, NonNegative, MAX_MONEY},
+    serialization::ZcashSerialize,
+    transaction::Transaction,
+    transparent,
+};
+
+use crate::service::Error;
+
+/// ZIP-317 marginal fee per logical action in zatoshis
+pub const ZIP317_MARGINAL_FEE: i64 = 5_000;
+/// ZIP-317 number of logical actions that are always charged for
+pub const ZIP317_GRACE_ACTIONS: u32 = 2;
+
+// ZIP-317 standard sizes of a P2PKH input and output
+const P2PKH_STANDARD_INPUT_SIZE: usize = 150;
+const P2PKH_STANDARD_OUTPUT_SIZE: usize = 34;
+
+/// The minimum fee a transaction must pay to be included in a block
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeePolicy {
+    /// Any non-negative fee is accepted
+    #[default]
+    None,
+    /// Every transaction must pay at least this amount
+    Flat(Amount<NonNegative>),
+    /// The ZIP-317 conventional fee: `marginal_fee * max(grace_actions, logical_actions)`
+    Zip317 {
+        marginal_fee: Amount<NonNegative>,
+        grace_actions: u32,
+    },
+}
+
+impl FeePolicy {
+    /// ZIP-317 with the parameters used on Zcash mainnet
+    pub fn zip317() -> Self {
+        Self::Zip317 {
+            marginal_fee: Amount::try_from(ZIP317_MARGINAL_FEE).expect("valid amount"),
+            grace_actions: ZIP317_GRACE_ACTIONS,
+        }
+    }
+
+    /// The minimum fee the given transaction must pay under this policy
+    pub fn minimum_fee(&self, tx: &Transaction) -> Amount<NonNegative> {
+        match self {
+            Self::None => Amount::zero(),
+            Self::Flat(fee) => *fee,
+            Self::Zip317 {
+                marginal_fee,
+                grace_actions,
+            } => {
+                let actions = logical_actions(tx).max(*grace_actions as usize) as u64;
+                (*marginal_fee * actions)
+                    .unwrap_or_else(|_| Amount::try_from(MAX_MONEY).expect("valid amount"))
+            }
+        }
+    }
+
+    /// Check the transaction pays enough fees and return the fee it pays.
+    /// `spent_outputs` are the transparent outputs spent by the transaction's inputs
+    pub fn check(
+        &self,
+        tx: &Transaction,
+        spent_outputs: &[transparent::Output],
+    ) -> Result<Amount<NonNegative>, Error> {
+        let paid = transaction_fee(tx, spent_outputs)?;
+        let required = self.minimum_fee(tx);
+        if i64::from(paid) < i64::from(required) {
+            return Err(Error::InsufficientFee { required, paid });
+        }
+        paid.constrain()
+            .map_err(|e| Error::InvalidTransaction(e.to_string()))
+    }
+}
+
+impl FromStr for FeePolicy {
+    type Err = String;
+
+    /// Parses `none`, `zip317` or `flat:<zatoshis>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "zip317" => Ok(Self::zip317()),
+            _ => s
+                .strip_prefix("flat:")
+                .and_then(|fee| fee.parse::<i64>().ok())
+                .and_then(|fee| Amount::try_from(fee).ok())
+                .map(Self::Flat)
+                .ok_or_else(|| format!("invalid fee policy {:?}", s)),
+        }
+    }
+}
+
+/// The fee paid by the transaction. This is negative if the transaction creates value.
+/// Fails if any of the sums leaves the valid range of amounts
+pub fn transaction_fee(
+    tx: &Transaction,
+    spent_outputs: &[transparent::Output],
+) -> Result<Amount<NegativeAllowed>, Error> {
+    let spent = sum(spent_outputs.iter().map(|o| o.value))?;
+    let created = sum(tx.outputs().iter().map(|o| o.value))?;
+    let orchard_value_balance = tx
+        .orchard_shielded_data()
+        .map(|data| data.value_balance)
+        .unwrap_or_else(Amount::zero);
+    (spent - created)
+        .and_then(|fee| fee + orchard_value_balance)
+        .map_err(value_out_of_range)
+}
+
+fn sum(
+    values: impl Iterator<Item = Amount<NonNegative>>,
+) -> Result<Amount<NegativeAllowed>, Error> {
+    values
+        .sum::<Result<Amount<NonNegative>, _>>()
+        .and_then(|total| total.constrain())
+        .map_err(value_out_of_range)
+}
+
+fn value_out_of_range(e: amount::Error) -> Error {
+    Error::InvalidTransaction(format!("transaction value is out of range ({})", e))
+}
+
+// The number of ZIP-317 logical actions in the transaction
+fn logical_actions(tx: &Transaction) -> usize {
+    let input_size: usize = tx.inputs().iter().map(serialized_size).sum();
+    let output_size: usize = tx.outputs().iter().map(serialized_size).sum();
+    let transparent_actions = input_size
+        .div_ceil(P2PKH_STANDARD_INPUT_SIZE)
+        .max(output_size.div_ceil(P2PKH_STANDARD_OUTPUT_SIZE));
+    transparent_actions + tx.orchard_actions().count()
+}
+
+fn serialized_size<T: ZcashSerialize>(item: &T) -> usize {
+    item.zcash_serialize_to_vec()
+        .expect("serializing to a Vec cannot fail")
+        .len()
+}
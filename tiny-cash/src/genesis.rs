@@ -0,0 +1,121 @@
+//! Deterministic, instance specific genesis blocks.
+//!
+//! The genesis block contains a single coinbase transaction with one zero value output whose script is
+//!
+//! ```text
+//! OP_RETURN <chain name> <consensus branch id (u32 LE)> <dApp address (20 bytes, empty if not set)> <params digest>
+//! ```
+//!
+//! where the params digest is the BLAKE2b-256 hash (personalization `CarteZcashGenPrm`) of the other
+//! consensus parameters of the instance (see [`params_digest`]). Two instances with different chain names,
+//! dApps or parameters therefore always have different genesis hashes. Every node of an instance must be
+//! configured with the same dApp address.
+//!
+//! The coinbase data must be the Zcash `GENESIS_COINBASE_DATA` as this is how a genesis coinbase
+//! is identified when deserializing it. Like in Zcash, the genesis outputs are never added to the UTXO set.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use zebra_chain::{
+    amount::Amount,
+    block::{self, Block, Header, Height},
+    fmt::HexDebug,
+    transaction::{LockTime, Transaction},
+    transparent::{self, Script, GENESIS_COINBASE_DATA},
+    work::{difficulty::CompactDifficulty, equihash::Solution},
+};
+
+use crate::fees::FeePolicy;
+use crate::service::Config;
+use crate::{push_data, OP_RETURN};
+
+const PARAMS_PERSONALIZATION: &[u8; 16] = b"CarteZcashGenPrm";
+
+/// Build the genesis block for the instance with the given configuration
+pub fn genesis_block(config: &Config) -> Block {
+    let chain_spec = &config.chain_spec;
+
+    let mut script = vec![OP_RETURN];
+    push_data(&mut script, chain_spec.chain_name().as_bytes());
+    push_data(
+        &mut script,
+        &chain_spec.consensus_branch_id().0.to_le_bytes(),
+    );
+    push_data(
+        &mut script,
+        config.dapp_address.as_ref().map_or(&[][..], |a| &a[..]),
+    );
+    push_data(&mut script, &params_digest(config));
+
+    // built directly rather than with `Transaction::new_v5_coinbase` as there is no network upgrade
+    // with a branch id active at height 0
+    let coinbase = Transaction::V5 {
+        network_upgrade: chain_spec.network_upgrade(),
+        lock_time: LockTime::unlocked(),
+        expiry_height: Height(0),
+        inputs: vec![transparent::Input::new_coinbase(
+            Height(0),
+            Some(GENESIS_COINBASE_DATA.to_vec()),
+            None,
+        )],
+        outputs: vec![transparent::Output {
+            value: Amount::zero(),
+            lock_script: Script::new(&script),
+        }],
+        sapling_shielded_data: None,
+        orchard_shielded_data: None,
+    };
+    let transactions = vec![Arc::new(coinbase)];
+
+    Block {
+        header: Header {
+            version: 5,
+            previous_block_hash: block::Hash([0; 32]),
+            merkle_root: transactions.iter().collect(),
+            commitment_bytes: HexDebug::default(),
+            time: DateTime::<Utc>::default(),
+            difficulty_threshold: CompactDifficulty::default(),
+            nonce: HexDebug::default(),
+            solution: Solution::default(),
+        }
+        .into(),
+        transactions,
+    }
+}
+
+/// Commitment to the consensus parameters of an instance that are not otherwise in the genesis block:
+/// the nullifier accumulator, the anchor window, the fee policy and the fee recipient
+pub fn params_digest(config: &Config) -> [u8; 32] {
+    let mut state = blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(PARAMS_PERSONALIZATION)
+        .to_state();
+
+    state.update(&[config.nullifier_accumulator.to_byte()]);
+    state.update(&config.anchor_window.to_bytes());
+    match config.fee_policy {
+        FeePolicy::None => state.update(&[0]),
+        FeePolicy::Flat(fee) => state.update(&[1]).update(&i64::from(fee).to_le_bytes()),
+        FeePolicy::Zip317 {
+            marginal_fee,
+            grace_actions,
+        } => state
+            .update(&[2])
+            .update(&i64::from(marginal_fee).to_le_bytes())
+            .update(&grace_actions.to_le_bytes()),
+    };
+    match &config.fee_recipient {
+        Some(script) => state
+            .update(&[1])
+            .update(&(script.as_raw_bytes().len() as u32).to_le_bytes())
+            .update(script.as_raw_bytes()),
+        None => state.update(&[0]),
+    };
+
+    state
+        .finalize()
+        .as_bytes()
+        .try_into()
+        .expect("hash length is 32 bytes")
+}
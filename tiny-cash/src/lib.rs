@@ -1,13 +1,22 @@
 use orchard::{
-    keys::{FullViewingKey, IncomingViewingKey, PreparedIncomingViewingKey, Scope, SpendingKey},
+    keys::{FullViewingKey, IncomingViewingKey, PreparedIncomingViewingKey, Scope},
     note::{ExtractedNoteCommitment, Nullifier},
     note_encryption::OrchardDomain,
 };
+use pasta_curves::{
+    arithmetic::CurveExt,
+    group::{
+        ff::{FromUniformBytes, PrimeField},
+        GroupEncoding,
+    },
+    pallas,
+};
 use zcash_note_encryption::{
     try_note_decryption, EphemeralKeyBytes, ShieldedOutput, ENC_CIPHERTEXT_SIZE,
 };
 use zebra_state::IntoDisk;
 
+pub use state::spendable_outputs;
 pub use zebra_chain::{
     amount::{self, Amount, NonNegative},
     block,
@@ -18,37 +27,168 @@ pub use zebra_chain::{
 };
 pub use zebra_state::SemanticallyVerifiedBlock;
 
+pub mod anchors;
+pub mod burn_memo;
+pub mod chain_spec;
+pub mod envelope;
+pub mod fees;
+pub mod genesis;
+pub mod nullifiers;
 pub mod service;
+mod set_hash;
+#[cfg(any(test, feature = "shielded-mint"))]
+mod shielded_mint;
+pub mod sighash;
+pub mod snapshot;
+mod state;
+pub mod state_root;
 #[cfg(test)]
 mod test;
 
 // outputs send to this address cannot be recovered and are considered burned
 pub fn mt_doom_address() -> orchard::Address {
-    mt_doom().address_at(0_usize, Scope::External)
+    MT_DOOM.address_at(0_usize, Scope::External)
 }
 
 pub fn mt_doom_ivk() -> IncomingViewingKey {
-    mt_doom().to_ivk(Scope::External)
+    MT_DOOM.to_ivk(Scope::External)
+}
+
+/// The full viewing key of the Mt Doom address
+pub fn mt_doom_fvk() -> FullViewingKey {
+    MT_DOOM.clone()
+}
+
+const MT_DOOM_DOMAIN: &str = "CarteZcash-MtDoom";
+const MT_DOOM_NK_PERSONALIZATION: &[u8; 16] = b"CarteZcashMtDmNk";
+const MT_DOOM_RIVK_PERSONALIZATION: &[u8; 16] = b"CarteZcashMtDmRk";
+
+lazy_static::lazy_static! {
+    static ref MT_DOOM: FullViewingKey = mt_doom();
 }
 
+// A nothing-up-my-sleeve full viewing key.
+// The spend validating key `ak` is a hash-to-curve output so nobody knows its discrete log `ask`
+// and therefore nobody can produce a valid spend authorization signature for notes sent to Mt Doom.
+// `nk` and `rivk` are hashes of fixed strings so anyone can view incoming notes and derive their nullifiers.
 fn mt_doom() -> FullViewingKey {
-    // TODO: This is a disaster waiting to happen. Anyone can spend from this address rather than no one!
-    // We want an address with a known FVK but an known Spending key. This actually should be pretty easy to do
-    let sk = SpendingKey::from_bytes([0x0; 32]).unwrap();
-    FullViewingKey::from(&sk)
+    let mut ak = pallas::Point::hash_to_curve(MT_DOOM_DOMAIN)(b"ak");
+    // orchard requires the y-coordinate sign bit of ak to be zero. Negating keeps the discrete log unknown
+    if ak.to_bytes()[31] & 0x80 != 0 {
+        ak = -ak;
+    }
+    let nk = pallas::Base::from_uniform_bytes(&hash_to_64_bytes(MT_DOOM_NK_PERSONALIZATION));
+    let rivk = pallas::Scalar::from_uniform_bytes(&hash_to_64_bytes(MT_DOOM_RIVK_PERSONALIZATION));
+
+    let mut bytes = [0; 96];
+    bytes[..32].copy_from_slice(&ak.to_bytes());
+    bytes[32..64].copy_from_slice(&nk.to_repr());
+    bytes[64..].copy_from_slice(&rivk.to_repr());
+    FullViewingKey::from_bytes(&bytes).expect("Mt Doom full viewing key is valid")
+}
+
+fn hash_to_64_bytes(personalization: &[u8; 16]) -> [u8; 64] {
+    blake2b_simd::Params::new()
+        .hash_length(64)
+        .personal(personalization)
+        .hash(MT_DOOM_DOMAIN.as_bytes())
+        .as_bytes()
+        .try_into()
+        .expect("hash length is 64 bytes")
 }
 
 // Attempt to decrypt action. It it was encrypted to Mt Doom address, return the amount and memo
 pub fn extract_burn_info(action: &Action) -> Option<(Amount<NonNegative>, Memo)> {
+    decrypt_burned_note(action).map(|(note, memo)| {
+        let memo = memo[..].try_into().unwrap();
+        (Amount::try_from(note.value().inner()).unwrap(), memo)
+    })
+}
+
+/// The nullifier that would be revealed by spending the note created by this action if it was sent to Mt Doom.
+/// These are added to the nullifier set when the note is created so burned notes can never be spent
+pub fn burned_note_nullifier(action: &Action) -> Option<zebra_chain::orchard::Nullifier> {
+    decrypt_burned_note(action).map(|(note, _)| {
+        zebra_chain::orchard::Nullifier::try_from(note.nullifier(&MT_DOOM).to_bytes())
+            .expect("orchard nullifiers are valid")
+    })
+}
+
+pub(crate) const OP_RETURN: u8 = 0x6a;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+
+/// The unspendable script a transparent output pays to burn its value.
+/// `OP_RETURN <"CarteZcash-MtDoom"> <memo>` where the memo has its trailing zeros removed.
+/// The memo says where to pay the withdrawal exactly like the memo of a shielded burn
+pub fn mt_doom_script(memo: &Memo) -> transparent::Script {
+    let len = memo.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let mut script = vec![OP_RETURN];
+    push_data(&mut script, MT_DOOM_DOMAIN.as_bytes());
+    push_data(&mut script, &memo.0[..len]);
+    transparent::Script::new(&script)
+}
+
+/// If the output pays the Mt Doom script, return the amount and memo
+pub fn extract_transparent_burn_info(
+    output: &transparent::Output,
+) -> Option<(Amount<NonNegative>, Memo)> {
+    let script = output.lock_script.as_raw_bytes();
+    let (&OP_RETURN, script) = script.split_first()?;
+    let (domain, script) = read_push_data(script)?;
+    let (memo, script) = read_push_data(script)?;
+    if domain != MT_DOOM_DOMAIN.as_bytes() || !script.is_empty() || memo.len() > 512 {
+        return None;
+    }
+    let mut padded = [0; 512];
+    padded[..memo.len()].copy_from_slice(memo);
+    Some((output.value, Memo(Box::new(padded))))
+}
+
+// append a script instruction that pushes the data onto the stack
+pub(crate) fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        len @ 0..=0x4b => script.push(len as u8),
+        len @ 0x4c..=0xff => script.extend([OP_PUSHDATA1, len as u8]),
+        len @ 0x100..=0xffff => {
+            script.push(OP_PUSHDATA2);
+            script.extend((len as u16).to_le_bytes());
+        }
+        _ => panic!("script data is limited to 65535 bytes"),
+    }
+    script.extend_from_slice(data);
+}
+
+// read a push data instruction from the start of the script, returning the data and the rest of the script
+fn read_push_data(script: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&op, script) = script.split_first()?;
+    let (len, script) = match op {
+        0..=0x4b => (op as usize, script),
+        OP_PUSHDATA1 => (*script.first()? as usize, &script[1..]),
+        OP_PUSHDATA2 => (
+            u16::from_le_bytes(script.get(..2)?.try_into().unwrap()) as usize,
+            &script[2..],
+        ),
+        _ => return None,
+    };
+    (script.len() >= len).then(|| script.split_at(len))
+}
+
+fn decrypt_burned_note(action: &Action) -> Option<(orchard::Note, [u8; 512])> {
+    decrypt_note(action, &mt_doom_ivk())
+}
+
+// Attempt to decrypt the note created by the action with the given incoming viewing key
+pub(crate) fn decrypt_note(
+    action: &Action,
+    ivk: &IncomingViewingKey,
+) -> Option<(orchard::Note, [u8; 512])> {
     try_note_decryption(
         &OrchardDomain::for_compact_action(&&compact_action_from(action)),
-        &PreparedIncomingViewingKey::new(&mt_doom_ivk()),
+        &PreparedIncomingViewingKey::new(ivk),
         &DecryptableAction(action.clone()),
     )
-    .map(|(note, _, memo)| {
-        let memo = memo[..].try_into().unwrap();
-        (Amount::try_from(note.value().inner()).unwrap(), memo)
-    })
+    .map(|(note, _, memo)| (note, memo))
 }
 
 struct DecryptableAction(Action);
@@ -70,11 +210,50 @@ impl ShieldedOutput<OrchardDomain, ENC_CIPHERTEXT_SIZE> for DecryptableAction {
 /// force initialization of the Orchard verifying key.
 /// This is an expensive but one-off operation
 /// if it isn't forced by falling this function
-/// it will be initialized the first time a shielded transaction is verified
+/// it will be initialized the first time a shielded transaction is verified.
+///
+/// The key can't yet be built ahead of time and loaded from a file. Neither `orchard::circuit::VerifyingKey`
+/// nor the `halo2_proofs` verifying key it wraps can be serialized, and zebra-consensus builds its own
+/// `VERIFYING_KEY` static with `VerifyingKey::build()` so a loaded key couldn't be handed to it.
 pub fn initialize_halo2() {
     lazy_static::initialize(&zebra_consensus::halo2::VERIFYING_KEY);
 }
 
+const VERIFYING_KEY_PERSONALIZATION: &[u8; 16] = b"CarteZcashOrchVk";
+
+/// A digest of the Orchard verifying key, building it if [`initialize_halo2`] hasn't been called.
+/// Nodes compare it against a pinned digest to check they verify proofs with the same key.
+/// As the key can't be serialized this is the BLAKE2b-256 hash of its `Debug` representation
+pub fn halo2_verifying_key_digest() -> [u8; 32] {
+    verifying_key_digest(&*zebra_consensus::halo2::VERIFYING_KEY)
+}
+
+fn verifying_key_digest(key: &impl std::fmt::Debug) -> [u8; 32] {
+    struct HashWriter(blake2b_simd::State);
+
+    impl std::fmt::Write for HashWriter {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            self.0.update(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut writer = HashWriter(
+        blake2b_simd::Params::new()
+            .hash_length(32)
+            .personal(VERIFYING_KEY_PERSONALIZATION)
+            .to_state(),
+    );
+    std::fmt::Write::write_fmt(&mut writer, format_args!("{:?}", key))
+        .expect("hashing never fails");
+    writer
+        .0
+        .finalize()
+        .as_bytes()
+        .try_into()
+        .expect("hash length is 32 bytes")
+}
+
 fn compact_action_from(action: &Action) -> orchard::note_encryption::CompactAction {
     orchard::note_encryption::CompactAction::from_parts(
         Nullifier::from_bytes(&action.nullifier.as_bytes()).unwrap(),
@@ -0,0 +1,394 @@
+//! Accumulators for the set of revealed Orchard nullifiers.
+//!
+//! TinyCash only needs to answer "has this nullifier been seen before?" but how the set is stored
+//! is a trade-off between state size and the work needed to commit to it. The accumulator is chosen
+//! through [`NullifierAccumulatorKind`] when creating a TinyCash instance.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use zebra_chain::orchard::Nullifier;
+
+use crate::set_hash::SetHash;
+
+const HASH_SET_PERSONALIZATION: &[u8; 16] = b"TinyCashNullifs_";
+const HASH_SET_DOMAIN: &str = "TinyCash-Nullifiers";
+const SMT_LEAF_PERSONALIZATION: &[u8; 16] = b"TinyCashNfSmtLf_";
+const SMT_NODE_PERSONALIZATION: &[u8; 16] = b"TinyCashNfSmtNd_";
+
+/// Depth of the sparse Merkle tree. There is one leaf for every possible 32 byte nullifier
+pub const SMT_DEPTH: usize = 256;
+
+// the hash of a subtree without any nullifiers
+const EMPTY_SUBTREE: [u8; 32] = [0; 32];
+
+/// The available nullifier accumulators
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullifierAccumulatorKind {
+    /// Keep every nullifier in a hash set. The root is an incremental multiset hash of the set
+    #[default]
+    HashSet,
+    /// Keep nullifiers as the leaves of a compact sparse Merkle tree keyed by the nullifier bytes.
+    /// The root can be updated in O(log n) and supports O(log n) (non-)membership proofs
+    SparseMerkle,
+}
+
+impl NullifierAccumulatorKind {
+    pub fn build(self) -> Box<dyn NullifierAccumulator> {
+        match self {
+            Self::HashSet => Box::<HashSetNullifiers>::default(),
+            Self::SparseMerkle => Box::<SparseMerkleNullifiers>::default(),
+        }
+    }
+
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Self::HashSet => 0,
+            Self::SparseMerkle => 1,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::HashSet),
+            1 => Some(Self::SparseMerkle),
+            _ => None,
+        }
+    }
+}
+
+/// A set of nullifiers that can produce a commitment to its contents
+pub trait NullifierAccumulator: fmt::Debug + Send + Sync {
+    fn kind(&self) -> NullifierAccumulatorKind;
+
+    fn contains(&self, nullifier: &Nullifier) -> bool;
+
+    fn insert(&mut self, nullifier: Nullifier);
+
+    /// Remove a nullifier when a block is rolled back
+    fn remove(&mut self, nullifier: &Nullifier);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All nullifiers in the set sorted by their byte encoding
+    fn nullifiers(&self) -> Vec<Nullifier>;
+
+    /// A commitment to the current contents of the set
+    fn root(&self) -> [u8; 32];
+
+    fn boxed_clone(&self) -> Box<dyn NullifierAccumulator>;
+}
+
+/// The original unbounded nullifier set
+#[derive(Clone, Debug)]
+pub struct HashSetNullifiers {
+    nullifiers: HashSet<Nullifier>,
+    // kept up to date on every insert and remove so the root never needs the whole set
+    digest: SetHash,
+}
+
+impl Default for HashSetNullifiers {
+    fn default() -> Self {
+        Self {
+            nullifiers: HashSet::new(),
+            digest: SetHash::new(HASH_SET_DOMAIN),
+        }
+    }
+}
+
+impl NullifierAccumulator for HashSetNullifiers {
+    fn kind(&self) -> NullifierAccumulatorKind {
+        NullifierAccumulatorKind::HashSet
+    }
+
+    fn contains(&self, nullifier: &Nullifier) -> bool {
+        self.nullifiers.contains(nullifier)
+    }
+
+    fn insert(&mut self, nullifier: Nullifier) {
+        if self.nullifiers.insert(nullifier) {
+            self.digest.insert(&<[u8; 32]>::from(nullifier));
+        }
+    }
+
+    fn remove(&mut self, nullifier: &Nullifier) {
+        if self.nullifiers.remove(nullifier) {
+            self.digest.remove(&<[u8; 32]>::from(*nullifier));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.nullifiers.len()
+    }
+
+    fn nullifiers(&self) -> Vec<Nullifier> {
+        sorted(self.nullifiers.iter().copied())
+    }
+
+    // Hash of the count and the multiset hash of the nullifiers (see [`crate::set_hash`])
+    fn root(&self) -> [u8; 32] {
+        self.digest
+            .commitment(HASH_SET_PERSONALIZATION, self.nullifiers.len())
+    }
+
+    fn boxed_clone(&self) -> Box<dyn NullifierAccumulator> {
+        Box::new(self.clone())
+    }
+}
+
+/// A compact sparse Merkle tree keyed by the nullifier bytes.
+///
+/// Conceptually there is a leaf for every possible nullifier but only the subtrees holding at least
+/// two nullifiers are stored as nodes. A subtree holding a single nullifier is stored, and hashed,
+/// as just that leaf wherever it sits in the tree, and an empty subtree hashes to all zeros. The tree
+/// therefore has a single canonical form for each set of nullifiers and stores a few nodes for each
+/// nullifier rather than one for every level of the tree.
+///
+/// The child of a node at depth `d` holding a key is selected by bit `d` of the key, most significant first.
+#[derive(Clone, Debug, Default)]
+pub struct SparseMerkleNullifiers {
+    root: Subtree,
+    len: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+enum Subtree {
+    #[default]
+    Empty,
+    Leaf([u8; 32]),
+    /// A subtree holding at least two leaves and its hash
+    Node {
+        hash: [u8; 32],
+        children: Box<[Subtree; 2]>,
+    },
+}
+
+/// A Merkle path from the root of a [`SparseMerkleNullifiers`] tree towards the leaf of a nullifier.
+///
+/// The path ends at the first empty subtree or leaf on the way down. For a tree of `n` random
+/// nullifiers this is O(log n) hashes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// The key of the leaf the path ends at, or `None` if it ends at an empty subtree
+    pub leaf: Option<[u8; 32]>,
+    /// The siblings of the nodes on the path ordered from the root down
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl Subtree {
+    fn hash(&self) -> [u8; 32] {
+        match self {
+            Self::Empty => EMPTY_SUBTREE,
+            Self::Leaf(key) => leaf_hash(key),
+            Self::Node { hash, .. } => *hash,
+        }
+    }
+
+    fn node(children: [Subtree; 2]) -> Self {
+        Self::Node {
+            hash: node_hash(&children[0].hash(), &children[1].hash()),
+            children: Box::new(children),
+        }
+    }
+
+    // Insert the key into this subtree at the given depth. Returns false if it was already present
+    fn insert(&mut self, key: [u8; 32], depth: usize) -> bool {
+        match self {
+            Self::Empty => *self = Self::Leaf(key),
+            Self::Leaf(existing) if *existing == key => return false,
+            Self::Leaf(existing) => {
+                // split the leaf into a node holding both keys
+                let mut children = [Self::Empty, Self::Empty];
+                children[bit(existing, depth)] = Self::Leaf(*existing);
+                children[bit(&key, depth)].insert(key, depth + 1);
+                *self = Self::node(children);
+            }
+            Self::Node { children, .. } => {
+                if !children[bit(&key, depth)].insert(key, depth + 1) {
+                    return false;
+                }
+                *self = Self::node(std::mem::take(children.as_mut()));
+            }
+        }
+        true
+    }
+
+    // Remove the key from this subtree at the given depth. Returns false if it was not present
+    fn remove(&mut self, key: &[u8; 32], depth: usize) -> bool {
+        match self {
+            Self::Leaf(existing) if existing == key => *self = Self::Empty,
+            Self::Node { children, .. } => {
+                if !children[bit(key, depth)].remove(key, depth + 1) {
+                    return false;
+                }
+                *self = match std::mem::take(children.as_mut()) {
+                    // a node left holding a single leaf collapses into that leaf
+                    [Self::Empty, Self::Leaf(leaf)] | [Self::Leaf(leaf), Self::Empty] => {
+                        Self::Leaf(leaf)
+                    }
+                    children => Self::node(children),
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn collect_leaves(&self, leaves: &mut Vec<[u8; 32]>) {
+        match self {
+            Self::Empty => {}
+            Self::Leaf(key) => leaves.push(*key),
+            Self::Node { children, .. } => {
+                children[0].collect_leaves(leaves);
+                children[1].collect_leaves(leaves);
+            }
+        }
+    }
+}
+
+impl SparseMerkleNullifiers {
+    /// Produce the Merkle path towards the leaf of the given nullifier.
+    /// Used with [`verify_membership`] if the nullifier is in the set or [`verify_non_membership`] if not
+    pub fn prove(&self, nullifier: &Nullifier) -> MerkleProof {
+        let key = <[u8; 32]>::from(*nullifier);
+        let mut siblings = Vec::new();
+        let mut subtree = &self.root;
+        let mut depth = 0;
+        while let Subtree::Node { children, .. } = subtree {
+            siblings.push(children[1 - bit(&key, depth)].hash());
+            subtree = &children[bit(&key, depth)];
+            depth += 1;
+        }
+        MerkleProof {
+            leaf: match subtree {
+                Subtree::Leaf(leaf) => Some(*leaf),
+                _ => None,
+            },
+            siblings,
+        }
+    }
+}
+
+impl NullifierAccumulator for SparseMerkleNullifiers {
+    fn kind(&self) -> NullifierAccumulatorKind {
+        NullifierAccumulatorKind::SparseMerkle
+    }
+
+    fn contains(&self, nullifier: &Nullifier) -> bool {
+        self.prove(nullifier).leaf == Some(<[u8; 32]>::from(*nullifier))
+    }
+
+    fn insert(&mut self, nullifier: Nullifier) {
+        if self.root.insert(<[u8; 32]>::from(nullifier), 0) {
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, nullifier: &Nullifier) {
+        if self.root.remove(&<[u8; 32]>::from(*nullifier), 0) {
+            self.len -= 1;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn nullifiers(&self) -> Vec<Nullifier> {
+        let mut leaves = Vec::with_capacity(self.len);
+        self.root.collect_leaves(&mut leaves);
+        sorted(
+            leaves
+                .into_iter()
+                .map(|key| Nullifier::try_from(key).expect("only valid nullifiers are inserted")),
+        )
+    }
+
+    fn root(&self) -> [u8; 32] {
+        self.root.hash()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn NullifierAccumulator> {
+        Box::new(self.clone())
+    }
+}
+
+/// Check the proof shows the nullifier is in the tree with the given root
+pub fn verify_membership(root: &[u8; 32], nullifier: &Nullifier, proof: &MerkleProof) -> bool {
+    let key = <[u8; 32]>::from(*nullifier);
+    proof.leaf == Some(key) && root_from_path(&key, proof).as_ref() == Some(root)
+}
+
+/// Check the proof shows the nullifier is NOT in the tree with the given root
+pub fn verify_non_membership(root: &[u8; 32], nullifier: &Nullifier, proof: &MerkleProof) -> bool {
+    let key = <[u8; 32]>::from(*nullifier);
+    proof.leaf != Some(key) && root_from_path(&key, proof).as_ref() == Some(root)
+}
+
+// The root of the tree if the path is a valid path towards the key
+fn root_from_path(key: &[u8; 32], proof: &MerkleProof) -> Option<[u8; 32]> {
+    if proof.siblings.len() > SMT_DEPTH {
+        return None;
+    }
+    let mut hash = match &proof.leaf {
+        // a leaf can only be on the path if its key starts with the same bits
+        Some(leaf)
+            if (0..proof.siblings.len()).any(|depth| bit(leaf, depth) != bit(key, depth)) =>
+        {
+            return None
+        }
+        Some(leaf) => leaf_hash(leaf),
+        None => EMPTY_SUBTREE,
+    };
+    for (depth, sibling) in proof.siblings.iter().enumerate().rev() {
+        hash = if bit(key, depth) == 1 {
+            node_hash(sibling, &hash)
+        } else {
+            node_hash(&hash, sibling)
+        };
+    }
+    Some(hash)
+}
+
+// Bit `depth` of the key, most significant first. This selects the child of a node at that depth
+fn bit(key: &[u8; 32], depth: usize) -> usize {
+    ((key[depth / 8] >> (7 - depth % 8)) & 1) as usize
+}
+
+fn leaf_hash(key: &[u8; 32]) -> [u8; 32] {
+    let mut state = hasher(SMT_LEAF_PERSONALIZATION);
+    state.update(key);
+    finalize(&state)
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut state = hasher(SMT_NODE_PERSONALIZATION);
+    state.update(left);
+    state.update(right);
+    finalize(&state)
+}
+
+fn sorted(nullifiers: impl Iterator<Item = Nullifier>) -> Vec<Nullifier> {
+    let mut nullifiers: Vec<_> = nullifiers.collect();
+    nullifiers.sort_by_key(|nullifier| <[u8; 32]>::from(*nullifier));
+    nullifiers
+}
+
+pub(crate) fn hasher(personalization: &[u8; 16]) -> blake2b_simd::State {
+    blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(personalization)
+        .to_state()
+}
+
+pub(crate) fn finalize(state: &blake2b_simd::State) -> [u8; 32] {
+    state
+        .finalize()
+        .as_bytes()
+        .try_into()
+        .expect("hash length is 32 bytes")
+}
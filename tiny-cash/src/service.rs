@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
-use futures_util::future::FutureExt;
+use futures_util::future::{join_all, BoxFuture, FutureExt, TryFutureExt};
+use futures_util::lock::Mutex;
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{HashMap, HashSet},
     future::Future,
     pin::Pin,
     sync::Arc,
@@ -9,18 +10,15 @@ use std::{
 };
 use tower::{buffer::Buffer, util::BoxService, BoxError};
 
-use zebra_chain::orchard::tree::NoteCommitmentTree;
+use zebra_chain::block;
 use zebra_chain::transparent;
 use zebra_chain::{
-    amount::{Amount, NonNegative},
+    amount::{Amount, NegativeAllowed, NonNegative},
     block::{Block, Header, Height},
     fmt::HexDebug,
-    parameters::{Network, NetworkUpgrade},
-    transaction::HashType,
-    transparent::{OrderedUtxo, OutPoint},
+    transaction::{self, LockTime},
     work::{difficulty::CompactDifficulty, equihash::Solution},
 };
-use zebra_chain::{block, serialization::ZcashDeserialize};
 use zebra_chain::{
     transaction::{Memo, Transaction},
     transparent::Script,
@@ -29,51 +27,115 @@ use zebra_consensus::script;
 use zebra_consensus::transaction as tx;
 use zebra_consensus::transaction::Verifier as TxVerifier;
 
-use crate::extract_burn_info;
+use crate::anchors::AnchorWindow;
+use crate::chain_spec::ChainSpec;
+use crate::fees::FeePolicy;
+use crate::genesis::genesis_block;
+use crate::nullifiers::NullifierAccumulatorKind;
+#[cfg(any(test, feature = "shielded-mint"))]
+use crate::shielded_mint::add_shielded_output;
+use crate::snapshot::SnapshotError;
+use crate::state::{spendable_outputs, ChainState};
+use crate::state_root::StateRoot;
+use crate::{extract_burn_info, extract_transparent_burn_info};
 
 pub type StateService = Buffer<
     BoxService<zebra_state::Request, zebra_state::Response, zebra_state::BoxError>,
     zebra_state::Request,
 >;
 
+/// The TinyCash state transition service.
+///
+/// Cloning gives another handle to the same chain state.
+#[derive(Clone)]
 pub struct TinyCash {
-    // Current tip of the chain
-    tip_height: Option<Height>,
-    tip_hash: Option<block::Hash>,
-
-    // Set of all unspent transparent outputs.
-    // This is expected to grow but very slowly since transparent outputs are only created
-    // by deposits and destroyed by subsequent spends.
-    utxos_set: HashMap<OutPoint, OrderedUtxo>,
-
-    // The frontier of the commitment tree. This is the Merkle path of the last added commitment
-    // Internally this just stores the frontier which is the Merkle path of the most recently added note
-    // This is all that is needed to update the root and produce a new frontier when new commitments are added
-    // So for a fixed depth this is constant size!
-    commitment_tree_frontier: NoteCommitmentTree,
-
-    // A FILO queue of orchard commitment tree roots.
-    // Only a fixed window are kept so we can have constant state.
-    // This means older witnesses become invalid after a period of time
-    historical_tree_roots: VecDeque<zebra_chain::orchard::tree::Root>,
-
-    // A set of all nullifiers that have been seen. This prevents double spends.
-    // sadly this is not fixed size but it could be replaced with a Sparse Merkle Tree
-    // in the future. This would require updates to wallets though
-    nullifier_set: HashSet<zebra_chain::orchard::Nullifier>,
+    state: Arc<Mutex<ChainState>>,
+    config: Arc<Config>,
+}
+
+/// Number of recent blocks a node that follows reverted inputs should keep undo records for.
+/// This matches the number of blocks zebra-state keeps in its non-finalized state
+pub const DEFAULT_ROLLBACK_DEPTH: u32 = 100;
+
+/// Configuration options for a TinyCash instance
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How the set of revealed nullifiers is stored and committed to
+    pub nullifier_accumulator: NullifierAccumulatorKind,
+    /// How long commitment tree roots can be used as anchors for shielded spends
+    pub anchor_window: AnchorWindow,
+    /// The minimum fee each transaction must pay
+    pub fee_policy: FeePolicy,
+    /// Where the coinbase of each block pays the fees collected from its transactions.
+    /// If not set the fees are burned
+    pub fee_recipient: Option<transparent::Script>,
+    /// The identity of this instance. Signatures for other instances are rejected
+    pub chain_spec: ChainSpec,
+    /// Address of the dApp contract on the base layer, committed to in the genesis block
+    pub dapp_address: Option<[u8; 20]>,
+    /// How many of the most recent blocks can be undone by rolling back.
+    /// Zero (the default) keeps no undo records. Only a node that can see inputs being reverted,
+    /// like one following the rollups node, needs to set this
+    pub rollback_depth: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nullifier_accumulator: Default::default(),
+            anchor_window: Default::default(),
+            fee_policy: Default::default(),
+            fee_recipient: None,
+            chain_spec: Default::default(),
+            dapp_address: None,
+            rollback_depth: 0,
+        }
+    }
 }
 
 impl TinyCash {
     pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
         Self {
-            tip_height: None,
-            tip_hash: None,
-            commitment_tree_frontier: NoteCommitmentTree::default(),
-            historical_tree_roots: VecDeque::new(),
-            utxos_set: HashMap::new(),
-            nullifier_set: HashSet::new(),
+            state: Arc::new(Mutex::new(ChainState::new(
+                config.nullifier_accumulator,
+                config.anchor_window,
+                config.rollback_depth,
+            ))),
+            config: Arc::new(config),
         }
     }
+
+    /// Restore a TinyCash instance from a snapshot produced by [`TinyCash::snapshot`]
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        Self::from_snapshot_with_config(bytes, Config::default())
+    }
+
+    /// Restore a TinyCash instance from a snapshot using the given fee settings.
+    /// The nullifier accumulator and anchor window are always taken from the snapshot
+    pub fn from_snapshot_with_config(bytes: &[u8], config: Config) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            state: Arc::new(Mutex::new(ChainState::from_snapshot(
+                bytes,
+                config.rollback_depth,
+            )?)),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Produce a deterministic snapshot of the current chain state.
+    /// This waits for any in-progress state transition to complete
+    pub async fn snapshot(&self) -> Vec<u8> {
+        self.state.lock().await.to_snapshot()
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn chain_state(&self) -> ChainState {
+        self.state.lock().await.clone()
+    }
 }
 
 /// The request type for the TinyCash service
@@ -84,23 +146,103 @@ pub enum Request {
     Mint {
         amount: Amount<NonNegative>,
         to: transparent::Script,
+        context: BlockContext,
+    },
+    /// Form a coinbase transaction that mints the given amount as a new note for the Orchard address
+    /// and produce a new block that includes it. Fails with [`Error::ShieldedMint`] unless the
+    /// `shielded-mint` feature is enabled
+    MintShielded {
+        amount: Amount<NonNegative>,
+        to: orchard::Address,
+        context: BlockContext,
     },
     /// Produce a new block that includes the given transaction
-    IncludeTransaction { transaction: Transaction },
+    IncludeTransaction {
+        transaction: Transaction,
+        context: BlockContext,
+    },
+    /// Produce a single new block that includes all of the given transactions that are valid.
+    /// Invalid transactions, or transactions that conflict with one earlier in the list, are left out of the block
+    IncludeTransactions(Vec<Transaction>, BlockContext),
+    /// Revert every block above the given height using the retained undo records.
+    /// The response holds the block at that height, which becomes the new tip
+    RollbackTo(Height),
+}
+
+/// Details of the L1 input that produced a block.
+///
+/// The time is used as the block header time and to check transaction lock times.
+/// The L1 block number is recorded in the coinbase so every block can be traced back to its input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockContext {
+    pub time: DateTime<Utc>,
+    pub l1_block_number: u64,
+}
+
+impl BlockContext {
+    // extra data included in the coinbase input of each block
+    fn coinbase_data(&self) -> Vec<u8> {
+        self.l1_block_number.to_le_bytes().to_vec()
+    }
 }
 
 /// The response type for the TinyCash service
 pub struct Response {
     ///The block that was added by this state transition
     pub block: zebra_state::SemanticallyVerifiedBlock,
-    /// The amount of coins that were burned by the transaction (if any) by transferring to the Mt Doom address (0x000...000)
+    /// The amount of coins that were burned by the transaction (if any) by transferring to the Mt Doom address
+    /// or the Mt Doom script
     pub burns: Vec<(Amount<NonNegative>, Memo)>,
+    /// The result for each transaction submitted for inclusion, in the order they were submitted.
+    /// Only those with an `Ok` result were included in the block
+    pub transaction_results: Vec<TransactionResult>,
+    /// Commitment to the full chain state after this block was added
+    pub state_root: StateRoot,
+    /// Root of the nullifier accumulator after this block was added
+    pub nullifier_root: [u8; 32],
 }
 
+/// Whether a transaction submitted for inclusion was accepted, identified by its hash
+pub type TransactionResult = (transaction::Hash, Result<(), Error>);
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("invalid transaction ({0:?})")]
     InvalidTransaction(String),
+    #[error("duplicate nullifier detected ({0:?})")]
+    DuplicateNullifier(zebra_chain::orchard::Nullifier),
+    #[error("tree root not found in state ({0:?}). Witness may be invalid or too old")]
+    UnknownAnchor(zebra_chain::orchard::tree::Root),
+    #[error("anchor from height {} is {} blocks old and has fallen out of the anchor window. Witness must be updated to a tree state at height {} or later (tip is at height {})", anchor_height.0, tip_height.0.saturating_sub(anchor_height.0), oldest_anchor_height.0, tip_height.0)]
+    AnchorTooOld {
+        anchor_height: Height,
+        oldest_anchor_height: Height,
+        tip_height: Height,
+    },
+    #[error("transparent input spends an unknown or already spent output ({0:?})")]
+    UnknownOutPoint(transparent::OutPoint),
+    #[error("transparent output spent more than once in the same block ({0:?})")]
+    DuplicateSpend(transparent::OutPoint),
+    #[error("transaction pays a fee of {} but at least {} is required", i64::from(*paid), i64::from(*required))]
+    InsufficientFee {
+        required: Amount<NonNegative>,
+        paid: Amount<NegativeAllowed>,
+    },
+    #[error("transaction is locked until {0:?}")]
+    LockTimeNotReached(LockTime),
+    #[error("transaction expired at height {} and cannot be included at height {}", expiry_height.0, height.0)]
+    Expired {
+        expiry_height: Height,
+        height: Height,
+    },
+    #[error("no valid transactions to include in block ({0:?})")]
+    NoValidTransactions(Vec<(transaction::Hash, Error)>),
+    #[error("failed to build shielded mint ({0})")]
+    ShieldedMint(String),
+    #[error("cannot roll back to height {0:?} as it is above the tip")]
+    RollbackAboveTip(Height),
+    #[error("cannot roll back to height {0:?} as its undo records are no longer retained")]
+    RollbackUnavailable(Height),
 }
 
 impl tower::Service<Request> for TinyCash {
@@ -114,96 +256,97 @@ impl tower::Service<Request> for TinyCash {
     }
 
     fn call(&mut self, req: Request) -> Self::Future {
-        let tip_height = self.tip_height;
-        let previous_block_hash = self.tip_hash.unwrap_or(Default::default());
-
-        let height = tip_height.map(|h| h.next().unwrap()).unwrap_or(Height(0));
+        let state = self.state.clone();
+        let config = self.config.clone();
 
-        let (block, burns, tx_to_verify) = match req {
-            Request::Genesis => (genesis_block(), Vec::new(), None),
-            Request::Mint { amount, to } => {
-                let block = build_mint_block(height, previous_block_hash, amount, to);
-                let burns = Vec::new();
-                (block, burns, None)
-            }
-            Request::IncludeTransaction { transaction } => {
-                let burns = transaction
-                    .orchard_actions()
-                    .filter_map(extract_burn_info)
-                    .collect();
-                let block = build_transact_block(height, previous_block_hash, transaction.clone());
-                (block, burns, Some(transaction))
-            }
-        };
+        async move {
+            // Hold the state for the whole transition so blocks are always built on the current tip
+            let mut state = state.lock().await;
+
+            let height = state.next_height();
+            let previous_block_hash = state.previous_block_hash();
+
+            let (block, burns, transaction_results) = match req {
+                Request::RollbackTo(height) => return rollback_to(&mut state, height),
+                Request::Genesis => (genesis_block(&config), Vec::new(), Vec::new()),
+                Request::Mint {
+                    amount,
+                    to,
+                    context,
+                } => {
+                    let block = build_mint_block(
+                        height,
+                        previous_block_hash,
+                        &config.chain_spec,
+                        &context,
+                        amount,
+                        to,
+                    );
+                    (block, Vec::new(), Vec::new())
+                }
+                Request::MintShielded {
+                    amount,
+                    to,
+                    context,
+                } => {
+                    let coinbase_tx = add_shielded_output(
+                        empty_coinbase_txn(height, &config.chain_spec, &context),
+                        amount,
+                        &to,
+                        state.commitment_tree_frontier.root(),
+                        &config.chain_spec,
+                    )?;
+                    let block =
+                        build_block(previous_block_hash, &context, vec![Arc::new(coinbase_tx)]);
+                    (block, Vec::new(), Vec::new())
+                }
+                Request::IncludeTransaction {
+                    transaction,
+                    context,
+                } => {
+                    Self::select_transactions(
+                        &state,
+                        &config,
+                        &context,
+                        height,
+                        previous_block_hash,
+                        vec![transaction],
+                    )
+                    .await?
+                }
+                Request::IncludeTransactions(transactions, context) => {
+                    Self::select_transactions(
+                        &state,
+                        &config,
+                        &context,
+                        height,
+                        previous_block_hash,
+                        transactions,
+                    )
+                    .await?
+                }
+            };
 
-        // the below checks are from the zebra-consensus block verifier
-        // this logic mostly taken from zebra-consensus block verifier
-        // https://github.com/ZcashFoundation/zebra/blob/main/zebra-consensus/src/block.rs
+            // the below checks are from the zebra-consensus block verifier
+            // this logic mostly taken from zebra-consensus block verifier
+            // https://github.com/ZcashFoundation/zebra/blob/main/zebra-consensus/src/block.rs
 
-        let block_hash = block.hash();
+            let block = Arc::new(block);
+            let block_hash = block.hash();
+            let transaction_hashes: Arc<[_]> =
+                block.transactions.iter().map(|t| t.hash()).collect();
 
-        self.tip_hash = Some(block_hash);
-        self.tip_height = Some(height);
-        let outputs_spent = if let Some(tx) = tx_to_verify.as_ref() {
-            self.outputs_spent_by_transaction(tx.clone().into())
-        } else {
-            Vec::new()
-        };
+            // Stage the changes this block makes. Nothing is written to the state until every check has passed
+            let staged = state.stage_block(&block, height, &transaction_hashes)?;
 
-        // build the set of new UTXOs this block creates and add to the global state
-        let transaction_hashes: Arc<[_]> = block.transactions.iter().map(|t| t.hash()).collect();
-        let known_utxos = Arc::new(transparent::new_ordered_outputs(
-            &block,
-            &transaction_hashes,
-        ));
-        let new_outputs = Arc::into_inner(known_utxos)
-            .expect("all verification tasks using known_utxos are complete");
-        tracing::info!("Adding new UTXOs to the set: {:?}", new_outputs);
-        self.utxos_set
-            .extend(new_outputs.iter().map(|(k, v)| (k.clone(), v.clone())));
-
-        // check this block doesn't reuse a known nullifier then add the block nullifiers to the state
-        for nullifier in block.clone().orchard_nullifiers() {
-            if self.nullifier_set.contains(nullifier) {
-                return async move {
-                    Err("Duplicate nullifier detected. Skipping transaction".into())
-                }.boxed();
-            }
-        }
-        self.nullifier_set
-            .extend(block.orchard_nullifiers().cloned());
-
-        // update the commitment tree frontier and add the new root to the queue
-        for commitment in block.orchard_note_commitments() {
-            self.commitment_tree_frontier
-                .append(*commitment)
-                .expect("failed to append to tree");
-        }
-        self.historical_tree_roots
-            .push_back(self.commitment_tree_frontier.root());
-
-        // ensure the anchors/tree-roots referenced by the transaction are in the state
-        if let Some(tx) = tx_to_verify.as_ref() {
-            if let Some(data) = tx.orchard_shielded_data() {
-                if !self.historical_tree_roots.contains(&data.shared_anchor) {
-                    return async move {
-                        Err("Tree root not found in state. Witness may be invalid or too old. Skipping transaction".into())
-                    }.boxed();
-                }
-            }
-        }
-
-        async move {
-            // verify the transaction if required
-            if let Some(tx) = tx_to_verify {
-                tracing::info!("Verifying transaction");
-                Self::verify_transaction(tx.into(), height, outputs_spent.as_slice()).await?;
-                tracing::info!("Transaction passed!");
-            }
+            let new_outputs = staged.new_outputs().clone();
+            state.commit(staged);
+            let state_root = state.state_root();
+            tracing::info!("New state root at height {:?}: {}", height, state_root);
 
             // contextually verify and commit the block
             let prepared_block = zebra_state::SemanticallyVerifiedBlock {
-                block: block.into(),
+                block,
                 hash: block_hash,
                 height,
                 new_outputs,
@@ -214,6 +357,9 @@ impl tower::Service<Request> for TinyCash {
             Ok(Response {
                 block: prepared_block,
                 burns,
+                transaction_results,
+                state_root,
+                nullifier_root: state.nullifier_set.root(),
             })
         }
         .boxed()
@@ -221,54 +367,202 @@ impl tower::Service<Request> for TinyCash {
 }
 
 impl TinyCash {
-    pub fn outputs_spent_by_transaction(&self, tx: Arc<Transaction>) -> Vec<transparent::Output> {
-        tx.inputs()
-            .iter()
-            .filter_map(|input| {
-                if let Some(outpoint) = input.outpoint() {
-                    self.utxos_set
-                        .get(&outpoint)
-                        .map(|utxo| utxo.as_ref().output.clone())
-                } else {
-                    None
+    /// Verify each of the given transactions against the current state and build a block from those that pass.
+    /// Transactions that reveal a nullifier or spend a transparent output already revealed or spent by an
+    /// earlier transaction in the list are rejected.
+    ///
+    /// The proofs and signatures of all the transactions are verified together so the zebra batch verifiers
+    /// can check them in as few batches as possible. If a batch fails each of its items is verified on its own,
+    /// so only the transactions with an invalid proof or signature are rejected.
+    async fn select_transactions(
+        state: &ChainState,
+        config: &Config,
+        context: &BlockContext,
+        height: Height,
+        previous_block_hash: block::Hash,
+        transactions: Vec<Transaction>,
+    ) -> Result<
+        (
+            Block,
+            Vec<(Amount<NonNegative>, Memo)>,
+            Vec<TransactionResult>,
+        ),
+        Error,
+    > {
+        // run the cheap contextual checks first and queue the proof and signature verification of the
+        // transactions that pass them
+        let verifications = transactions.into_iter().map(|transaction| {
+            let transaction: Arc<Transaction> = transaction.into();
+            let checks =
+                Self::check_transaction(state, config, context, transaction.clone(), height);
+            async move {
+                let result = match checks {
+                    Ok((fee, verification)) => verification.await.map(|()| fee),
+                    Err(e) => Err(e),
+                };
+                (transaction, result)
+            }
+        });
+        let verified = join_all(verifications).await;
+
+        let mut transaction_results = Vec::with_capacity(verified.len());
+        let mut block_nullifiers = HashSet::new();
+        let mut block_spent_outpoints = HashSet::new();
+        let mut accepted = Vec::new();
+        let mut burns = Vec::new();
+        let mut fees = Amount::<NonNegative>::zero();
+
+        // conflicts are resolved in order so the first transaction to reveal a nullifier or spend an output wins
+        for (transaction, result) in verified {
+            let hash = transaction.hash();
+
+            let result = result
+                .and_then(|fee| {
+                    Self::check_block_conflicts(
+                        &transaction,
+                        &block_nullifiers,
+                        &block_spent_outpoints,
+                    )
+                    .map(|()| fee)
+                })
+                // the fees of the block must fit in the coinbase
+                .and_then(|fee| {
+                    (fees + fee).map_err(|e| {
+                        Error::InvalidTransaction(format!("fees of the block overflow: {}", e))
+                    })
+                });
+
+            let result = match result {
+                Ok(total_fees) => {
+                    fees = total_fees;
+                    tracing::info!("Transaction {} passed!", hash);
+                    block_nullifiers.extend(transaction.orchard_nullifiers().cloned());
+                    block_spent_outpoints.extend(spent_outpoints(&transaction));
+                    burns.extend(transaction.orchard_actions().filter_map(extract_burn_info));
+                    burns.extend(
+                        transaction
+                            .outputs()
+                            .iter()
+                            .filter_map(extract_transparent_burn_info),
+                    );
+                    accepted.push(transaction);
+                    Ok(())
                 }
-            })
-            .collect()
+                Err(e) => {
+                    tracing::info!("Transaction {} rejected: {}", hash, e);
+                    Err(e)
+                }
+            };
+            transaction_results.push((hash, result));
+        }
+
+        if accepted.is_empty() {
+            return Err(Error::NoValidTransactions(
+                transaction_results
+                    .into_iter()
+                    .filter_map(|(hash, result)| result.err().map(|e| (hash, e)))
+                    .collect(),
+            ));
+        }
+
+        let block = build_transact_block(
+            height,
+            previous_block_hash,
+            &config.chain_spec,
+            context,
+            fees,
+            config.fee_recipient.as_ref(),
+            accepted,
+        );
+        Ok((block, burns, transaction_results))
     }
-}
 
-impl TinyCash {
-    async fn verify_transaction(
+    /// Ensure the transaction doesn't reveal a nullifier or spend an output that an earlier transaction in the block already did
+    fn check_block_conflicts(
+        transaction: &Transaction,
+        block_nullifiers: &HashSet<zebra_chain::orchard::Nullifier>,
+        block_spent_outpoints: &HashSet<transparent::OutPoint>,
+    ) -> Result<(), Error> {
+        if let Some(nullifier) = transaction
+            .orchard_nullifiers()
+            .find(|nullifier| block_nullifiers.contains(*nullifier))
+        {
+            return Err(Error::DuplicateNullifier(*nullifier));
+        }
+        spent_outpoints(transaction)
+            .find(|outpoint| block_spent_outpoints.contains(outpoint))
+            .map_or(Ok(()), |outpoint| Err(Error::DuplicateSpend(outpoint)))
+    }
+
+    /// Run the contextual checks for a single transaction against the current state.
+    /// Returns the fee it pays and a future that verifies its scripts, proofs and signatures
+    fn check_transaction(
+        state: &ChainState,
+        config: &Config,
+        context: &BlockContext,
         tx: Arc<Transaction>,
         height: Height,
+    ) -> Result<(Amount<NonNegative>, BoxFuture<'static, Result<(), Error>>), Error> {
+        check_lock_time_and_expiry(&tx, height, context.time)?;
+        state.check_anchor(&tx)?;
+        state.check_nullifiers(&tx)?;
+        let mut spent = HashSet::new();
+        if let Some(outpoint) = spent_outpoints(&tx).find(|outpoint| !spent.insert(*outpoint)) {
+            return Err(Error::DuplicateSpend(outpoint));
+        }
+        let outputs_spent = state.outputs_spent_by_transaction(tx.clone())?;
+        let fee = config.fee_policy.check(&tx, &outputs_spent)?;
+        tracing::info!("Verifying transaction {}", tx.hash());
+        let verification = Self::verify_transaction(
+            &config.chain_spec,
+            tx,
+            height,
+            context.time,
+            outputs_spent.as_slice(),
+        )
+        .map_err(into_transaction_error)?;
+        Ok((fee, verification.map_err(into_transaction_error).boxed()))
+    }
+
+    // Queue the script, proof and signature checks of the transaction with the zebra verifiers
+    fn verify_transaction(
+        chain_spec: &ChainSpec,
+        tx: Arc<Transaction>,
+        height: Height,
+        time: DateTime<Utc>,
         all_previous_outputs: &[transparent::Output],
-    ) -> Result<(), BoxError> {
+    ) -> Result<BoxFuture<'static, Result<(), BoxError>>, BoxError> {
         let async_checks = match tx.as_ref() {
             Transaction::V5 {
+                network_upgrade,
                 sapling_shielded_data,
                 orchard_shielded_data,
                 ..
             } => {
+                if *network_upgrade != chain_spec.network_upgrade() {
+                    return Err(Error::InvalidTransaction(format!(
+                        "transaction is for network upgrade {:?} but this chain follows {:?}",
+                        network_upgrade,
+                        chain_spec.network_upgrade()
+                    ))
+                    .into());
+                }
                 if sapling_shielded_data.is_some() {
                     return Err(Error::InvalidTransaction(
                         "Sapling shielded data is not supported".to_string(),
                     )
                     .into());
                 }
-                let shielded_sighash = tx.sighash(
-                    NetworkUpgrade::Nu5,
-                    HashType::ALL,
-                    all_previous_outputs,
-                    None,
-                );
+                // the sighash commits to the consensus branch id of this instance
+                let shielded_sighash = chain_spec.shielded_sighash(&tx, all_previous_outputs);
                 TxVerifier::<StateService>::verify_transparent_inputs_and_outputs(
                     &tx::Request::Block {
                         transaction: tx.clone(),
                         known_utxos: HashMap::new().into(),
                         height,
-                        time: DateTime::<Utc>::default(),
+                        time,
                     },
-                    Network::Mainnet,
+                    chain_spec.network(),
                     script::Verifier, // TODO: Maybe try and reuse this
                     zebra_script::CachedFfiTransaction::new(
                         tx.clone(),
@@ -288,44 +582,105 @@ impl TinyCash {
                 .into());
             }
         };
-        async_checks.check().await
+        Ok(async_checks.check().boxed())
+    }
+}
+
+// Errors from the zebra verifiers are reported as invalid transactions
+fn into_transaction_error(e: BoxError) -> Error {
+    match e.downcast::<Error>() {
+        Ok(e) => *e,
+        Err(e) => Error::InvalidTransaction(e.to_string()),
     }
 }
 
+// Restore the state to how it was after the block at `height` and respond with that block
+fn rollback_to(state: &mut ChainState, height: Height) -> Result<Response, BoxError> {
+    let block = state.rollback_to(height)?;
+    let transaction_hashes: Arc<[_]> = block.transactions.iter().map(|t| t.hash()).collect();
+    // the same outputs as when the block was committed
+    let new_outputs = spendable_outputs(&block, height, &transaction_hashes);
+    let state_root = state.state_root();
+    tracing::info!("Rolled back to height {:?}: {}", height, state_root);
+
+    Ok(Response {
+        block: zebra_state::SemanticallyVerifiedBlock {
+            hash: block.hash(),
+            block,
+            height,
+            new_outputs,
+            transaction_hashes,
+        },
+        burns: Vec::new(),
+        transaction_results: Vec::new(),
+        state_root,
+        nullifier_root: state.nullifier_set.root(),
+    })
+}
+
+// Proving a shielded mint builds the Halo2 proving key and an Orchard proof, which is far more expensive
+// than anything else a node does. It is only done if enabled
+#[cfg(not(any(test, feature = "shielded-mint")))]
+fn add_shielded_output(
+    _coinbase: Transaction,
+    _amount: Amount<NonNegative>,
+    _to: &orchard::Address,
+    _anchor: zebra_chain::orchard::tree::Root,
+    _chain_spec: &ChainSpec,
+) -> Result<Transaction, Error> {
+    Err(Error::ShieldedMint(
+        "shielded mints are disabled on this instance".to_string(),
+    ))
+}
+
 fn build_mint_block(
     height: Height,
     previous_block_hash: block::Hash,
+    chain_spec: &ChainSpec,
+    context: &BlockContext,
     amount: Amount<NonNegative>,
     to: transparent::Script,
 ) -> Block {
-    let coinbase_tx = mint_coinbase_txn(amount, &to, height);
-    build_block(previous_block_hash, vec![Arc::new(coinbase_tx)])
+    let coinbase_tx = mint_coinbase_txn(amount, &to, height, chain_spec, context);
+    build_block(previous_block_hash, context, vec![Arc::new(coinbase_tx)])
 }
 
+// the coinbase of a transact block pays the fees of its transactions to the fee recipient
 fn build_transact_block(
     height: Height,
     previous_block_hash: block::Hash,
-    transaction: Transaction,
+    chain_spec: &ChainSpec,
+    context: &BlockContext,
+    fees: Amount<NonNegative>,
+    fee_recipient: Option<&transparent::Script>,
+    transactions: Vec<Arc<Transaction>>,
 ) -> Block {
-    let coinbase_tx = empty_coinbase_txn(height);
+    let coinbase_tx = match fee_recipient {
+        Some(recipient) => mint_coinbase_txn(fees, recipient, height, chain_spec, context),
+        // without a recipient the fees are never minted so are burned
+        None => empty_coinbase_txn(height, chain_spec, context),
+    };
     build_block(
         previous_block_hash,
-        vec![Arc::new(coinbase_tx), Arc::new(transaction)],
+        context,
+        std::iter::once(Arc::new(coinbase_tx))
+            .chain(transactions)
+            .collect(),
     )
 }
 
-fn genesis_block() -> Block {
-    Block::zcash_deserialize(zebra_test::vectors::BLOCK_MAINNET_GENESIS_BYTES.as_slice()).unwrap()
-}
-
-fn build_block(previous_block_hash: block::Hash, transactions: Vec<Arc<Transaction>>) -> Block {
+fn build_block(
+    previous_block_hash: block::Hash,
+    context: &BlockContext,
+    transactions: Vec<Arc<Transaction>>,
+) -> Block {
     Block {
         header: Header {
             version: 5,
             previous_block_hash,
             merkle_root: transactions.iter().collect(),
             commitment_bytes: HexDebug::default(),
-            time: DateTime::<Utc>::default(),
+            time: context.time,
             difficulty_threshold: CompactDifficulty::default(),
             nonce: HexDebug::default(),
             solution: Solution::default(),
@@ -335,22 +690,80 @@ fn build_block(previous_block_hash: block::Hash, transactions: Vec<Arc<Transacti
     }
 }
 
-// create a new transparent v5 coinbase transaction that mints the given amount and sends it to the given address
+// create a new transparent v5 coinbase transaction that mints the given amount and sends it to the given address.
+// The coinbase data is the instance id followed by the L1 block number.
+// Built directly rather than with `Transaction::new_v5_coinbase` so it always uses the network upgrade of
+// the chain spec rather than the one zebra would activate at this height
 fn mint_coinbase_txn(
     amount: Amount<NonNegative>,
     to: &transparent::Script,
     height: Height,
+    chain_spec: &ChainSpec,
+    context: &BlockContext,
 ) -> Transaction {
-    Transaction::new_v5_coinbase(
-        Network::Mainnet,
-        height,
-        vec![(amount, to.clone())],
-        Vec::new(),
-    )
+    let mut coinbase_data = chain_spec.coinbase_data().to_vec();
+    coinbase_data.extend(context.coinbase_data());
+    Transaction::V5 {
+        network_upgrade: chain_spec.network_upgrade(),
+        lock_time: LockTime::unlocked(),
+        expiry_height: height,
+        inputs: vec![transparent::Input::new_coinbase(
+            height,
+            Some(coinbase_data),
+            None,
+        )],
+        outputs: vec![transparent::Output::new_coinbase(amount, to.clone())],
+        sapling_shielded_data: None,
+        orchard_shielded_data: None,
+    }
 }
 
 // TODO: If this can avoid adding a spend this will slow state growth
 // currently having it empty makes the block verifier freak out though
-fn empty_coinbase_txn(height: Height) -> Transaction {
-    mint_coinbase_txn(Amount::zero(), &Script::new(&[0x0; 32]), height)
+fn empty_coinbase_txn(
+    height: Height,
+    chain_spec: &ChainSpec,
+    context: &BlockContext,
+) -> Transaction {
+    mint_coinbase_txn(
+        Amount::zero(),
+        &Script::new(&[0x0; 32]),
+        height,
+        chain_spec,
+        context,
+    )
+}
+
+// Zcash lock time and expiry rules.
+// A locked transaction can be included once the block height or time is past its lock time
+// and a transaction with an expiry height cannot be included in any block above it
+fn check_lock_time_and_expiry(
+    tx: &Transaction,
+    height: Height,
+    time: DateTime<Utc>,
+) -> Result<(), Error> {
+    match tx.lock_time() {
+        Some(lock_time @ LockTime::Height(lock_height)) if height <= lock_height => {
+            return Err(Error::LockTimeNotReached(lock_time))
+        }
+        Some(lock_time @ LockTime::Time(lock_time_utc)) if time <= lock_time_utc => {
+            return Err(Error::LockTimeNotReached(lock_time))
+        }
+        _ => {}
+    }
+    match tx.expiry_height() {
+        Some(expiry_height) if height > expiry_height => Err(Error::Expired {
+            expiry_height,
+            height,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// the transparent outputs spent by the inputs of the transaction
+fn spent_outpoints(transaction: &Transaction) -> impl Iterator<Item = transparent::OutPoint> + '_ {
+    transaction
+        .inputs()
+        .iter()
+        .filter_map(|input| input.outpoint())
 }
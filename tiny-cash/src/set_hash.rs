@@ -0,0 +1,50 @@
+//! An incremental hash of a set of byte strings.
+//!
+//! This is the elliptic curve multiset hash (ECMH): every element is hashed to a point on the Pallas
+//! curve and the digest is the sum of those points. Adding or removing an element is a single
+//! hash-to-curve and point addition, independent of the size of the set, and the digest does not
+//! depend on the order elements were added in. This lets the state root be kept up to date by
+//! touching only the entries each block changes rather than rehashing the whole state.
+
+use pasta_curves::{
+    arithmetic::CurveExt,
+    group::{Group, GroupEncoding},
+    pallas,
+};
+
+use crate::nullifiers::{finalize, hasher};
+
+/// The sum of the hashes of each element in a set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SetHash {
+    // hash-to-curve domain separating the sets of different parts of the state
+    domain: &'static str,
+    sum: pallas::Point,
+}
+
+impl SetHash {
+    /// The hash of the empty set
+    pub fn new(domain: &'static str) -> Self {
+        Self {
+            domain,
+            sum: pallas::Point::identity(),
+        }
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        self.sum += pallas::Point::hash_to_curve(self.domain)(element);
+    }
+
+    /// Remove an element previously inserted
+    pub fn remove(&mut self, element: &[u8]) {
+        self.sum -= pallas::Point::hash_to_curve(self.domain)(element);
+    }
+
+    /// BLAKE2b-256 hash of the number of elements (u32 little-endian) followed by the compressed sum
+    pub fn commitment(&self, personalization: &[u8; 16], len: usize) -> [u8; 32] {
+        let mut state = hasher(personalization);
+        state.update(&(len as u32).to_le_bytes());
+        state.update(&self.sum.to_bytes());
+        finalize(&state)
+    }
+}
@@ -0,0 +1,195 @@
+//! Minting directly into the Orchard shielded pool.
+//!
+//! NU5 allows a coinbase transaction to create Orchard notes so a deposit can be minted as a single coinbase
+//! output to an Orchard address. Like Zcash (ZIP-213) the output is encrypted with an all-zero outgoing viewing
+//! key so anyone can see the amount and recipient of a shielded mint, which are public on the L1 anyway.
+//!
+//! Every node must produce exactly the same block for an input so all randomness used to build the bundle
+//! comes from an RNG seeded with a hash of the coinbase and the deposit.
+
+use orchard::{
+    builder::{Builder, BundleType, UnauthorizedBundle},
+    bundle::{Authorization, Bundle},
+    circuit::ProvingKey,
+    keys::{OutgoingViewingKey, SpendAuthorizingKey},
+    tree::Anchor,
+    value::NoteValue,
+};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use zebra_chain::{
+    amount::{Amount, NonNegative},
+    orchard::{self as zebra_orchard, tree::Root},
+    serialization::ZcashDeserialize,
+    transaction::Transaction,
+    transparent,
+};
+
+use crate::chain_spec::ChainSpec;
+use crate::service::Error;
+
+const RNG_PERSONALIZATION: &[u8; 16] = b"CarteZcashMintRn";
+
+// 0xF6 followed by zeros is the empty memo
+const EMPTY_MEMO: [u8; 512] = {
+    let mut memo = [0; 512];
+    memo[0] = 0xf6;
+    memo
+};
+
+lazy_static::lazy_static! {
+    static ref PROVING_KEY: ProvingKey = ProvingKey::build();
+}
+
+/// Add an Orchard bundle to the coinbase that creates a note of `amount` for `to`.
+/// `anchor` must be the current commitment tree root
+pub(crate) fn add_shielded_output(
+    coinbase: Transaction,
+    amount: Amount<NonNegative>,
+    to: &orchard::Address,
+    anchor: Root,
+    chain_spec: &ChainSpec,
+) -> Result<Transaction, Error> {
+    let mut rng = ChaCha20Rng::from_seed(rng_seed(&coinbase, amount, to));
+
+    let anchor = Option::<Anchor>::from(Anchor::from_bytes(anchor.into()))
+        .ok_or_else(|| mint_error("invalid anchor"))?;
+    let mut builder = Builder::new(BundleType::Coinbase, anchor);
+    builder
+        .add_output(
+            Some(OutgoingViewingKey::from([0; 32])),
+            *to,
+            NoteValue::from_raw(amount.zatoshis() as u64),
+            EMPTY_MEMO,
+        )
+        .map_err(mint_error)?;
+    let (bundle, _) = builder
+        .build::<i64>(&mut rng)
+        .map_err(mint_error)?
+        .ok_or_else(|| mint_error("empty bundle"))?;
+
+    authorize_bundle(coinbase, bundle, &[], &[], chain_spec, &mut rng)
+}
+
+/// Prove and sign an Orchard bundle and add it to the transaction.
+/// The signatures are made over the shielded sighash of the instance for the transaction with the bundle added,
+/// exactly as a wallet would make them
+pub(crate) fn authorize_bundle(
+    tx: Transaction,
+    bundle: UnauthorizedBundle<i64>,
+    spend_auth_keys: &[SpendAuthorizingKey],
+    all_previous_outputs: &[transparent::Output],
+    chain_spec: &ChainSpec,
+    rng: &mut ChaCha20Rng,
+) -> Result<Transaction, Error> {
+    // the sighash doesn't commit to proofs or signatures so it can be computed before they exist
+    let unsigned = with_orchard_shielded_data(
+        tx.clone(),
+        &encode_bundle(
+            &bundle,
+            &[],
+            &vec![[0; 64]; bundle.actions().len()],
+            [0; 64],
+        ),
+    )?;
+    let sighash = chain_spec.shielded_sighash(&unsigned, all_previous_outputs);
+
+    let bundle = bundle
+        .create_proof(&PROVING_KEY, &mut *rng)
+        .map_err(mint_error)?
+        .apply_signatures(&mut *rng, sighash.0, spend_auth_keys)
+        .map_err(mint_error)?;
+    let spend_auth_sigs: Vec<[u8; 64]> = bundle
+        .actions()
+        .iter()
+        .map(|action| action.authorization().into())
+        .collect();
+    with_orchard_shielded_data(
+        tx,
+        &encode_bundle(
+            &bundle,
+            bundle.authorization().proof().as_ref(),
+            &spend_auth_sigs,
+            bundle.authorization().binding_signature().into(),
+        ),
+    )
+}
+
+// Replace the Orchard shielded data of the transaction with the given ZIP-225 encoded bundle
+fn with_orchard_shielded_data(mut tx: Transaction, bundle: &[u8]) -> Result<Transaction, Error> {
+    let shielded_data =
+        Option::<zebra_orchard::ShieldedData>::zcash_deserialize(bundle).map_err(mint_error)?;
+    if let Transaction::V5 {
+        orchard_shielded_data,
+        ..
+    } = &mut tx
+    {
+        *orchard_shielded_data = shielded_data;
+    }
+    Ok(tx)
+}
+
+// The ZIP-225 encoding of an Orchard bundle with the given proof and signatures
+fn encode_bundle<A: Authorization>(
+    bundle: &Bundle<A, i64>,
+    proof: &[u8],
+    spend_auth_sigs: &[[u8; 64]],
+    binding_sig: [u8; 64],
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_compact_size(&mut bytes, bundle.actions().len());
+    for action in bundle.actions() {
+        bytes.extend(action.cv_net().to_bytes());
+        bytes.extend(action.nullifier().to_bytes());
+        bytes.extend(<[u8; 32]>::from(action.rk()));
+        bytes.extend(action.cmx().to_bytes());
+        bytes.extend(action.encrypted_note().epk_bytes);
+        bytes.extend(action.encrypted_note().enc_ciphertext);
+        bytes.extend(action.encrypted_note().out_ciphertext);
+    }
+    bytes.push(bundle.flags().to_byte());
+    bytes.extend(bundle.value_balance().to_le_bytes());
+    bytes.extend(bundle.anchor().to_bytes());
+    write_compact_size(&mut bytes, proof.len());
+    bytes.extend(proof);
+    for sig in spend_auth_sigs {
+        bytes.extend(sig);
+    }
+    bytes.extend(binding_sig);
+    bytes
+}
+
+fn write_compact_size(bytes: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=0xfc => bytes.push(n as u8),
+        0xfd..=0xffff => {
+            bytes.push(0xfd);
+            bytes.extend((n as u16).to_le_bytes());
+        }
+        _ => {
+            bytes.push(0xfe);
+            bytes.extend((n as u32).to_le_bytes());
+        }
+    }
+}
+
+fn rng_seed(
+    coinbase: &Transaction,
+    amount: Amount<NonNegative>,
+    to: &orchard::Address,
+) -> [u8; 32] {
+    blake2b_simd::Params::new()
+        .hash_length(32)
+        .personal(RNG_PERSONALIZATION)
+        .to_state()
+        .update(&coinbase.hash().0)
+        .update(&amount.zatoshis().to_le_bytes())
+        .update(&to.to_raw_address_bytes())
+        .finalize()
+        .as_bytes()
+        .try_into()
+        .expect("hash length is 32 bytes")
+}
+
+fn mint_error(e: impl std::fmt::Debug) -> Error {
+    Error::ShieldedMint(format!("{:?}", e))
+}
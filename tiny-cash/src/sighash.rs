@@ -0,0 +1,160 @@
+//! The ZIP-244 sighash Orchard signatures are made over, computed with the consensus branch id of the instance.
+//!
+//! zebra can only compute sighashes for the branch ids of Zcash network upgrades so it is computed here. The
+//! transaction header still carries the NU5 branch id; only the two places the branch id enters the sighash
+//! (the header digest and the personalization of the final hash) use the branch id of the instance.
+//! With the NU5 branch id this is exactly zebra's `SIGHASH_ALL` shielded sighash.
+//!
+//! Sapling is not supported by CarteZcash so the Sapling digest is always that of an empty bundle.
+
+use blake2b_simd::{Params, State};
+use zebra_chain::serialization::ZcashSerialize;
+use zebra_chain::transaction::{SigHash, Transaction};
+use zebra_chain::transparent;
+
+use crate::chain_spec::ConsensusBranchId;
+
+const SIGHASH_ALL: u8 = 0x01;
+
+// the version, version group id, branch id, lock time and expiry height at the start of a v5 transaction
+const HEADER_LEN: usize = 20;
+const BRANCH_ID_OFFSET: usize = 8;
+
+// the outpoint starts and the sequence number ends every serialized input
+const OUTPOINT_LEN: usize = 36;
+const SEQUENCE_LEN: usize = 4;
+
+// the fields of a serialized Orchard action, in order
+const CV_LEN: usize = 32;
+const NULLIFIER_LEN: usize = 32;
+const RK_LEN: usize = 32;
+const CMX_LEN: usize = 32;
+const EPK_LEN: usize = 32;
+const ENC_CIPHERTEXT_LEN: usize = 580;
+const COMPACT_NOTE_LEN: usize = 52;
+const MEMO_LEN: usize = 512;
+
+/// The sighash the Orchard spend authorization and binding signatures of `tx` must be made over.
+/// `all_previous_outputs` are the outputs spent by the transparent inputs, in order
+pub fn shielded_sighash(
+    tx: &Transaction,
+    branch_id: ConsensusBranchId,
+    all_previous_outputs: &[transparent::Output],
+) -> SigHash {
+    let mut personalization = *b"ZcashTxHash_\0\0\0\0";
+    personalization[12..].copy_from_slice(&branch_id.0.to_le_bytes());
+
+    let mut h = hasher(&personalization);
+    h.update(header_digest(tx, branch_id).as_bytes());
+    h.update(transparent_sig_digest(tx, all_previous_outputs).as_bytes());
+    h.update(hasher(b"ZTxIdSaplingHash").finalize().as_bytes());
+    h.update(orchard_digest(tx).as_bytes());
+    SigHash(
+        h.finalize()
+            .as_bytes()
+            .try_into()
+            .expect("hash length is 32 bytes"),
+    )
+}
+
+fn hasher(personalization: &[u8; 16]) -> State {
+    Params::new()
+        .hash_length(32)
+        .personal(personalization)
+        .to_state()
+}
+
+fn serialize(item: &impl ZcashSerialize) -> Vec<u8> {
+    item.zcash_serialize_to_vec()
+        .expect("serializing into a vec never fails")
+}
+
+fn header_digest(tx: &Transaction, branch_id: ConsensusBranchId) -> blake2b_simd::Hash {
+    let mut header = serialize(tx);
+    header.truncate(HEADER_LEN);
+    header[BRANCH_ID_OFFSET..BRANCH_ID_OFFSET + 4].copy_from_slice(&branch_id.0.to_le_bytes());
+    hasher(b"ZTxIdHeadersHash").update(&header).finalize()
+}
+
+// the signature digest of the transparent part for a shielded signature, with SIGHASH_ALL
+fn transparent_sig_digest(
+    tx: &Transaction,
+    all_previous_outputs: &[transparent::Output],
+) -> blake2b_simd::Hash {
+    let mut h = hasher(b"ZTxIdTranspaHash");
+    if tx.inputs().is_empty() && tx.outputs().is_empty() {
+        return h.finalize();
+    }
+
+    let inputs: Vec<Vec<u8>> = tx.inputs().iter().map(serialize).collect();
+    let mut prevouts = hasher(b"ZTxIdPrevoutHash");
+    let mut sequences = hasher(b"ZTxIdSequencHash");
+    for input in &inputs {
+        prevouts.update(&input[..OUTPOINT_LEN]);
+        sequences.update(&input[input.len() - SEQUENCE_LEN..]);
+    }
+    let mut outputs = hasher(b"ZTxIdOutputsHash");
+    for output in tx.outputs() {
+        outputs.update(&serialize(output));
+    }
+
+    if tx.is_coinbase() || tx.inputs().is_empty() {
+        // identical to the transparent part of the txid
+        h.update(prevouts.finalize().as_bytes());
+        h.update(sequences.finalize().as_bytes());
+        h.update(outputs.finalize().as_bytes());
+        return h.finalize();
+    }
+
+    let mut amounts = hasher(b"ZTxTrAmountsHash");
+    let mut scripts = hasher(b"ZTxTrScriptsHash");
+    for output in all_previous_outputs {
+        amounts.update(&serialize(&output.value));
+        scripts.update(&serialize(&output.lock_script));
+    }
+    h.update(&[SIGHASH_ALL]);
+    h.update(prevouts.finalize().as_bytes());
+    h.update(amounts.finalize().as_bytes());
+    h.update(scripts.finalize().as_bytes());
+    h.update(sequences.finalize().as_bytes());
+    h.update(outputs.finalize().as_bytes());
+    // no transparent input is being signed
+    h.update(hasher(b"Zcash___TxInHash").finalize().as_bytes());
+    h.finalize()
+}
+
+fn orchard_digest(tx: &Transaction) -> blake2b_simd::Hash {
+    let mut h = hasher(b"ZTxIdOrchardHash");
+    let Some(shielded_data) = tx.orchard_shielded_data() else {
+        return h.finalize();
+    };
+
+    let mut compact = hasher(b"ZTxIdOrcActCHash");
+    let mut memos = hasher(b"ZTxIdOrcActMHash");
+    let mut noncompact = hasher(b"ZTxIdOrcActNHash");
+    for action in shielded_data.actions() {
+        let action = serialize(action);
+        let (cv, rest) = action.split_at(CV_LEN);
+        let (nullifier, rest) = rest.split_at(NULLIFIER_LEN);
+        let (rk, rest) = rest.split_at(RK_LEN);
+        let (cmx_and_epk, rest) = rest.split_at(CMX_LEN + EPK_LEN);
+        let (enc_ciphertext, out_ciphertext) = rest.split_at(ENC_CIPHERTEXT_LEN);
+
+        compact.update(nullifier);
+        compact.update(cmx_and_epk);
+        compact.update(&enc_ciphertext[..COMPACT_NOTE_LEN]);
+        memos.update(&enc_ciphertext[COMPACT_NOTE_LEN..COMPACT_NOTE_LEN + MEMO_LEN]);
+        noncompact.update(cv);
+        noncompact.update(rk);
+        noncompact.update(&enc_ciphertext[COMPACT_NOTE_LEN + MEMO_LEN..]);
+        noncompact.update(out_ciphertext);
+    }
+
+    h.update(compact.finalize().as_bytes());
+    h.update(memos.finalize().as_bytes());
+    h.update(noncompact.finalize().as_bytes());
+    h.update(&[shielded_data.flags.bits()]);
+    h.update(&serialize(&shielded_data.value_balance));
+    h.update(&serialize(&shielded_data.shared_anchor));
+    h.finalize()
+}
@@ -0,0 +1,290 @@
+//! A versioned, deterministic binary encoding of the TinyCash chain state.
+//!
+//! Two nodes with the same state always produce byte-identical snapshots so they can be
+//! compared directly (e.g. between the Cartesi machine build and the fullnode build).
+//!
+//! All integers are little-endian. The layout is:
+//!
+//! ```text
+//! magic                   4 bytes  "TCSS"
+//! version                 u8
+//! tip                     u8 flag (0 = no tip, 1 = tip follows)
+//!   tip height            u32
+//!   tip hash              32 bytes
+//! commitment frontier     u32 length + bincode encoded NoteCommitmentTree
+//! anchor window           u8 kind (0 = blocks, 1 = roots) + u32 size
+//! historical tree roots   u32 count + entries, oldest first
+//!   height                u32 height the root was first seen
+//!   root                  32 bytes
+//! evicted tree roots      u32 count + entries, oldest first (at most `EVICTED_CAPACITY`)
+//!   height                u32 height the root was first seen
+//!   root                  32 bytes
+//! utxos                   u32 count + entries sorted by outpoint (txid bytes, index)
+//!   outpoint              32 byte txid + u32 index
+//!   output                Zcash serialized transparent output
+//!   height                u32
+//!   from coinbase         u8
+//!   tx index in block     u64
+//! nullifier accumulator   u8 kind (0 = hash set, 1 = sparse Merkle tree)
+//! nullifiers              u32 count + 32 bytes each, sorted by bytes
+//! ```
+//!
+//! Snapshots of any other version are rejected.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use bincode::Options;
+use zebra_chain::orchard::tree::{NoteCommitmentTree, Root};
+use zebra_chain::{
+    block::{self, Height},
+    orchard::Nullifier,
+    serialization::{SerializationError, ZcashDeserialize, ZcashSerialize},
+    transparent::{OrderedUtxo, OutPoint, Output, Utxo},
+};
+
+use crate::anchors::{AnchorWindow, Anchors};
+use crate::nullifiers::NullifierAccumulatorKind;
+use crate::set_hash::SetHash;
+use crate::state::{ChainState, UndoLog, UTXOS_DOMAIN};
+
+const MAGIC: [u8; 4] = *b"TCSS";
+
+/// The snapshot format version written by this build
+pub const SNAPSHOT_VERSION: u8 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("not a TinyCash snapshot")]
+    BadMagic,
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u8),
+    #[error("snapshot has {0} unexpected trailing bytes")]
+    TrailingBytes(usize),
+    #[error("invalid snapshot field ({0})")]
+    InvalidField(&'static str),
+    #[error("failed to read snapshot ({0})")]
+    Io(#[from] io::Error),
+    #[error("failed to deserialize snapshot ({0})")]
+    Serialization(#[from] SerializationError),
+    #[error("failed to (de)serialize commitment tree ({0})")]
+    Tree(#[from] bincode::Error),
+}
+
+impl ChainState {
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        self.write_snapshot(&mut w)
+            .expect("writing to a Vec cannot fail");
+        w
+    }
+
+    fn write_snapshot(&self, w: &mut Vec<u8>) -> Result<(), SnapshotError> {
+        w.write_all(&MAGIC)?;
+        w.write_all(&[SNAPSHOT_VERSION])?;
+
+        match (self.tip_height, self.tip_hash) {
+            (Some(height), Some(hash)) => {
+                w.write_all(&[1])?;
+                w.write_all(&height.0.to_le_bytes())?;
+                w.write_all(&hash.0)?;
+            }
+            _ => w.write_all(&[0])?,
+        }
+
+        let tree = tree_options().serialize(&self.commitment_tree_frontier)?;
+        write_len(&mut *w, tree.len())?;
+        w.write_all(&tree)?;
+
+        w.write_all(&self.historical_tree_roots.window().to_bytes())?;
+        self.write_tree_roots(&mut *w)?;
+        self.write_utxos(&mut *w)?;
+        w.write_all(&[self.nullifier_set.kind().to_byte()])?;
+        self.write_nullifiers(&mut *w)?;
+
+        Ok(())
+    }
+
+    fn write_tree_roots<W: Write>(&self, mut w: W) -> Result<(), SnapshotError> {
+        write_len(&mut w, self.historical_tree_roots.len())?;
+        for (height, root) in self.historical_tree_roots.iter() {
+            w.write_all(&height.0.to_le_bytes())?;
+            w.write_all(&<[u8; 32]>::from(*root))?;
+        }
+
+        write_len(&mut w, self.historical_tree_roots.evicted().count())?;
+        for (height, root) in self.historical_tree_roots.evicted() {
+            w.write_all(&height.0.to_le_bytes())?;
+            w.write_all(&<[u8; 32]>::from(*root))?;
+        }
+        Ok(())
+    }
+
+    fn write_utxos<W: Write>(&self, mut w: W) -> Result<(), SnapshotError> {
+        let mut utxos: Vec<_> = self.utxos_set.iter().collect();
+        utxos.sort_by_key(|(outpoint, _)| (outpoint.hash.0, outpoint.index));
+        write_len(&mut w, utxos.len())?;
+        for (outpoint, ordered) in utxos {
+            write_utxo(&mut w, outpoint, ordered)?;
+        }
+        Ok(())
+    }
+
+    fn write_nullifiers<W: Write>(&self, mut w: W) -> Result<(), SnapshotError> {
+        let nullifiers = self.nullifier_set.nullifiers();
+        write_len(&mut w, nullifiers.len())?;
+        for nullifier in nullifiers {
+            w.write_all(&<[u8; 32]>::from(nullifier))?;
+        }
+        Ok(())
+    }
+
+    /// Restore the state from a snapshot. There are no undo records so it can only be
+    /// rolled back to blocks committed after it was restored
+    pub fn from_snapshot(bytes: &[u8], rollback_depth: u32) -> Result<Self, SnapshotError> {
+        let mut r = bytes;
+
+        if read_array::<4>(&mut r)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = read_u8(&mut r)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let (tip_height, tip_hash) = match read_u8(&mut r)? {
+            0 => (None, None),
+            1 => (
+                Some(Height(read_u32(&mut r)?)),
+                Some(block::Hash(read_array(&mut r)?)),
+            ),
+            _ => return Err(SnapshotError::InvalidField("tip flag")),
+        };
+
+        let tree_len = read_len(&mut r)?;
+        if tree_len > r.len() {
+            return Err(SnapshotError::InvalidField("commitment tree length"));
+        }
+        let (tree, rest) = r.split_at(tree_len);
+        let commitment_tree_frontier: NoteCommitmentTree = tree_options().deserialize(tree)?;
+        r = rest;
+
+        let mut historical_tree_roots = Anchors::new(
+            AnchorWindow::from_bytes(read_array(&mut r)?)
+                .ok_or(SnapshotError::InvalidField("anchor window"))?,
+        );
+        for _ in 0..read_len(&mut r)? {
+            let height = Height(read_u32(&mut r)?);
+            let root = Root::try_from(read_array::<32>(&mut r)?)
+                .map_err(|_| SnapshotError::InvalidField("tree root"))?;
+            historical_tree_roots.push(height, root);
+        }
+        for _ in 0..read_len(&mut r)? {
+            let height = Height(read_u32(&mut r)?);
+            let root = Root::try_from(read_array::<32>(&mut r)?)
+                .map_err(|_| SnapshotError::InvalidField("evicted tree root"))?;
+            historical_tree_roots.insert_evicted(height, root);
+        }
+
+        let mut utxos_set = HashMap::new();
+        let mut utxos_digest = SetHash::new(UTXOS_DOMAIN);
+        for _ in 0..read_len(&mut r)? {
+            let outpoint = OutPoint::zcash_deserialize(&mut r)?;
+            let output = Output::zcash_deserialize(&mut r)?;
+            let height = Height(read_u32(&mut r)?);
+            let from_coinbase = match read_u8(&mut r)? {
+                0 => false,
+                1 => true,
+                _ => return Err(SnapshotError::InvalidField("utxo from_coinbase")),
+            };
+            let tx_index_in_block = u64::from_le_bytes(read_array(&mut r)?)
+                .try_into()
+                .map_err(|_| SnapshotError::InvalidField("utxo tx index"))?;
+            let ordered = OrderedUtxo {
+                utxo: Utxo {
+                    output,
+                    height,
+                    from_coinbase,
+                },
+                tx_index_in_block,
+            };
+            utxos_digest.insert(&utxo_entry(&outpoint, &ordered));
+            utxos_set.insert(outpoint, ordered);
+        }
+
+        let kind = NullifierAccumulatorKind::from_byte(read_u8(&mut r)?)
+            .ok_or(SnapshotError::InvalidField("nullifier accumulator kind"))?;
+        let mut nullifier_set = kind.build();
+        for _ in 0..read_len(&mut r)? {
+            let nullifier = Nullifier::try_from(read_array::<32>(&mut r)?)
+                .map_err(|_| SnapshotError::InvalidField("nullifier"))?;
+            nullifier_set.insert(nullifier);
+        }
+
+        if !r.is_empty() {
+            return Err(SnapshotError::TrailingBytes(r.len()));
+        }
+
+        Ok(Self {
+            tip_height,
+            tip_hash,
+            utxos_set,
+            utxos_digest,
+            commitment_tree_frontier,
+            historical_tree_roots,
+            nullifier_set,
+            undo_log: UndoLog::new(rollback_depth),
+        })
+    }
+}
+
+/// The snapshot encoding of a single UTXO set entry
+pub(crate) fn utxo_entry(outpoint: &OutPoint, ordered: &OrderedUtxo) -> Vec<u8> {
+    let mut entry = Vec::new();
+    write_utxo(&mut entry, outpoint, ordered).expect("writing to a Vec cannot fail");
+    entry
+}
+
+fn write_utxo<W: Write>(
+    mut w: W,
+    outpoint: &OutPoint,
+    ordered: &OrderedUtxo,
+) -> Result<(), SnapshotError> {
+    outpoint.zcash_serialize(&mut w)?;
+    ordered.utxo.output.zcash_serialize(&mut w)?;
+    w.write_all(&ordered.utxo.height.0.to_le_bytes())?;
+    w.write_all(&[ordered.utxo.from_coinbase as u8])?;
+    w.write_all(&(ordered.tx_index_in_block as u64).to_le_bytes())?;
+    Ok(())
+}
+
+// the same encoding zebra-state uses to store commitment trees on disk
+fn tree_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+fn write_len<W: Write>(mut w: W, len: usize) -> Result<(), SnapshotError> {
+    let len: u32 = len
+        .try_into()
+        .map_err(|_| SnapshotError::InvalidField("length"))?;
+    w.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_array<const N: usize>(r: &mut &[u8]) -> Result<[u8; N], SnapshotError> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8(r: &mut &[u8]) -> Result<u8, SnapshotError> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_u32(r: &mut &[u8]) -> Result<u32, SnapshotError> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_len(r: &mut &[u8]) -> Result<usize, SnapshotError> {
+    Ok(read_u32(r)? as usize)
+}
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use zebra_chain::orchard::tree::NoteCommitmentTree;
+use zebra_chain::{
+    block::{self, Block, Height},
+    orchard::Nullifier,
+    transaction::{self, Transaction},
+    transparent::{self, OrderedUtxo, OutPoint},
+};
+
+use crate::anchors::{AnchorWindow, Anchors, AnchorsUndo};
+use crate::nullifiers::{NullifierAccumulator, NullifierAccumulatorKind};
+use crate::service::Error;
+use crate::set_hash::SetHash;
+use crate::snapshot::utxo_entry;
+use crate::{burned_note_nullifier, extract_transparent_burn_info};
+
+pub(crate) const UTXOS_DOMAIN: &str = "TinyCash-UtxoSet";
+
+/// The chain state maintained by TinyCash.
+///
+/// This is only ever modified by committing a [`StagedBlock`] which has passed all checks.
+/// This way a rejected block or transaction can never leave the state partially updated.
+pub(crate) struct ChainState {
+    // Current tip of the chain
+    pub tip_height: Option<Height>,
+    pub tip_hash: Option<block::Hash>,
+
+    // Set of all unspent transparent outputs.
+    // This is expected to grow but very slowly since transparent outputs are only created
+    // by deposits and destroyed by subsequent spends.
+    pub utxos_set: HashMap<OutPoint, OrderedUtxo>,
+
+    // Multiset hash of the snapshot encoding of every UTXO. This is kept up to date as outputs are
+    // created and spent so the state root never needs to hash the whole set
+    pub utxos_digest: SetHash,
+
+    // The frontier of the commitment tree. This is the Merkle path of the last added commitment
+    // Internally this just stores the frontier which is the Merkle path of the most recently added note
+    // This is all that is needed to update the root and produce a new frontier when new commitments are added
+    // So for a fixed depth this is constant size!
+    pub commitment_tree_frontier: NoteCommitmentTree,
+
+    // A FIFO queue of distinct orchard commitment tree roots and the height they were first seen.
+    // Only those inside the anchor window are kept so we can have constant state.
+    // This means older witnesses become invalid after a period of time
+    pub historical_tree_roots: Anchors,
+
+    // A set of all nullifiers that have been seen. This prevents double spends.
+    // With the default accumulator this is not fixed size. The sparse Merkle tree accumulator
+    // still stores every nullifier but allows the set to be replaced with client supplied
+    // non-membership witnesses in the future. This would require updates to wallets though
+    pub nullifier_set: Box<dyn NullifierAccumulator>,
+
+    // Undo records for the most recent blocks so the state can be rolled back.
+    // These are not part of the state commitment or snapshots
+    pub undo_log: UndoLog,
+}
+
+impl Clone for ChainState {
+    fn clone(&self) -> Self {
+        Self {
+            tip_height: self.tip_height,
+            tip_hash: self.tip_hash,
+            utxos_set: self.utxos_set.clone(),
+            utxos_digest: self.utxos_digest,
+            commitment_tree_frontier: self.commitment_tree_frontier.clone(),
+            historical_tree_roots: self.historical_tree_roots.clone(),
+            nullifier_set: self.nullifier_set.boxed_clone(),
+            undo_log: self.undo_log.clone(),
+        }
+    }
+}
+
+impl PartialEq for ChainState {
+    fn eq(&self, other: &Self) -> bool {
+        self.tip_height == other.tip_height
+            && self.tip_hash == other.tip_hash
+            && self.utxos_set == other.utxos_set
+            && self.commitment_tree_frontier == other.commitment_tree_frontier
+            && self.historical_tree_roots == other.historical_tree_roots
+            && self.nullifier_set.kind() == other.nullifier_set.kind()
+            && self.nullifier_set.root() == other.nullifier_set.root()
+    }
+}
+
+impl fmt::Debug for ChainState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainState")
+            .field("tip_height", &self.tip_height)
+            .field("tip_hash", &self.tip_hash)
+            .field("utxos_set", &self.utxos_set)
+            .field("historical_tree_roots", &self.historical_tree_roots)
+            .field("nullifier_set", &self.nullifier_set)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The new UTXOs the block at `height` creates. Like in Zcash the genesis outputs are unspendable.
+/// Outputs paying the Mt Doom script are burned so never become spendable either
+pub fn spendable_outputs(
+    block: &Block,
+    height: Height,
+    transaction_hashes: &[transaction::Hash],
+) -> HashMap<OutPoint, OrderedUtxo> {
+    if height == Height(0) {
+        return HashMap::new();
+    }
+    let mut new_outputs = transparent::new_ordered_outputs(block, transaction_hashes);
+    new_outputs.retain(|_, utxo| extract_transparent_burn_info(&utxo.utxo.output).is_none());
+    new_outputs
+}
+
+/// Undo records for the most recent blocks, oldest first.
+/// Up to `depth` blocks can be undone. Rolling back returns the new tip block so its record is needed too,
+/// which is why `depth + 1` records are kept. No records are made if `depth` is zero
+#[derive(Clone, Debug)]
+pub(crate) struct UndoLog {
+    depth: usize,
+    blocks: VecDeque<BlockUndo>,
+}
+
+// Everything needed to revert a committed block and the block itself
+#[derive(Clone, Debug)]
+struct BlockUndo {
+    block: Arc<Block>,
+    height: Height,
+    previous_tip: Option<(Height, block::Hash)>,
+    created_outpoints: Vec<OutPoint>,
+    spent_utxos: Vec<(OutPoint, OrderedUtxo)>,
+    inserted_nullifiers: Vec<Nullifier>,
+    commitment_tree_frontier: NoteCommitmentTree,
+    anchors: AnchorsUndo,
+}
+
+impl UndoLog {
+    pub fn new(depth: u32) -> Self {
+        Self {
+            depth: depth as usize,
+            blocks: VecDeque::new(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    fn push(&mut self, undo: BlockUndo) {
+        self.blocks.push_back(undo);
+        while self.blocks.len() > self.depth + 1 {
+            self.blocks.pop_front();
+        }
+    }
+}
+
+/// The changes a single block makes to the [`ChainState`].
+///
+/// These are built up against a read-only view of the state and only applied
+/// by [`ChainState::commit`] once the block has been fully verified.
+/// Dropping a staged block discards all of its changes.
+pub(crate) struct StagedBlock {
+    block: Arc<Block>,
+    height: Height,
+    hash: block::Hash,
+    new_outputs: HashMap<OutPoint, OrderedUtxo>,
+    new_nullifiers: HashSet<Nullifier>,
+    spent_outpoints: HashSet<OutPoint>,
+    commitment_tree_frontier: NoteCommitmentTree,
+}
+
+impl ChainState {
+    pub fn new(
+        nullifier_accumulator: NullifierAccumulatorKind,
+        anchor_window: AnchorWindow,
+        rollback_depth: u32,
+    ) -> Self {
+        Self {
+            tip_height: None,
+            tip_hash: None,
+            commitment_tree_frontier: NoteCommitmentTree::default(),
+            historical_tree_roots: Anchors::new(anchor_window),
+            utxos_set: HashMap::new(),
+            utxos_digest: SetHash::new(UTXOS_DOMAIN),
+            nullifier_set: nullifier_accumulator.build(),
+            undo_log: UndoLog::new(rollback_depth),
+        }
+    }
+
+    /// The height the next block added to the chain will have
+    pub fn next_height(&self) -> Height {
+        self.tip_height
+            .map(|h| h.next().unwrap())
+            .unwrap_or(Height(0))
+    }
+
+    /// The hash the next block added to the chain must reference as its parent
+    pub fn previous_block_hash(&self) -> block::Hash {
+        self.tip_hash.unwrap_or(Default::default())
+    }
+
+    /// Stage the changes the given block would make to the state.
+    /// This checks the block doesn't reuse any known nullifiers or spend any unknown or already spent
+    /// transparent outputs but does not modify the state.
+    pub fn stage_block(
+        &self,
+        block: &Arc<Block>,
+        height: Height,
+        transaction_hashes: &[transaction::Hash],
+    ) -> Result<StagedBlock, Error> {
+        // check this block doesn't reuse a known nullifier or reveal the same nullifier twice
+        let mut new_nullifiers = HashSet::new();
+        for nullifier in block.orchard_nullifiers() {
+            if self.nullifier_set.contains(nullifier) || !new_nullifiers.insert(*nullifier) {
+                return Err(Error::DuplicateNullifier(*nullifier));
+            }
+        }
+
+        // check every transparent input spends a known output that no other input in this block spends
+        let mut spent_outpoints = HashSet::new();
+        for outpoint in block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs())
+            .filter_map(|input| input.outpoint())
+        {
+            if !self.utxos_set.contains_key(&outpoint) {
+                return Err(Error::UnknownOutPoint(outpoint));
+            }
+            if !spent_outpoints.insert(outpoint) {
+                return Err(Error::DuplicateSpend(outpoint));
+            }
+        }
+
+        // notes sent to Mt Doom must never be spent so their nullifiers are revealed as soon as they are created
+        new_nullifiers.extend(
+            block
+                .transactions
+                .iter()
+                .flat_map(|tx| tx.orchard_actions())
+                .filter_map(burned_note_nullifier),
+        );
+
+        let new_outputs = spendable_outputs(block, height, transaction_hashes);
+
+        // update a copy of the commitment tree frontier
+        let mut commitment_tree_frontier = self.commitment_tree_frontier.clone();
+        for commitment in block.orchard_note_commitments() {
+            commitment_tree_frontier
+                .append(*commitment)
+                .expect("failed to append to tree");
+        }
+
+        Ok(StagedBlock {
+            block: block.clone(),
+            height,
+            hash: block.hash(),
+            new_outputs,
+            new_nullifiers,
+            spent_outpoints,
+            commitment_tree_frontier,
+        })
+    }
+
+    /// Ensure the transaction doesn't reveal a nullifier that is already in the state.
+    /// This includes the nullifiers of notes burned by sending them to Mt Doom
+    pub fn check_nullifiers(&self, tx: &Transaction) -> Result<(), Error> {
+        match tx
+            .orchard_nullifiers()
+            .find(|nullifier| self.nullifier_set.contains(nullifier))
+        {
+            Some(nullifier) => Err(Error::DuplicateNullifier(*nullifier)),
+            None => Ok(()),
+        }
+    }
+
+    /// Ensure the anchor/tree-root referenced by the transaction is in the state
+    pub fn check_anchor(&self, tx: &Transaction) -> Result<(), Error> {
+        if let Some(data) = tx.orchard_shielded_data() {
+            self.historical_tree_roots
+                .check(&data.shared_anchor, self.tip_height.unwrap_or(Height(0)))?;
+        }
+        Ok(())
+    }
+
+    /// The outputs spent by each transparent input of the transaction, in input order.
+    /// Fails if any input spends an output that is not in the UTXO set
+    pub fn outputs_spent_by_transaction(
+        &self,
+        tx: Arc<Transaction>,
+    ) -> Result<Vec<transparent::Output>, Error> {
+        tx.inputs()
+            .iter()
+            .filter_map(|input| input.outpoint())
+            .map(|outpoint| {
+                self.utxos_set
+                    .get(&outpoint)
+                    .map(|utxo| utxo.as_ref().output.clone())
+                    .ok_or(Error::UnknownOutPoint(outpoint))
+            })
+            .collect()
+    }
+
+    /// Apply the changes of a fully verified block to the state
+    pub fn commit(&mut self, staged: StagedBlock) {
+        // the record is only built if the state can be rolled back
+        let mut undo = self.undo_log.is_enabled().then(|| BlockUndo {
+            block: staged.block.clone(),
+            height: staged.height,
+            previous_tip: self.tip_height.zip(self.tip_hash),
+            created_outpoints: staged.new_outputs.keys().copied().collect(),
+            spent_utxos: Vec::new(),
+            inserted_nullifiers: Vec::new(),
+            commitment_tree_frontier: self.commitment_tree_frontier.clone(),
+            anchors: AnchorsUndo::default(),
+        });
+
+        self.tip_height = Some(staged.height);
+        self.tip_hash = Some(staged.hash);
+
+        tracing::info!("Adding new UTXOs to the set: {:?}", staged.new_outputs);
+        for outpoint in &staged.spent_outpoints {
+            if let Some(utxo) = self.remove_utxo(outpoint) {
+                if let Some(undo) = &mut undo {
+                    undo.spent_utxos.push((*outpoint, utxo));
+                }
+            }
+        }
+        for (outpoint, utxo) in staged.new_outputs {
+            self.insert_utxo(outpoint, utxo);
+        }
+        for nullifier in staged.new_nullifiers {
+            // only remember nullifiers this block added so a rollback never removes an earlier one
+            if !self.nullifier_set.contains(&nullifier) {
+                self.nullifier_set.insert(nullifier);
+                if let Some(undo) = &mut undo {
+                    undo.inserted_nullifiers.push(nullifier);
+                }
+            }
+        }
+
+        self.commitment_tree_frontier = staged.commitment_tree_frontier;
+        let anchors = self
+            .historical_tree_roots
+            .push(staged.height, self.commitment_tree_frontier.root());
+
+        if let Some(mut undo) = undo {
+            undo.anchors = anchors;
+            self.undo_log.push(undo);
+        }
+    }
+
+    /// Revert every block above `height` so the state is exactly as it was after that block was committed.
+    /// Returns the block at `height`, which is the new tip.
+    /// At most `rollback_depth` blocks can be undone, and none from before a snapshot was loaded.
+    /// Nothing can be undone if `rollback_depth` is zero
+    pub fn rollback_to(&mut self, height: Height) -> Result<Arc<Block>, Error> {
+        match self.tip_height {
+            Some(tip_height) if height <= tip_height => {}
+            _ => return Err(Error::RollbackAboveTip(height)),
+        }
+        if !self
+            .undo_log
+            .blocks
+            .iter()
+            .any(|undo| undo.height == height)
+        {
+            return Err(Error::RollbackUnavailable(height));
+        }
+
+        while self.tip_height != Some(height) {
+            let undo = self
+                .undo_log
+                .blocks
+                .pop_back()
+                .expect("undo records are kept for every block above the target");
+            self.revert(undo);
+        }
+        Ok(self
+            .undo_log
+            .blocks
+            .back()
+            .expect("target block is retained")
+            .block
+            .clone())
+    }
+
+    // Revert the changes the tip block made to the state
+    fn revert(&mut self, undo: BlockUndo) {
+        for outpoint in &undo.created_outpoints {
+            self.remove_utxo(outpoint);
+        }
+        for (outpoint, utxo) in undo.spent_utxos {
+            self.insert_utxo(outpoint, utxo);
+        }
+        for nullifier in &undo.inserted_nullifiers {
+            self.nullifier_set.remove(nullifier);
+        }
+        self.commitment_tree_frontier = undo.commitment_tree_frontier;
+        self.historical_tree_roots.undo(undo.anchors);
+        self.tip_height = undo.previous_tip.map(|(height, _)| height);
+        self.tip_hash = undo.previous_tip.map(|(_, hash)| hash);
+    }
+
+    // Add an output to the UTXO set keeping the digest in sync
+    fn insert_utxo(&mut self, outpoint: OutPoint, utxo: OrderedUtxo) {
+        self.utxos_digest.insert(&utxo_entry(&outpoint, &utxo));
+        if let Some(replaced) = self.utxos_set.insert(outpoint, utxo) {
+            self.utxos_digest.remove(&utxo_entry(&outpoint, &replaced));
+        }
+    }
+
+    // Remove an output from the UTXO set keeping the digest in sync
+    fn remove_utxo(&mut self, outpoint: &OutPoint) -> Option<OrderedUtxo> {
+        let utxo = self.utxos_set.remove(outpoint)?;
+        self.utxos_digest.remove(&utxo_entry(outpoint, &utxo));
+        Some(utxo)
+    }
+}
+
+impl StagedBlock {
+    pub fn new_outputs(&self) -> &HashMap<OutPoint, OrderedUtxo> {
+        &self.new_outputs
+    }
+}
@@ -0,0 +1,70 @@
+//! A canonical commitment to the full TinyCash application state.
+//!
+//! The state root is the BLAKE2b-256 hash (personalization `TinyCashStateRt_`) of
+//!
+//! ```text
+//! tip height          u32 little-endian (0 if there is no tip)
+//! tip hash            32 bytes (zero if there is no tip)
+//! utxo set digest     32 bytes
+//! nullifier digest    32 bytes
+//! anchors digest      32 bytes
+//! ```
+//!
+//! where the UTXO set and anchors digests are the BLAKE2b-256 hash, under their own personalization,
+//! of the number of entries (u32 little-endian) and the multiset hash (see [`crate::set_hash`]) of the
+//! snapshot encoding of each entry (see [`crate::snapshot`]). The nullifier digest is the root of the
+//! configured nullifier accumulator (see [`crate::nullifiers`]).
+//!
+//! Every digest is updated as a block is committed or reverted, touching only the entries it changes,
+//! so computing the state root does not depend on the size of the state.
+//! Two instances that processed the same inputs will always produce the same state root.
+
+use std::fmt;
+
+use crate::nullifiers::{finalize, hasher};
+use crate::state::ChainState;
+
+const STATE_ROOT_PERSONALIZATION: &[u8; 16] = b"TinyCashStateRt_";
+const UTXOS_PERSONALIZATION: &[u8; 16] = b"TinyCashUtxoSet_";
+const ANCHORS_PERSONALIZATION: &[u8; 16] = b"TinyCashAnchors_";
+
+/// A commitment to the tip, the UTXO set, the nullifier set and the recent anchors
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StateRoot(pub [u8; 32]);
+
+impl fmt::Debug for StateRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StateRoot")
+            .field(&hex::encode(self.0))
+            .finish()
+    }
+}
+
+impl fmt::Display for StateRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl ChainState {
+    /// Compute the state root of the current state
+    pub fn state_root(&self) -> StateRoot {
+        let utxos = self
+            .utxos_digest
+            .commitment(UTXOS_PERSONALIZATION, self.utxos_set.len());
+        let nullifiers = self.nullifier_set.root();
+        let anchors = self
+            .historical_tree_roots
+            .digest()
+            .commitment(ANCHORS_PERSONALIZATION, self.historical_tree_roots.len());
+
+        let mut state = hasher(STATE_ROOT_PERSONALIZATION);
+        state.update(&self.tip_height.map(|h| h.0).unwrap_or(0).to_le_bytes());
+        state.update(&self.tip_hash.map(|h| h.0).unwrap_or([0; 32]));
+        state.update(&utxos);
+        state.update(&nullifiers);
+        state.update(&anchors);
+
+        StateRoot(finalize(&state))
+    }
+}
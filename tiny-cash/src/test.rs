@@ -1,6 +1,30 @@
 use std::collections::HashSet;
 
+use chrono::DateTime;
+
+use crate::anchors::{AnchorWindow, Anchors, EVICTED_CAPACITY};
+use crate::chain_spec::ChainSpec;
+use crate::fees::{transaction_fee, FeePolicy, ZIP317_MARGINAL_FEE};
+use crate::genesis::genesis_block;
+use crate::nullifiers::{
+    verify_membership, verify_non_membership, NullifierAccumulator, NullifierAccumulatorKind,
+    SparseMerkleNullifiers,
+};
 use crate::service::*;
+use crate::set_hash::SetHash;
+use crate::shielded_mint::authorize_bundle;
+use crate::snapshot::{SnapshotError, SNAPSHOT_VERSION};
+use crate::state::ChainState;
+use incrementalmerkletree::{Hashable, Level};
+use orchard::{
+    builder::{Builder, BundleType},
+    keys::{FullViewingKey, Scope, SpendAuthorizingKey, SpendingKey},
+    note::ExtractedNoteCommitment,
+    tree::{Anchor, MerkleHashOrchard, MerklePath},
+    value::NoteValue,
+};
+use pasta_curves::group::ff::PrimeField;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
 use tower::ServiceExt;
 use tower::{buffer::Buffer, util::BoxService};
 use zebra_chain::parameters::{Network, NetworkUpgrade};
@@ -9,10 +33,12 @@ use zebra_chain::transparent::Script;
 
 use tower::{BoxError, Service};
 
-use zebra_chain::transaction::Transaction;
+use zebra_chain::orchard::{tree::Root, Nullifier};
+use zebra_chain::serialization::{ZcashDeserialize, ZcashSerialize};
+use zebra_chain::transaction::{self, Transaction};
 use zebra_chain::transparent;
 use zebra_chain::{
-    amount::{Amount, NonNegative},
+    amount::{Amount, NonNegative, MAX_MONEY},
     block,
     block::Height,
 };
@@ -22,6 +48,11 @@ fn accepting() -> Script {
     Script::new(&[1, 1])
 }
 
+// anything sent to this script can never be spent (OP_FALSE)
+fn rejecting() -> Script {
+    Script::new(&[0])
+}
+
 #[tokio::test(flavor = "multi_thread")]
 #[tracing_test::traced_test]
 async fn test_genesis() {
@@ -67,6 +98,7 @@ async fn test_mint_txns_update_balance() {
             Request::Mint {
                 amount: Amount::try_from(1).unwrap(),
                 to: recipient.create_script_from_address(),
+                context: BlockContext::default(),
             },
             &mut tinycash,
             &mut state_service,
@@ -134,6 +166,7 @@ async fn test_include_transparent_transaction() {
         Request::Mint {
             amount: Amount::try_from(100).unwrap(),
             to: accepting(),
+            context: BlockContext::default(),
         },
         &mut tinycash,
         &mut state_service,
@@ -155,80 +188,2515 @@ async fn test_include_transparent_transaction() {
         .ready()
         .await
         .unwrap()
-        .call(Request::IncludeTransaction { transaction: tx })
+        .call(Request::IncludeTransaction {
+            transaction: tx,
+            context: BlockContext::default(),
+        })
         .await
         .unwrap();
 }
 
-// combines tiny-cash with a zebra state service.
-// Adds the block to the state service after it has been verified and added to tiny-cash
-async fn exeucte_and_commit_block<TC, S>(
-    request: Request,
-    tinycash: &mut TC,
-    state_service: &mut S,
-) -> Result<std::sync::Arc<block::Block>, tower::BoxError>
-where
-    TC: Service<Request, Response = Response, Error = BoxError> + Send + Clone + 'static,
-    TC::Future: Send + 'static,
-    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = BoxError>
-        + Send
-        + Clone
-        + 'static,
-    S::Future: Send + 'static,
-{
-    let response = tinycash.ready().await?.call(request).await?;
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_rejected_transactions_do_not_modify_state() {
+    let handle = TinyCash::new();
+    let mut tinycash = Buffer::new(BoxService::new(handle.clone()), 10);
 
-    if response.block.height == Height(0) {
-        // genesis block needs to be committed as a checkpoint
-        state_service
-            .ready()
-            .await?
-            .call(zebra_state::Request::CommitCheckpointVerifiedBlock(
-                zebra_state::CheckpointVerifiedBlock::from(response.block.clone().block),
-            ))
-            .await?;
-    } else {
-        state_service
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let b1 = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount: Amount::try_from(100).unwrap(),
+            to: rejecting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+
+    let before = handle.chain_state().await;
+
+    // fails script verification after the block has been staged
+    let unspendable = build_transaction_spending(
+        transparent::OutPoint {
+            hash: b1.transactions[0].hash(),
+            index: 0,
+        },
+        100.try_into().unwrap(),
+    );
+    // fails the transaction version check
+    let v4 = Transaction::V4 {
+        inputs: Vec::new(),
+        outputs: vec![transparent::Output {
+            value: 100.try_into().unwrap(),
+            lock_script: accepting(),
+        }],
+        lock_time: LockTime::Height(Height(0)),
+        expiry_height: Height(0),
+        joinsplit_data: None,
+        sapling_shielded_data: None,
+    };
+
+    for transaction in [unspendable, v4] {
+        tinycash
             .ready()
-            .await?
-            .call(zebra_state::Request::CommitSemanticallyVerifiedBlock(
-                response.block.clone(),
-            ))
-            .await?;
+            .await
+            .unwrap()
+            .call(Request::IncludeTransaction {
+                transaction,
+                context: BlockContext::default(),
+            })
+            .await
+            .expect_err("transaction should be rejected");
+
+        assert_state_unchanged(&before, &handle.chain_state().await);
     }
-    Ok(response.block.block)
+
+    // a valid block still builds on the unchanged tip
+    let b2 = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount: Amount::try_from(1).unwrap(),
+            to: accepting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block;
+    assert_eq!(b2.height, Height(2));
+    assert_eq!(b2.block.header.previous_block_hash, b1.hash());
 }
 
-/// Given a `previous_outpoint` build a new transaction that should pass
-fn build_transaction_spending(
-    previous_outpoint: transparent::OutPoint, // specifies how to find the UTXOs to spend
-    amount: Amount<NonNegative>,
-    // script_should_succeed: bool,
-) -> Transaction {
-    // A script with a single opcode that accepts the transaction (pushes true on the stack)
-    let accepting_script = transparent::Script::new(&[1, 1]);
-    // A script with a single opcode that rejects the transaction (OP_FALSE)
-    // let rejecting_script = transparent::Script::new(&[0]);
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_duplicate_nullifier_within_block_does_not_modify_state() {
+    let (mut tinycash, commitments, sk, note, mut rng) = chain_with_shielded_note().await;
 
-    // Use the `previous_outpoint` as input
-    let input = transparent::Input::PrevOut {
-        outpoint: previous_outpoint,
-        unlock_script: accepting_script.clone(),
-        sequence: 0,
-    };
+    // the same note spent twice in one transaction reveals its nullifier twice
+    let path = commitments.path(&note);
+    let transaction = build_shielded_spend(
+        &sk,
+        &[(note, path.clone()), (note, path)],
+        note.recipient(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let reasons =
+        rejected_without_state_change(&mut tinycash, transaction, BlockContext::default()).await;
+    assert!(matches!(reasons.as_slice(), [Error::DuplicateNullifier(_)]));
+}
 
-    let output = transparent::Output {
-        value: amount,
-        lock_script: accepting_script,
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_duplicate_nullifier_against_state_does_not_modify_state() {
+    let (mut tinycash, commitments, sk, note, mut rng) = chain_with_shielded_note().await;
+    let path = commitments.path(&note);
+
+    let spend = build_shielded_spend(
+        &sk,
+        &[(note, path.clone())],
+        note.recipient(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+
+    // a different transaction spending the same note reveals a nullifier already in the state
+    let double_spend = build_shielded_spend(
+        &sk,
+        &[(note, path)],
+        note.recipient(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let reasons =
+        rejected_without_state_change(&mut tinycash, double_spend, BlockContext::default()).await;
+    assert!(matches!(reasons.as_slice(), [Error::DuplicateNullifier(_)]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_unknown_anchor_does_not_modify_state() {
+    let (mut tinycash, _, sk, _, mut rng) = chain_with_shielded_note().await;
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+
+    // the root of a tree this chain never had
+    let mut tree = zebra_chain::orchard::tree::NoteCommitmentTree::default();
+    tree.append(pasta_curves::pallas::Base::from(1)).unwrap();
+    let transaction = build_shielding_transaction(
+        outpoint,
+        amount,
+        FullViewingKey::from(&sk).address_at(0_usize, Scope::External),
+        tree.root(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let reasons =
+        rejected_without_state_change(&mut tinycash, transaction, BlockContext::default()).await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::UnknownAnchor(root)] if *root == tree.root()
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_evicted_anchor_is_too_old_and_never_valid_anchor_is_unknown() {
+    // only the current root is a valid anchor
+    let mut tinycash = TinyCash::with_config(Config {
+        anchor_window: AnchorWindow::Roots(1),
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    // shielding a note changes the tree root, evicting the root of the empty tree
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let sk = SpendingKey::from_bytes([7; 32]).unwrap();
+    shielded_note(
+        &mut tinycash,
+        &mut NoteCommitments::default(),
+        &sk,
+        Amount::try_from(1_000).unwrap(),
+        &ChainSpec::default(),
+        &mut rng,
+    )
+    .await;
+
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+    let to = FullViewingKey::from(&sk).address_at(0_usize, Scope::External);
+
+    let empty_root = zebra_chain::orchard::tree::NoteCommitmentTree::default().root();
+    let transaction = build_shielding_transaction(
+        outpoint,
+        amount,
+        to,
+        empty_root,
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let reasons =
+        rejected_without_state_change(&mut tinycash, transaction, BlockContext::default()).await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::AnchorTooOld {
+            anchor_height: Height(0),
+            oldest_anchor_height: Height(2),
+            tip_height: Height(3),
+        }]
+    ));
+
+    // the root of a tree this chain never had is unknown even though roots have been evicted
+    let mut tree = zebra_chain::orchard::tree::NoteCommitmentTree::default();
+    tree.append(pasta_curves::pallas::Base::from(1)).unwrap();
+    let transaction = build_shielding_transaction(
+        outpoint,
+        amount,
+        to,
+        tree.root(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let reasons =
+        rejected_without_state_change(&mut tinycash, transaction, BlockContext::default()).await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::UnknownAnchor(root)] if *root == tree.root()
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_lock_time_and_expiry_do_not_modify_state() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+
+    // the next block is at height 2
+    let context = BlockContext {
+        time: DateTime::from_timestamp(1710913093, 0).unwrap(),
+        l1_block_number: 12,
+    };
+    let spend_with = |lock: LockTime, expiry: Height| {
+        let mut tx = build_transaction_spending(outpoint, amount);
+        if let Transaction::V5 {
+            lock_time,
+            expiry_height,
+            ..
+        } = &mut tx
+        {
+            *lock_time = lock;
+            *expiry_height = expiry;
+        }
+        tx
     };
 
-    Transaction::V5 {
-        inputs: vec![input],
-        outputs: vec![output],
-        lock_time: LockTime::Height(Height(0)),
-        expiry_height: Height(0),
-        sapling_shielded_data: None,
-        orchard_shielded_data: None,
-        network_upgrade: NetworkUpgrade::Nu5,
+    let reasons = rejected_without_state_change(
+        &mut tinycash,
+        spend_with(LockTime::Height(Height(2)), Height(0)),
+        context,
+    )
+    .await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::LockTimeNotReached(LockTime::Height(Height(2)))]
+    ));
+
+    let reasons = rejected_without_state_change(
+        &mut tinycash,
+        spend_with(LockTime::Time(context.time), Height(0)),
+        context,
+    )
+    .await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::LockTimeNotReached(LockTime::Time(_))]
+    ));
+
+    let reasons = rejected_without_state_change(
+        &mut tinycash,
+        spend_with(LockTime::unlocked(), Height(1)),
+        context,
+    )
+    .await;
+    assert!(matches!(
+        reasons.as_slice(),
+        [Error::Expired {
+            expiry_height: Height(1),
+            height: Height(2)
+        }]
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_include_transactions_batch() {
+    let network = Network::Mainnet;
+
+    let (state_service, _, _, _) = zebra_state::init(
+        zebra_state::Config::ephemeral(),
+        network,
+        block::Height::MAX,
+        0,
+    );
+
+    let mut state_service = Buffer::new(state_service, 10);
+    let mut tinycash = Buffer::new(BoxService::new(TinyCash::new()), 10);
+
+    exeucte_and_commit_block(Request::Genesis, &mut tinycash, &mut state_service)
+        .await
+        .unwrap();
+
+    let mut outpoints = Vec::new();
+    for to in [accepting(), accepting(), rejecting()] {
+        let block = exeucte_and_commit_block(
+            Request::Mint {
+                amount: Amount::try_from(100).unwrap(),
+                to,
+                context: BlockContext::default(),
+            },
+            &mut tinycash,
+            &mut state_service,
+        )
+        .await
+        .unwrap();
+        outpoints.push(transparent::OutPoint {
+            hash: block.transactions[0].hash(),
+            index: 0,
+        });
+    }
+
+    let transactions: Vec<_> = outpoints
+        .into_iter()
+        .map(|outpoint| build_transaction_spending(outpoint, 100.try_into().unwrap()))
+        .collect();
+    let hashes: Vec<_> = transactions.iter().map(|tx| tx.hash()).collect();
+
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransactions(
+            transactions,
+            BlockContext::default(),
+        ))
+        .await
+        .unwrap();
+
+    // one block with the coinbase and the two valid transactions
+    assert_eq!(response.block.height, Height(4));
+    assert_eq!(response.block.block.transactions.len(), 3);
+    assert!(response.burns.is_empty());
+
+    assert_eq!(response.transaction_results.len(), 3);
+    for ((hash, result), expected_hash) in response.transaction_results.iter().zip(&hashes) {
+        assert_eq!(hash, expected_hash);
+        assert_eq!(
+            result.is_ok(),
+            response.block.transaction_hashes.contains(hash)
+        );
+    }
+    assert!(response.transaction_results[0].1.is_ok());
+    assert!(response.transaction_results[1].1.is_ok());
+    assert!(response.transaction_results[2].1.is_err());
+
+    state_service
+        .ready()
+        .await
+        .unwrap()
+        .call(zebra_state::Request::CommitSemanticallyVerifiedBlock(
+            response.block,
+        ))
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_batch_verification_finds_invalid_transaction() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let amount = Amount::try_from(100).unwrap();
+    let mut outpoints = Vec::new();
+    for _ in 0..4 {
+        outpoints.push(mint_spendable(&mut tinycash, amount).await);
+    }
+    // this one passes the contextual checks but fails script verification
+    let block = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount,
+            to: rejecting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+    outpoints.insert(
+        2,
+        transparent::OutPoint {
+            hash: block.transactions[0].hash(),
+            index: 0,
+        },
+    );
+
+    let transactions: Vec<_> = outpoints
+        .iter()
+        .map(|outpoint| build_transaction_spending(*outpoint, amount))
+        .collect();
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransactions(
+            transactions,
+            BlockContext::default(),
+        ))
+        .await
+        .unwrap();
+
+    // only the invalid transaction is left out of the block
+    assert_eq!(response.block.block.transactions.len(), 5);
+    for (i, (_, result)) in response.transaction_results.iter().enumerate() {
+        if i == 2 {
+            assert!(matches!(result, Err(Error::InvalidTransaction(_))));
+        } else {
+            assert!(result.is_ok());
+        }
     }
+    let state = tinycash.chain_state().await;
+    assert!(state.utxos_set.contains_key(&outpoints[2]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_batch_verification_finds_invalid_orchard_spend() {
+    let (mut tinycash, mut commitments, sk, note, mut rng) = chain_with_shielded_note().await;
+    let mut notes = vec![note];
+    for _ in 0..3 {
+        notes.push(
+            shielded_note(
+                &mut tinycash,
+                &mut commitments,
+                &sk,
+                Amount::try_from(1_000).unwrap(),
+                &ChainSpec::default(),
+                &mut rng,
+            )
+            .await,
+        );
+    }
+
+    let transactions: Vec<_> = notes
+        .iter()
+        .enumerate()
+        .map(|(i, note)| {
+            let mut transaction = build_shielded_spend(
+                &sk,
+                &[(*note, commitments.path(note))],
+                note.recipient(),
+                &ChainSpec::default(),
+                &mut rng,
+            );
+            // changing the transaction after it was signed invalidates its signatures
+            if i == 2 {
+                if let Transaction::V5 { expiry_height, .. } = &mut transaction {
+                    *expiry_height = Height(100);
+                }
+            }
+            transaction
+        })
+        .collect();
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransactions(
+            transactions.clone(),
+            BlockContext::default(),
+        ))
+        .await
+        .unwrap();
+
+    // only the spend with the bad signatures is left out of the block
+    assert_eq!(response.block.block.transactions.len(), 4);
+    for (i, (_, result)) in response.transaction_results.iter().enumerate() {
+        if i == 2 {
+            assert!(matches!(result, Err(Error::InvalidTransaction(_))));
+        } else {
+            assert!(result.is_ok());
+        }
+    }
+    let state = tinycash.chain_state().await;
+    for (i, transaction) in transactions.iter().enumerate() {
+        assert_eq!(
+            transaction
+                .orchard_nullifiers()
+                .all(|nullifier| state.nullifier_set.contains(nullifier)),
+            i != 2
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_state_root_commits_to_each_block() {
+    let mut tinycash = Buffer::new(BoxService::new(TinyCash::new()), 10);
+
+    let mut roots = HashSet::new();
+    let genesis = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    roots.insert(genesis.state_root);
+
+    for _ in 0..5 {
+        let response = tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Mint {
+                amount: Amount::try_from(1).unwrap(),
+                to: accepting(),
+                context: BlockContext::default(),
+            })
+            .await
+            .unwrap();
+        // every block changes the tip so must change the state root
+        assert!(roots.insert(response.state_root));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_state_root_is_updated_incrementally() {
+    let (mut tinycash, commitments, sk, note, mut rng) = chain_with_shielded_note().await;
+
+    // spend the note so the block adds a nullifier and an anchor
+    let to = FullViewingKey::from(&sk).address_at(0_usize, Scope::External);
+    let spend = build_shielded_spend(
+        &sk,
+        &[(note, commitments.path(&note))],
+        to,
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    assert!(response.transaction_results[0].1.is_ok());
+
+    // a state restored from a snapshot computes every digest from scratch
+    let restored = TinyCash::from_snapshot(&tinycash.snapshot().await).unwrap();
+    assert_eq!(
+        restored.chain_state().await.state_root(),
+        response.state_root
+    );
+
+    // rolling back removes exactly the entries the reverted blocks added, including the spent UTXO
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(1)))
+        .await
+        .unwrap();
+    let restored = TinyCash::from_snapshot(&tinycash.snapshot().await).unwrap();
+    assert_eq!(
+        restored.chain_state().await.state_root(),
+        response.state_root
+    );
+}
+
+#[test]
+fn test_set_hash() {
+    let mut forwards = SetHash::new("TinyCash-Test");
+    let empty = forwards.commitment(b"TinyCashTestSet_", 0);
+    for element in [b"one", b"two", b"six"] {
+        forwards.insert(element);
+    }
+    let mut backwards = SetHash::new("TinyCash-Test");
+    for element in [b"six", b"two", b"one"] {
+        backwards.insert(element);
+    }
+    // the digest does not depend on insertion order
+    assert_eq!(forwards, backwards);
+
+    // sets in different domains have different digests
+    let mut other_domain = SetHash::new("TinyCash-Other");
+    for element in [b"one", b"two", b"six"] {
+        other_domain.insert(element);
+    }
+    assert_ne!(other_domain, forwards);
+
+    // removing an element is the same as never inserting it
+    forwards.remove(b"two");
+    let mut without = SetHash::new("TinyCash-Test");
+    without.insert(b"one");
+    without.insert(b"six");
+    assert_eq!(forwards, without);
+    forwards.remove(b"one");
+    forwards.remove(b"six");
+    assert_eq!(forwards.commitment(b"TinyCashTestSet_", 0), empty);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_snapshot_round_trip() {
+    let handle = TinyCash::new();
+    let mut tinycash = Buffer::new(BoxService::new(handle.clone()), 10);
+
+    // an empty state round trips
+    let empty = TinyCash::from_snapshot(&handle.snapshot().await).unwrap();
+    assert_eq!(empty.chain_state().await, handle.chain_state().await);
+
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let recipient = transparent::Address::from_pub_key_hash(Network::Mainnet, [2; 20]);
+    for _ in 0..10 {
+        tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Mint {
+                amount: Amount::try_from(1).unwrap(),
+                to: recipient.create_script_from_address(),
+                context: BlockContext::default(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let b1 = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount: Amount::try_from(100).unwrap(),
+            to: accepting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+    let tx = build_transaction_spending(
+        transparent::OutPoint {
+            hash: b1.transactions[0].hash(),
+            index: 0,
+        },
+        100.try_into().unwrap(),
+    );
+
+    let snapshot = handle.snapshot().await;
+    let restored = TinyCash::from_snapshot(&snapshot).unwrap();
+
+    assert_eq!(restored.chain_state().await, handle.chain_state().await);
+    assert_eq!(restored.snapshot().await, snapshot);
+    assert_eq!(
+        restored.chain_state().await.state_root(),
+        handle.chain_state().await.state_root()
+    );
+
+    // both instances produce the same next block and the same resulting snapshot
+    let mut restored_service = Buffer::new(BoxService::new(restored.clone()), 10);
+    let mut next_blocks = Vec::new();
+    let mut state_roots = Vec::new();
+    for service in [&mut tinycash, &mut restored_service] {
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::IncludeTransaction {
+                transaction: tx.clone(),
+                context: BlockContext::default(),
+            })
+            .await
+            .unwrap();
+        next_blocks.push(response.block.hash);
+        state_roots.push(response.state_root);
+    }
+    assert_eq!(next_blocks[0], next_blocks[1]);
+    assert_eq!(state_roots[0], state_roots[1]);
+    assert_eq!(state_roots[0], handle.chain_state().await.state_root());
+    assert_eq!(restored.snapshot().await, handle.snapshot().await);
+
+    // malformed snapshots are rejected
+    assert!(TinyCash::from_snapshot(&snapshot[..snapshot.len() - 1]).is_err());
+    assert!(TinyCash::from_snapshot(&[snapshot.as_slice(), &[0]].concat()).is_err());
+    assert!(TinyCash::from_snapshot(b"not a snapshot").is_err());
+    let mut other_version = snapshot.clone();
+    other_version[4] = SNAPSHOT_VERSION + 1;
+    assert!(matches!(
+        TinyCash::from_snapshot(&other_version),
+        Err(SnapshotError::UnsupportedVersion(v)) if v == SNAPSHOT_VERSION + 1
+    ));
+}
+
+#[test]
+fn test_sparse_merkle_nullifiers() {
+    let nullifiers: Vec<_> = (0..20u8)
+        .map(|i| {
+            let mut bytes = [0; 32];
+            bytes[0] = i.wrapping_mul(37);
+            bytes[1] = i;
+            bytes[30] = 0xa5 ^ i;
+            Nullifier::try_from(bytes).unwrap()
+        })
+        .collect();
+    let (present, absent) = nullifiers.split_at(10);
+
+    let mut smt = SparseMerkleNullifiers::default();
+    let empty_root = smt.root();
+    for nullifier in present {
+        smt.insert(*nullifier);
+        // inserting twice has no effect
+        smt.insert(*nullifier);
+    }
+    assert_eq!(smt.len(), present.len());
+    assert_ne!(smt.root(), empty_root);
+
+    // the root does not depend on insertion order
+    let mut reversed = SparseMerkleNullifiers::default();
+    for nullifier in present.iter().rev() {
+        reversed.insert(*nullifier);
+    }
+    assert_eq!(reversed.root(), smt.root());
+    assert_eq!(reversed.nullifiers(), smt.nullifiers());
+
+    let root = smt.root();
+    for nullifier in present {
+        assert!(smt.contains(nullifier));
+        let proof = smt.prove(nullifier);
+        assert!(verify_membership(&root, nullifier, &proof));
+        assert!(!verify_non_membership(&root, nullifier, &proof));
+    }
+    for nullifier in absent {
+        assert!(!smt.contains(nullifier));
+        let proof = smt.prove(nullifier);
+        // paths stop at the first leaf or empty subtree so proofs stay small
+        assert!(proof.siblings.len() < 32);
+        assert!(verify_non_membership(&root, nullifier, &proof));
+        assert!(!verify_membership(&root, nullifier, &proof));
+        // a proof against a different root fails
+        assert!(!verify_non_membership(&empty_root, nullifier, &proof));
+        // the path towards a different nullifier does not show this one is absent
+        assert!(!verify_non_membership(
+            &root,
+            nullifier,
+            &smt.prove(&present[0])
+        ));
+    }
+
+    // the tree is canonical. Removing nullifiers gives the same root as never inserting them
+    let mut single = SparseMerkleNullifiers::default();
+    single.insert(present[0]);
+    let mut collapsed = smt.clone();
+    for nullifier in &present[1..] {
+        collapsed.remove(nullifier);
+    }
+    assert_eq!(collapsed.root(), single.root());
+    assert!(smt.prove(&present[0]).siblings.len() > collapsed.prove(&present[0]).siblings.len());
+    assert!(collapsed.prove(&present[0]).siblings.is_empty());
+
+    // removing nullifiers restores the previous roots
+    smt.remove(&absent[0]);
+    assert_eq!(smt.root(), root);
+    for nullifier in present {
+        smt.remove(nullifier);
+    }
+    assert!(smt.is_empty());
+    assert_eq!(smt.root(), empty_root);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_snapshot_round_trip_sparse_merkle_nullifiers() {
+    let handle = TinyCash::with_config(Config {
+        nullifier_accumulator: NullifierAccumulatorKind::SparseMerkle,
+        ..Default::default()
+    });
+    let mut tinycash = Buffer::new(BoxService::new(handle.clone()), 10);
+
+    for request in [
+        Request::Genesis,
+        Request::Mint {
+            amount: Amount::try_from(1).unwrap(),
+            to: accepting(),
+            context: BlockContext::default(),
+        },
+    ] {
+        tinycash.ready().await.unwrap().call(request).await.unwrap();
+    }
+
+    let snapshot = handle.snapshot().await;
+    let restored = TinyCash::from_snapshot(&snapshot).unwrap();
+    let state = restored.chain_state().await;
+    assert_eq!(
+        state.nullifier_set.kind(),
+        NullifierAccumulatorKind::SparseMerkle
+    );
+    assert_eq!(state, handle.chain_state().await);
+    assert_eq!(restored.snapshot().await, snapshot);
+}
+
+// Mint the given amount to an accepting script and return the outpoint of the new output
+async fn mint_spendable(
+    tinycash: &mut TinyCash,
+    amount: Amount<NonNegative>,
+) -> transparent::OutPoint {
+    let block = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount,
+            to: accepting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+    transparent::OutPoint {
+        hash: block.transactions[0].hash(),
+        index: 0,
+    }
+}
+
+// Submit the transaction, check it is rejected without changing the state or tip and return why
+async fn rejected_without_state_change(
+    tinycash: &mut TinyCash,
+    transaction: Transaction,
+    context: BlockContext,
+) -> Vec<Error> {
+    let before = tinycash.chain_state().await;
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context,
+        })
+        .await
+        .expect_err("transaction should be rejected");
+    assert_state_unchanged(&before, &tinycash.chain_state().await);
+    rejection_reasons(err)
+}
+
+fn rejection_reasons(err: BoxError) -> Vec<Error> {
+    match *err.downcast::<Error>().expect("expected a TinyCash error") {
+        Error::NoValidTransactions(rejected) => rejected.into_iter().map(|(_, e)| e).collect(),
+        e => vec![e],
+    }
+}
+
+proptest::proptest! {
+    #![proptest_config(proptest::prelude::ProptestConfig::with_cases(8))]
+
+    #[test]
+    fn prop_replayed_transparent_spend_is_rejected(amount in 1..1_000_000i64, replays in 1..4usize) {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let mut tinycash = TinyCash::new();
+            tinycash.ready().await.unwrap().call(Request::Genesis).await.unwrap();
+            let outpoint = mint_spendable(&mut tinycash, amount.try_into().unwrap()).await;
+            let spend = build_transaction_spending(outpoint, amount.try_into().unwrap());
+
+            tinycash
+                .ready()
+                .await
+                .unwrap()
+                .call(Request::IncludeTransaction { transaction: spend.clone(), context: BlockContext::default() })
+                .await
+                .unwrap();
+            let after_spend = tinycash.chain_state().await;
+            assert!(!after_spend.utxos_set.contains_key(&outpoint));
+
+            for _ in 0..replays {
+                let err = tinycash
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(Request::IncludeTransaction { transaction: spend.clone(), context: BlockContext::default() })
+                    .await
+                    .expect_err("replayed spend should be rejected");
+                assert!(matches!(
+                    rejection_reasons(err).as_slice(),
+                    [Error::UnknownOutPoint(o)] if *o == outpoint
+                ));
+                assert_state_unchanged(&after_spend, &tinycash.chain_state().await);
+            }
+        });
+    }
+
+    #[test]
+    fn prop_transparent_spend_replayed_in_batch_is_included_once(amount in 1..1_000_000i64, copies in 2..5usize) {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let mut tinycash = TinyCash::new();
+            tinycash.ready().await.unwrap().call(Request::Genesis).await.unwrap();
+            let outpoint = mint_spendable(&mut tinycash, amount.try_into().unwrap()).await;
+            let spend = build_transaction_spending(outpoint, amount.try_into().unwrap());
+
+            let response = tinycash
+                .ready()
+                .await
+                .unwrap()
+                .call(Request::IncludeTransactions(vec![spend; copies], BlockContext::default()))
+                .await
+                .unwrap();
+
+            // coinbase plus the first copy only
+            assert_eq!(response.block.block.transactions.len(), 2);
+            assert!(response.transaction_results[0].1.is_ok());
+            for (_, result) in &response.transaction_results[1..] {
+                assert!(matches!(result, Err(Error::DuplicateSpend(o)) if *o == outpoint));
+            }
+            assert!(!tinycash.chain_state().await.utxos_set.contains_key(&outpoint));
+        });
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_transaction_spending_output_twice_is_rejected() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let outpoint = mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+
+    let mut transaction = build_transaction_spending(outpoint, Amount::try_from(100).unwrap());
+    if let Transaction::V5 { inputs, .. } = &mut transaction {
+        inputs.push(inputs[0].clone());
+    }
+
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .expect_err("double spend within a transaction should be rejected");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::DuplicateSpend(o)] if *o == outpoint
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_transparent_burn() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+
+    let memo = crate::burn_memo::BurnMemo::new(1, [0xab; 20]).encode();
+    let mut transaction = build_transaction_spending(outpoint, amount);
+    if let Transaction::V5 { outputs, .. } = &mut transaction {
+        outputs[0].lock_script = crate::mt_doom_script(&memo);
+    }
+    let burn = transparent::OutPoint {
+        hash: transaction.hash(),
+        index: 0,
+    };
+
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(response.burns, vec![(amount, memo)]);
+    // the burned output can never be spent
+    assert!(!tinycash.chain_state().await.utxos_set.contains_key(&burn));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_fees_are_enforced_and_collected() {
+    let mut tinycash = TinyCash::with_config(Config {
+        fee_policy: FeePolicy::Flat(Amount::try_from(10).unwrap()),
+        fee_recipient: Some(rejecting()),
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let first = mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+    let second = mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+
+    // creates more value than it spends
+    let inflating = build_transaction_spending(first, Amount::try_from(101).unwrap());
+    // pays less than the minimum fee
+    let cheap = build_transaction_spending(first, Amount::try_from(95).unwrap());
+    for transaction in [inflating, cheap] {
+        let err = tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::IncludeTransaction {
+                transaction,
+                context: BlockContext::default(),
+            })
+            .await
+            .expect_err("transaction should be rejected");
+        assert!(matches!(
+            rejection_reasons(err).as_slice(),
+            [Error::InsufficientFee { .. }]
+        ));
+    }
+
+    // fees of 10 and 20
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransactions(
+            vec![
+                build_transaction_spending(first, Amount::try_from(90).unwrap()),
+                build_transaction_spending(second, Amount::try_from(80).unwrap()),
+            ],
+            BlockContext::default(),
+        ))
+        .await
+        .unwrap();
+    assert!(response.transaction_results.iter().all(|(_, r)| r.is_ok()));
+
+    let coinbase = &response.block.block.transactions[0];
+    assert_eq!(coinbase.outputs().len(), 1);
+    assert_eq!(coinbase.outputs()[0].value, Amount::try_from(30).unwrap());
+    assert_eq!(coinbase.outputs()[0].lock_script, rejecting());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_transaction_overflowing_the_block_fees_is_rejected_alone() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let max = Amount::try_from(MAX_MONEY).unwrap();
+    let first = mint_spendable(&mut tinycash, max).await;
+    let second = mint_spendable(&mut tinycash, max).await;
+    let third = mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+
+    // each of the first two pays everything it spends as a fee, together more than can exist
+    let transactions = vec![
+        build_transaction_spending(first, Amount::zero()),
+        build_transaction_spending(second, Amount::zero()),
+        build_transaction_spending(third, Amount::try_from(100).unwrap()),
+    ];
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransactions(
+            transactions,
+            BlockContext::default(),
+        ))
+        .await
+        .unwrap();
+
+    let results: Vec<_> = response
+        .transaction_results
+        .iter()
+        .map(|(_, result)| result)
+        .collect();
+    assert!(matches!(
+        results.as_slice(),
+        [Ok(()), Err(Error::InvalidTransaction(_)), Ok(())]
+    ));
+    assert_eq!(response.block.block.transactions.len(), 3);
+}
+
+#[test]
+fn test_transaction_fee_with_extreme_values() {
+    let max = Amount::<NonNegative>::try_from(MAX_MONEY).unwrap();
+    let outpoint = transparent::OutPoint {
+        hash: transaction::Hash([0; 32]),
+        index: 0,
+    };
+    let spent = |value| transparent::Output {
+        value,
+        lock_script: accepting(),
+    };
+    let out_of_range =
+        |result: Result<_, Error>| matches!(result, Err(Error::InvalidTransaction(_)));
+
+    // spending and recreating the whole supply pays no fee
+    let tx = build_transaction_spending(outpoint, max);
+    assert_eq!(transaction_fee(&tx, &[spent(max)]).unwrap(), Amount::zero());
+    // creating the whole supply from nothing is the most negative fee possible
+    assert_eq!(i64::from(transaction_fee(&tx, &[]).unwrap()), -MAX_MONEY);
+
+    // spent or created outputs summing past the maximum supply are rejected rather than wrapping
+    let mut two_outputs = tx.clone();
+    if let Transaction::V5 { outputs, .. } = &mut two_outputs {
+        outputs.push(spent(max));
+    }
+    assert!(out_of_range(transaction_fee(&two_outputs, &[spent(max)])));
+    assert!(out_of_range(transaction_fee(
+        &tx,
+        &[spent(max), spent(max)]
+    )));
+    assert!(matches!(
+        FeePolicy::None.check(&tx, &[spent(max), spent(max)]),
+        Err(Error::InvalidTransaction(_))
+    ));
+
+    // shielding the whole supply moves it into the pool without paying a fee
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let to = FullViewingKey::from(&SpendingKey::from_bytes([7; 32]).unwrap())
+        .address_at(0_usize, Scope::External);
+    let mut shielding = build_shielding_transaction(
+        outpoint,
+        max,
+        to,
+        zebra_chain::orchard::tree::NoteCommitmentTree::default().root(),
+        &ChainSpec::default(),
+        &mut rng,
+    );
+    assert_eq!(
+        transaction_fee(&shielding, &[spent(max)]).unwrap(),
+        Amount::zero()
+    );
+    // a value balance taking the whole supply out of the pool on top of the spent outputs overflows
+    if let Transaction::V5 {
+        orchard_shielded_data: Some(data),
+        ..
+    } = &mut shielding
+    {
+        data.value_balance = max.constrain().unwrap();
+    }
+    assert!(out_of_range(transaction_fee(&shielding, &[spent(max)])));
+    assert_eq!(
+        i64::from(transaction_fee(&shielding, &[]).unwrap()),
+        MAX_MONEY
+    );
+}
+
+#[test]
+fn test_fee_policy() {
+    let tx = build_transaction_spending(
+        transparent::OutPoint {
+            hash: transaction::Hash([0; 32]),
+            index: 0,
+        },
+        Amount::try_from(100).unwrap(),
+    );
+
+    assert_eq!(FeePolicy::None.minimum_fee(&tx), Amount::zero());
+    // a single input and output is within the grace actions
+    assert_eq!(
+        FeePolicy::zip317().minimum_fee(&tx),
+        Amount::try_from(2 * ZIP317_MARGINAL_FEE).unwrap()
+    );
+
+    assert_eq!("none".parse(), Ok(FeePolicy::None));
+    assert_eq!("zip317".parse(), Ok(FeePolicy::zip317()));
+    assert_eq!(
+        "flat:1000".parse(),
+        Ok(FeePolicy::Flat(Amount::try_from(1000).unwrap()))
+    );
+    assert!("flat:-1".parse::<FeePolicy>().is_err());
+    assert!("free".parse::<FeePolicy>().is_err());
+}
+
+#[test]
+fn test_halo2_verifying_key_digest_is_reproducible() {
+    // a key built independently of the one zebra-consensus verifies with has the same digest
+    let digest = crate::halo2_verifying_key_digest();
+    assert_eq!(
+        digest,
+        crate::verifying_key_digest(&orchard::circuit::VerifyingKey::build())
+    );
+    assert_eq!(digest, crate::halo2_verifying_key_digest());
+}
+
+#[test]
+fn test_mt_doom_key() {
+    use orchard::keys::{FullViewingKey, SpendingKey};
+
+    let fvk = crate::mt_doom_fvk();
+    let bytes = fvk.to_bytes();
+    // ak is encoded with a zero sign bit
+    assert_eq!(bytes[31] & 0x80, 0);
+    assert_eq!(FullViewingKey::from_bytes(&bytes), Some(fvk.clone()));
+
+    // no longer derived from a spending key anyone can reproduce
+    let zero_key = FullViewingKey::from(&SpendingKey::from_bytes([0; 32]).unwrap());
+    assert_ne!(fvk, zero_key);
+    assert_ne!(
+        crate::mt_doom_address(),
+        zero_key.address_at(0_usize, orchard::keys::Scope::External)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_shielded_mint() {
+    use orchard::keys::{FullViewingKey, Scope, SpendingKey};
+
+    let fvk = FullViewingKey::from(&SpendingKey::from_bytes([7; 32]).unwrap());
+    let to = fvk.address_at(0_usize, Scope::External);
+    let amount = Amount::try_from(1_000).unwrap();
+
+    let mut blocks = Vec::new();
+    for _ in 0..2 {
+        let mut tinycash = TinyCash::new();
+        tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Genesis)
+            .await
+            .unwrap();
+        let before = tinycash.chain_state().await;
+        let response = tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::MintShielded {
+                amount,
+                to,
+                context: BlockContext::default(),
+            })
+            .await
+            .unwrap();
+
+        // the coinbase creates a note the recipient can decrypt
+        let coinbase = &response.block.block.transactions[0];
+        assert!(coinbase.is_coinbase());
+        let shielded_data = coinbase.orchard_shielded_data().unwrap();
+        assert_eq!(i64::from(shielded_data.value_balance), -1_000);
+        let (note, _) = coinbase
+            .orchard_actions()
+            .find_map(|action| crate::decrypt_note(action, &fvk.to_ivk(Scope::External)))
+            .expect("recipient can decrypt the minted note");
+        assert_eq!(note.value().inner(), 1_000);
+        assert_eq!(note.recipient(), to);
+
+        // the new commitment tree root can be used as an anchor
+        let after = tinycash.chain_state().await;
+        assert_ne!(
+            after.commitment_tree_frontier.root(),
+            before.commitment_tree_frontier.root()
+        );
+        assert!(after
+            .historical_tree_roots
+            .check(&after.commitment_tree_frontier.root(), Height(1))
+            .is_ok());
+        blocks.push(response.block.hash);
+    }
+
+    // every node builds the same block for the same deposit
+    assert_eq!(blocks[0], blocks[1]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_block_context() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let context = BlockContext {
+        time: DateTime::from_timestamp(1710913093, 0).unwrap(),
+        l1_block_number: 11,
+    };
+    let block = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount: Amount::try_from(100).unwrap(),
+            to: accepting(),
+            context,
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+    assert_eq!(block.header.time, context.time);
+    assert!(matches!(
+        &block.transactions[0].inputs()[0],
+        transparent::Input::Coinbase { data, .. } if data.as_ref()[4..] == 11u64.to_le_bytes()
+    ));
+
+    let outpoint = transparent::OutPoint {
+        hash: block.transactions[0].hash(),
+        index: 0,
+    };
+    let spend_with = |lock: LockTime, expiry: Height| {
+        let mut tx = build_transaction_spending(outpoint, Amount::try_from(100).unwrap());
+        if let Transaction::V5 {
+            lock_time,
+            expiry_height,
+            ..
+        } = &mut tx
+        {
+            *lock_time = lock;
+            *expiry_height = expiry;
+        }
+        tx
+    };
+
+    // the next block is at height 2, one second after the last
+    let next = BlockContext {
+        time: context.time + chrono::Duration::seconds(1),
+        l1_block_number: 12,
+    };
+    let rejected = [
+        spend_with(LockTime::Height(Height(2)), Height(0)),
+        spend_with(LockTime::Time(next.time), Height(0)),
+        spend_with(LockTime::unlocked(), Height(1)),
+    ];
+    for transaction in rejected {
+        let err = tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::IncludeTransaction {
+                transaction,
+                context: next,
+            })
+            .await
+            .expect_err("transaction should be rejected");
+        assert!(matches!(
+            rejection_reasons(err).as_slice(),
+            [Error::LockTimeNotReached(_) | Error::Expired { .. }]
+        ));
+    }
+
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend_with(LockTime::Time(context.time), Height(2)),
+            context: next,
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.block.height, Height(2));
+    assert_eq!(response.block.block.header.time, next.time);
+}
+
+#[test]
+fn test_chain_spec() {
+    let eth = ChainSpec::new("eth.1");
+    let testnet = ChainSpec::new("testnet.eth.421614");
+
+    assert_eq!(eth, ChainSpec::new("eth.1"));
+    assert_ne!(eth.instance_id(), testnet.instance_id());
+    assert_eq!(eth.instance_id().to_string().len(), 8);
+
+    // the address encodings follow the chain name
+    assert!(!eth.is_testnet());
+    assert_eq!(eth.network(), Network::Mainnet);
+    assert!(testnet.is_testnet());
+    assert_eq!(testnet.network(), Network::Testnet);
+    assert_eq!(ChainSpec::default().network(), Network::Testnet);
+
+    // every instance follows NU5 but has its own branch id for signatures
+    let nu5 = u32::from(NetworkUpgrade::Nu5.branch_id().unwrap());
+    assert_eq!(eth.network_upgrade(), NetworkUpgrade::Nu5);
+    assert_ne!(eth.consensus_branch_id(), testnet.consensus_branch_id());
+    assert_ne!(eth.consensus_branch_id().0, nu5);
+    assert_eq!(eth.consensus_branch_id().to_string(), "eefcecb0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_shielded_sighash_is_zip244_sighash() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(1_000).unwrap();
+    let coinbase = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Mint {
+            amount,
+            to: accepting(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block
+        .transactions[0]
+        .clone();
+    let outpoint = transparent::OutPoint {
+        hash: coinbase.hash(),
+        index: 0,
+    };
+    let anchor = tinycash.chain_state().await.commitment_tree_frontier.root();
+    let to = FullViewingKey::from(&SpendingKey::from_bytes([8; 32]).unwrap())
+        .address_at(0_usize, Scope::External);
+    let shielding = build_shielding_transaction(
+        outpoint,
+        amount,
+        to,
+        anchor,
+        &ChainSpec::default(),
+        &mut ChaCha20Rng::seed_from_u64(0),
+    );
+    let mut shielded_only = shielding.clone();
+    if let Transaction::V5 { inputs, .. } = &mut shielded_only {
+        inputs.clear();
+    }
+    let spent = [transparent::Output {
+        value: amount,
+        lock_script: accepting(),
+    }];
+
+    // with the NU5 branch id it is the sighash zebra computes for NU5
+    let nu5 = crate::chain_spec::ConsensusBranchId(NetworkUpgrade::Nu5.branch_id().unwrap().into());
+    for (transaction, previous_outputs) in [
+        (coinbase.as_ref(), &[][..]),
+        (&shielding, &spent[..]),
+        (&shielded_only, &[][..]),
+    ] {
+        assert_eq!(
+            crate::sighash::shielded_sighash(transaction, nu5, previous_outputs),
+            transaction.sighash(
+                NetworkUpgrade::Nu5,
+                transaction::HashType::ALL,
+                previous_outputs,
+                None
+            )
+        );
+    }
+
+    // and every instance has its own
+    assert_ne!(
+        ChainSpec::new("eth.1").shielded_sighash(&shielding, &spent),
+        ChainSpec::new("dai.1").shielded_sighash(&shielding, &spent)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_shielded_signatures_are_instance_specific() {
+    let chain_spec = ChainSpec::new("eth.1");
+    let mut tinycash = TinyCash::with_config(Config {
+        chain_spec: chain_spec.clone(),
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(1_000).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+    let anchor = tinycash.chain_state().await.commitment_tree_frontier.root();
+    let to = FullViewingKey::from(&SpendingKey::from_bytes([8; 32]).unwrap())
+        .address_at(0_usize, Scope::External);
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+    // signed for another instance
+    let transaction = build_shielding_transaction(
+        outpoint,
+        amount,
+        to,
+        anchor,
+        &ChainSpec::new("dai.1"),
+        &mut rng,
+    );
+    assert!(matches!(
+        rejected_without_state_change(&mut tinycash, transaction, BlockContext::default())
+            .await
+            .as_slice(),
+        [Error::InvalidTransaction(_)]
+    ));
+
+    let transaction =
+        build_shielding_transaction(outpoint, amount, to, anchor, &chain_spec, &mut rng);
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    assert!(response.transaction_results[0].1.is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_mint_and_spend_on_testnet() {
+    let chain_spec = ChainSpec::new("testnet.eth.421614");
+    let mut tinycash = TinyCash::with_config(Config {
+        chain_spec: chain_spec.clone(),
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let mut commitments = NoteCommitments::default();
+    let sk = SpendingKey::from_bytes([7; 32]).unwrap();
+    let to = FullViewingKey::from(&SpendingKey::from_bytes([8; 32]).unwrap())
+        .address_at(0_usize, Scope::External);
+    let amount = Amount::try_from(1_000).unwrap();
+
+    // a minted output is moved into the shielded pool and the note spent, all signed for the instance
+    let note = shielded_note(
+        &mut tinycash,
+        &mut commitments,
+        &sk,
+        amount,
+        &chain_spec,
+        &mut rng,
+    )
+    .await;
+    let spend = build_shielded_spend(
+        &sk,
+        &[(note, commitments.path(&note))],
+        to,
+        &chain_spec,
+        &mut rng,
+    );
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    assert!(response.transaction_results[0].1.is_ok());
+    assert_eq!(response.block.height, Height(3));
+
+    // transactions made for another network upgrade are rejected
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+    let mut transaction = build_transaction_spending(outpoint, amount);
+    if let Transaction::V5 {
+        network_upgrade, ..
+    } = &mut transaction
+    {
+        *network_upgrade = NetworkUpgrade::Canopy;
+    }
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .expect_err("transaction for another network upgrade should be rejected");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::InvalidTransaction(_)]
+    ));
+}
+
+#[test]
+fn test_genesis_is_instance_specific() {
+    let config = Config {
+        dapp_address: Some([0xab; 20]),
+        ..Default::default()
+    };
+    let genesis = genesis_block(&config);
+
+    // deterministic and recognised as a genesis block
+    assert_eq!(genesis.hash(), genesis_block(&config).hash());
+    assert_eq!(genesis.coinbase_height(), Some(Height(0)));
+    let bytes = genesis.zcash_serialize_to_vec().unwrap();
+    assert_eq!(
+        block::Block::zcash_deserialize(bytes.as_slice())
+            .unwrap()
+            .hash(),
+        genesis.hash()
+    );
+
+    // changing any part of the instance changes the genesis hash
+    let others = [
+        Config {
+            chain_spec: ChainSpec::new("eth.1"),
+            ..config.clone()
+        },
+        Config {
+            dapp_address: Some([0xcd; 20]),
+            ..config.clone()
+        },
+        Config {
+            dapp_address: None,
+            ..config.clone()
+        },
+        Config {
+            fee_policy: FeePolicy::zip317(),
+            ..config.clone()
+        },
+        Config {
+            nullifier_accumulator: NullifierAccumulatorKind::SparseMerkle,
+            ..config.clone()
+        },
+    ];
+    let mut hashes: HashSet<_> = others.iter().map(|c| genesis_block(c).hash()).collect();
+    hashes.insert(genesis.hash());
+    assert_eq!(hashes.len(), others.len() + 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_transparent_spends_are_instance_specific() {
+    let mut instances = Vec::new();
+    for chain_name in ["eth.1", "dai.1"] {
+        let mut tinycash = TinyCash::with_config(Config {
+            chain_spec: ChainSpec::new(chain_name),
+            ..Default::default()
+        });
+        tinycash
+            .ready()
+            .await
+            .unwrap()
+            .call(Request::Genesis)
+            .await
+            .unwrap();
+        let outpoint = mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+        instances.push((tinycash, outpoint));
+    }
+
+    // the same mint produces different outputs on each instance
+    assert_ne!(instances[0].1, instances[1].1);
+
+    // so a spend made for one instance can't be replayed on the other
+    let spend = build_transaction_spending(instances[0].1, Amount::try_from(100).unwrap());
+    let err = instances[1]
+        .0
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend.clone(),
+            context: BlockContext::default(),
+        })
+        .await
+        .expect_err("spend should be rejected on another instance");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::UnknownOutPoint(_)]
+    ));
+    instances[0]
+        .0
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_anchor_window() {
+    let root = |i: u16| {
+        let mut bytes = [0; 32];
+        bytes[..2].copy_from_slice(&i.to_le_bytes());
+        Root::try_from(bytes).unwrap()
+    };
+
+    // blocks without orchard actions don't add a new root
+    let mut anchors = Anchors::new(AnchorWindow::Blocks(5));
+    for height in 0..10 {
+        anchors.push(Height(height), root(0));
+    }
+    assert_eq!(anchors.len(), 1);
+    assert!(anchors.check(&root(0), Height(9)).is_ok());
+    assert!(matches!(
+        anchors.check(&root(1), Height(9)),
+        Err(Error::UnknownAnchor(_))
+    ));
+
+    // root 1 at heights 10..=11, root 2 at 12..=13, root 3 from 14
+    anchors.push(Height(10), root(1));
+    anchors.push(Height(11), root(1));
+    anchors.push(Height(12), root(2));
+    anchors.push(Height(13), root(2));
+    anchors.push(Height(14), root(3));
+    // window is heights 10..=14 so root 0 has been evicted
+    assert_eq!(anchors.len(), 3);
+    assert!(anchors.check(&root(1), Height(14)).is_ok());
+    assert!(matches!(
+        anchors.check(&root(0), Height(14)),
+        Err(Error::AnchorTooOld {
+            anchor_height: Height(0),
+            oldest_anchor_height: Height(10),
+            tip_height: Height(14),
+        })
+    ));
+    // a root that was never in the window is unknown rather than too old
+    assert!(matches!(
+        anchors.check(&root(9), Height(14)),
+        Err(Error::UnknownAnchor(r)) if r == root(9)
+    ));
+
+    // window is heights 12..=16
+    anchors.push(Height(15), root(3));
+    anchors.push(Height(16), root(3));
+    assert_eq!(anchors.len(), 2);
+    assert!(anchors.check(&root(2), Height(16)).is_ok());
+    assert!(matches!(
+        anchors.check(&root(1), Height(16)),
+        Err(Error::AnchorTooOld {
+            anchor_height: Height(10),
+            oldest_anchor_height: Height(12),
+            ..
+        })
+    ));
+
+    // only the most recent distinct roots are kept regardless of how long ago they were seen
+    let mut anchors = Anchors::new(AnchorWindow::Roots(2));
+    anchors.push(Height(0), root(0));
+    anchors.push(Height(1), root(1));
+    for height in 2..100 {
+        anchors.push(Height(height), root(2));
+    }
+    assert_eq!(anchors.len(), 2);
+    assert!(anchors.check(&root(1), Height(99)).is_ok());
+    assert!(matches!(
+        anchors.check(&root(0), Height(99)),
+        Err(Error::AnchorTooOld {
+            anchor_height: Height(0),
+            oldest_anchor_height: Height(1),
+            tip_height: Height(99),
+        })
+    ));
+    assert!(matches!(
+        anchors.check(&root(9), Height(99)),
+        Err(Error::UnknownAnchor(_))
+    ));
+
+    // undoing a push restores the evicted roots
+    let before = anchors.clone();
+    let undo = anchors.push(Height(100), root(3));
+    assert!(matches!(
+        anchors.check(&root(1), Height(100)),
+        Err(Error::AnchorTooOld { .. })
+    ));
+    anchors.undo(undo);
+    assert_eq!(anchors, before);
+    assert!(anchors.check(&root(1), Height(100)).is_ok());
+
+    // only the most recently evicted roots are remembered, older ones are unknown
+    let mut anchors = Anchors::new(AnchorWindow::Roots(1));
+    let pushes = EVICTED_CAPACITY as u16 + 2;
+    for i in 0..pushes {
+        anchors.push(Height(i.into()), root(i));
+    }
+    assert_eq!(anchors.evicted().count(), EVICTED_CAPACITY);
+    assert!(matches!(
+        anchors.check(&root(0), Height(pushes.into())),
+        Err(Error::UnknownAnchor(_))
+    ));
+    assert!(matches!(
+        anchors.check(&root(1), Height(pushes.into())),
+        Err(Error::AnchorTooOld {
+            anchor_height: Height(1),
+            ..
+        })
+    ));
+
+    // undoing a push also restores the evicted root it made room for
+    let before = anchors.clone();
+    let undo = anchors.push(Height(pushes.into()), root(pushes));
+    assert!(matches!(
+        anchors.check(&root(1), Height(pushes.into())),
+        Err(Error::UnknownAnchor(_))
+    ));
+    anchors.undo(undo);
+    assert_eq!(anchors, before);
+}
+
+#[test]
+fn test_burn_memo() {
+    use crate::burn_memo::{decode, BurnMemo, BurnMemoError, DecodedBurnMemo};
+    use zebra_chain::transaction::Memo;
+
+    let recipient = [0xab; 20];
+    let memo = BurnMemo::new(1, recipient)
+        .with_withdrawal_type(0)
+        .with_token([0xcd; 20]);
+    let encoded = memo.encode();
+    assert_eq!(decode(&encoded), Ok(DecodedBurnMemo::Binary(memo)));
+
+    // a typo in the recipient is caught by the checksum
+    let mut typo = encoded.clone();
+    typo.0[12] ^= 1;
+    assert_eq!(decode(&typo), Err(BurnMemoError::BadChecksum));
+
+    let mut unknown_version = encoded.clone();
+    unknown_version.0[1] = 2;
+    assert_eq!(
+        decode(&unknown_version),
+        Err(BurnMemoError::UnsupportedVersion(2))
+    );
+
+    let mut trailing_data = encoded;
+    trailing_data.0[511] = 1;
+    assert_eq!(decode(&trailing_data), Err(BurnMemoError::Malformed));
+
+    // the legacy format is still accepted
+    let mut legacy = [0; 512];
+    legacy[..40].copy_from_slice(hex::encode(recipient).as_bytes());
+    assert_eq!(
+        decode(&Memo(Box::new(legacy))),
+        Ok(DecodedBurnMemo::LegacyHex { recipient })
+    );
+    let mut invalid_legacy = legacy;
+    invalid_legacy[0] = b'z';
+    assert_eq!(
+        decode(&Memo(Box::new(invalid_legacy))),
+        Err(BurnMemoError::InvalidLegacyHex)
+    );
+}
+
+#[test]
+fn test_input_envelope() {
+    use crate::envelope::{
+        decode, encode_batch, encode_compressed_batch, encode_transaction, AdminCommand, Envelope,
+        EnvelopeError,
+    };
+
+    let outpoint = transparent::OutPoint {
+        hash: transaction::Hash([1; 32]),
+        index: 0,
+    };
+    let tx = build_transaction_spending(outpoint, Amount::try_from(100).unwrap());
+    let raw = tx.zcash_serialize_to_vec().unwrap();
+    let sender = [1; 20];
+
+    assert!(matches!(
+        decode(&encode_transaction(&raw), sender, None),
+        Ok(Envelope::Transaction(t)) if t == tx
+    ));
+    assert!(matches!(
+        decode(&encode_batch([&raw[..], &raw[..]]), sender, None),
+        Ok(Envelope::Batch(txs)) if txs == vec![tx.clone(), tx.clone()]
+    ));
+    assert!(matches!(
+        decode(&encode_compressed_batch([&raw[..], &raw[..]]).unwrap(), sender, None),
+        Ok(Envelope::Batch(txs)) if txs == vec![tx.clone(), tx.clone()]
+    ));
+    // raw transactions are still accepted during the migration
+    assert!(matches!(
+        decode(&raw, sender, None),
+        Ok(Envelope::Transaction(t)) if t == tx
+    ));
+
+    assert!(matches!(
+        decode(
+            &[&encode_transaction(&raw)[..], &[0]].concat(),
+            sender,
+            None
+        ),
+        Err(EnvelopeError::TrailingData(1))
+    ));
+    assert!(matches!(
+        decode(&encode_transaction(&raw[..10]), sender, None),
+        Err(EnvelopeError::InvalidTransaction(_))
+    ));
+    assert!(matches!(
+        decode(&encode_batch([]), sender, None),
+        Err(EnvelopeError::EmptyBatch)
+    ));
+    assert!(matches!(
+        decode(&[1, 0x02, 0xde, 0xad], sender, None),
+        Err(EnvelopeError::InvalidCompressedBatch(_))
+    ));
+    let truncated = zstd::encode_all(&[0, 0, 1, 0, 5][..], 0).unwrap();
+    assert!(matches!(
+        decode(&[&[1, 0x02][..], &truncated].concat(), sender, None),
+        Err(EnvelopeError::TruncatedTransaction)
+    ));
+    assert!(matches!(
+        decode(&[1, 0x7f], sender, None),
+        Err(EnvelopeError::UnknownType(0x7f))
+    ));
+    assert!(matches!(
+        decode(&[2, 0], sender, None),
+        Err(EnvelopeError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+        decode(&[], sender, None),
+        Err(EnvelopeError::Empty)
+    ));
+
+    // admin commands are only accepted from the admin
+    let set_cap = [&[1, 0x03, 0x00, 1][..], &1000u64.to_be_bytes()].concat();
+    assert!(matches!(
+        decode(&set_cap, sender, Some([2; 20])),
+        Err(EnvelopeError::UnauthorizedAdmin(s)) if s == sender
+    ));
+    assert!(matches!(
+        decode(&set_cap, sender, Some(sender)),
+        Ok(Envelope::Admin(AdminCommand::SetDepositCap(Some(cap))))
+            if cap == Amount::try_from(1000).unwrap()
+    ));
+    assert!(matches!(
+        decode(&[1, 0x03, 0x00, 0], sender, Some(sender)),
+        Ok(Envelope::Admin(AdminCommand::SetDepositCap(None)))
+    ));
+    assert!(matches!(
+        decode(&[1, 0x03, 0x00, 1, 0], sender, Some(sender)),
+        Err(EnvelopeError::InvalidAdminCommand)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_rollback() {
+    let mut tinycash = TinyCash::with_config(Config {
+        rollback_depth: 3,
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+    let at_height_1 = tinycash.chain_state().await;
+    let snapshot = tinycash.snapshot().await;
+
+    let spend = build_transaction_spending(outpoint, amount);
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend.clone(),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    mint_spendable(&mut tinycash, amount).await;
+
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(4)))
+        .await
+        .expect_err("cannot roll forward");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::RollbackAboveTip(Height(4))]
+    ));
+
+    // rolling back restores the state and returns the new tip
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(1)))
+        .await
+        .unwrap();
+    assert_eq!(response.block.height, Height(1));
+    assert_eq!(Some(response.block.hash), at_height_1.tip_hash);
+    assert_eq!(response.state_root, at_height_1.state_root());
+    assert_state_unchanged(&at_height_1, &tinycash.chain_state().await);
+    assert_eq!(tinycash.snapshot().await, snapshot);
+
+    // so the same spend can be included again
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: spend,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction: build_transaction_spending(outpoint, amount),
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    mint_spendable(&mut tinycash, amount).await;
+
+    // only the last `rollback_depth` blocks can be undone
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(1)))
+        .await
+        .expect_err("undo records should have been dropped");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::RollbackUnavailable(Height(1))]
+    ));
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(2)))
+        .await
+        .unwrap();
+    // three blocks were undone
+    assert_eq!(response.block.height, Height(2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_rollback_is_opt_in() {
+    let mut tinycash = TinyCash::new();
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    mint_spendable(&mut tinycash, Amount::try_from(100).unwrap()).await;
+
+    // no undo records are kept by default
+    let err = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(0)))
+        .await
+        .expect_err("rollback should be unavailable");
+    assert!(matches!(
+        rejection_reasons(err).as_slice(),
+        [Error::RollbackUnavailable(Height(0))]
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[tracing_test::traced_test]
+async fn test_rollback_returns_spendable_outputs() {
+    let mut tinycash = TinyCash::with_config(Config {
+        rollback_depth: 3,
+        ..Default::default()
+    });
+    let genesis = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+    let amount = Amount::try_from(100).unwrap();
+    let outpoint = mint_spendable(&mut tinycash, amount).await;
+
+    // a block with a burn so one of its outputs is not spendable
+    let memo = crate::burn_memo::BurnMemo::new(1, [0xab; 20]).encode();
+    let mut transaction = build_transaction_spending(outpoint, amount);
+    if let Transaction::V5 { outputs, .. } = &mut transaction {
+        outputs[0].lock_script = crate::mt_doom_script(&memo);
+    }
+    let burned = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap();
+    mint_spendable(&mut tinycash, amount).await;
+
+    // the tip returned by a rollback has the same outputs as when it was committed
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(2)))
+        .await
+        .unwrap();
+    assert_eq!(response.block.new_outputs, burned.block.new_outputs);
+
+    // genesis outputs are never spendable
+    let response = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::RollbackTo(Height(0)))
+        .await
+        .unwrap();
+    assert!(response.block.new_outputs.is_empty());
+    assert_eq!(response.block.new_outputs, genesis.block.new_outputs);
+}
+
+fn assert_state_unchanged(before: &ChainState, after: &ChainState) {
+    assert_eq!(before.state_root(), after.state_root());
+    assert_eq!(before.tip_height, after.tip_height);
+    assert_eq!(before.tip_hash, after.tip_hash);
+    assert_eq!(before.utxos_set, after.utxos_set);
+    assert_eq!(
+        before.nullifier_set.nullifiers(),
+        after.nullifier_set.nullifiers()
+    );
+    assert_eq!(before.nullifier_set.root(), after.nullifier_set.root());
+    assert_eq!(
+        before
+            .historical_tree_roots
+            .iter()
+            .map(|(height, root)| (*height, <[u8; 32]>::from(*root)))
+            .collect::<Vec<_>>(),
+        after
+            .historical_tree_roots
+            .iter()
+            .map(|(height, root)| (*height, <[u8; 32]>::from(*root)))
+            .collect::<Vec<_>>(),
+    );
+    assert_eq!(
+        <[u8; 32]>::from(before.commitment_tree_frontier.root()),
+        <[u8; 32]>::from(after.commitment_tree_frontier.root()),
+    );
+}
+
+// combines tiny-cash with a zebra state service.
+// Adds the block to the state service after it has been verified and added to tiny-cash
+async fn exeucte_and_commit_block<TC, S>(
+    request: Request,
+    tinycash: &mut TC,
+    state_service: &mut S,
+) -> Result<std::sync::Arc<block::Block>, tower::BoxError>
+where
+    TC: Service<Request, Response = Response, Error = BoxError> + Send + Clone + 'static,
+    TC::Future: Send + 'static,
+    S: Service<zebra_state::Request, Response = zebra_state::Response, Error = BoxError>
+        + Send
+        + Clone
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let response = tinycash.ready().await?.call(request).await?;
+
+    if response.block.height == Height(0) {
+        // genesis block needs to be committed as a checkpoint
+        state_service
+            .ready()
+            .await?
+            .call(zebra_state::Request::CommitCheckpointVerifiedBlock(
+                zebra_state::CheckpointVerifiedBlock::from(response.block.clone().block),
+            ))
+            .await?;
+    } else {
+        state_service
+            .ready()
+            .await?
+            .call(zebra_state::Request::CommitSemanticallyVerifiedBlock(
+                response.block.clone(),
+            ))
+            .await?;
+    }
+    Ok(response.block.block)
+}
+
+/// Given a `previous_outpoint` build a new transaction that should pass
+fn build_transaction_spending(
+    previous_outpoint: transparent::OutPoint, // specifies how to find the UTXOs to spend
+    amount: Amount<NonNegative>,
+    // script_should_succeed: bool,
+) -> Transaction {
+    // A script with a single opcode that accepts the transaction (pushes true on the stack)
+    let accepting_script = transparent::Script::new(&[1, 1]);
+    // A script with a single opcode that rejects the transaction (OP_FALSE)
+    // let rejecting_script = transparent::Script::new(&[0]);
+
+    // Use the `previous_outpoint` as input
+    let input = transparent::Input::PrevOut {
+        outpoint: previous_outpoint,
+        unlock_script: accepting_script.clone(),
+        sequence: 0,
+    };
+
+    let output = transparent::Output {
+        value: amount,
+        lock_script: accepting_script,
+    };
+
+    Transaction::V5 {
+        inputs: vec![input],
+        outputs: vec![output],
+        lock_time: LockTime::Height(Height(0)),
+        expiry_height: Height(0),
+        sapling_shielded_data: None,
+        orchard_shielded_data: None,
+        network_upgrade: NetworkUpgrade::Nu5,
+    }
+}
+
+// A chain on the default chain spec with a spendable note of 1000 zatoshis for a test key. It keeps undo records.
+// Returns the chain, its note commitments, the key, the note and an RNG for building more transactions
+async fn chain_with_shielded_note() -> (
+    TinyCash,
+    NoteCommitments,
+    SpendingKey,
+    orchard::Note,
+    ChaCha20Rng,
+) {
+    let mut tinycash = TinyCash::with_config(Config {
+        rollback_depth: DEFAULT_ROLLBACK_DEPTH,
+        ..Default::default()
+    });
+    tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::Genesis)
+        .await
+        .unwrap();
+
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let mut commitments = NoteCommitments::default();
+    let sk = SpendingKey::from_bytes([7; 32]).unwrap();
+    let note = shielded_note(
+        &mut tinycash,
+        &mut commitments,
+        &sk,
+        Amount::try_from(1_000).unwrap(),
+        &ChainSpec::default(),
+        &mut rng,
+    )
+    .await;
+    (tinycash, commitments, sk, note, rng)
+}
+
+// Every Orchard note commitment added to a test chain, in tree order, so notes in it can be spent
+#[derive(Default)]
+struct NoteCommitments(Vec<MerkleHashOrchard>);
+
+impl NoteCommitments {
+    fn append(&mut self, block: &block::Block) {
+        self.0.extend(
+            block
+                .orchard_note_commitments()
+                .map(|cm_x| MerkleHashOrchard::from_bytes(&cm_x.to_repr()).unwrap()),
+        );
+    }
+
+    // the Merkle path from the commitment of the note to the current root
+    fn path(&self, note: &orchard::Note) -> MerklePath {
+        let leaf = MerkleHashOrchard::from_cmx(&ExtractedNoteCommitment::from(note.commitment()));
+        let position = self
+            .0
+            .iter()
+            .position(|cm| cm.to_bytes() == leaf.to_bytes())
+            .expect("note is in the tree");
+
+        let mut layer = self.0.clone();
+        let mut auth_path = Vec::new();
+        for level in 0..32u8 {
+            let empty = MerkleHashOrchard::empty_root(Level::from(level));
+            auth_path.push(layer.get((position >> level) ^ 1).copied().unwrap_or(empty));
+            layer = layer
+                .chunks(2)
+                .map(|pair| {
+                    MerkleHashOrchard::combine(
+                        Level::from(level),
+                        &pair[0],
+                        pair.get(1).unwrap_or(&empty),
+                    )
+                })
+                .collect();
+        }
+        MerklePath::from_parts(position as u32, auth_path.try_into().unwrap())
+    }
+}
+
+// Build a transaction moving a transparent output paying an accepting script into a new note for `to`
+fn build_shielding_transaction(
+    outpoint: transparent::OutPoint,
+    amount: Amount<NonNegative>,
+    to: orchard::Address,
+    anchor: Root,
+    chain_spec: &ChainSpec,
+    rng: &mut ChaCha20Rng,
+) -> Transaction {
+    let mut builder = Builder::new(
+        BundleType::DEFAULT,
+        Anchor::from_bytes(anchor.into()).unwrap(),
+    );
+    builder
+        .add_output(
+            None,
+            to,
+            NoteValue::from_raw(amount.zatoshis() as u64),
+            [0; 512],
+        )
+        .unwrap();
+    let (bundle, _) = builder.build::<i64>(&mut *rng).unwrap().unwrap();
+
+    let mut transaction = build_transaction_spending(outpoint, amount);
+    if let Transaction::V5 { outputs, .. } = &mut transaction {
+        outputs.clear();
+    }
+    authorize_bundle(
+        transaction,
+        bundle,
+        &[],
+        &[transparent::Output {
+            value: amount,
+            lock_script: accepting(),
+        }],
+        chain_spec,
+        rng,
+    )
+    .unwrap()
+}
+
+// Build a transaction spending each of the notes of `sk` (with their Merkle paths) into one new note for `to`.
+// Wallets never spend a note twice but nothing stops a transaction from doing so
+fn build_shielded_spend(
+    sk: &SpendingKey,
+    notes: &[(orchard::Note, MerklePath)],
+    to: orchard::Address,
+    chain_spec: &ChainSpec,
+    rng: &mut ChaCha20Rng,
+) -> Transaction {
+    let fvk = FullViewingKey::from(sk);
+    let (first, first_path) = &notes[0];
+    let mut builder = Builder::new(
+        BundleType::DEFAULT,
+        first_path.root(ExtractedNoteCommitment::from(first.commitment())),
+    );
+    for (note, path) in notes {
+        builder.add_spend(fvk.clone(), *note, path.clone()).unwrap();
+    }
+    builder
+        .add_output(
+            None,
+            to,
+            NoteValue::from_raw(notes.iter().map(|(note, _)| note.value().inner()).sum()),
+            [0; 512],
+        )
+        .unwrap();
+    let (bundle, _) = builder.build::<i64>(&mut *rng).unwrap().unwrap();
+
+    let transaction = Transaction::V5 {
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        lock_time: LockTime::Height(Height(0)),
+        expiry_height: Height(0),
+        sapling_shielded_data: None,
+        orchard_shielded_data: None,
+        network_upgrade: NetworkUpgrade::Nu5,
+    };
+    authorize_bundle(
+        transaction,
+        bundle,
+        &[SpendAuthorizingKey::from(sk)],
+        &[],
+        chain_spec,
+        rng,
+    )
+    .unwrap()
+}
+
+// Mint `amount` and move it into a new note for `sk`, returning the note.
+// The commitments of every block added are tracked so the note can be spent
+async fn shielded_note(
+    tinycash: &mut TinyCash,
+    commitments: &mut NoteCommitments,
+    sk: &SpendingKey,
+    amount: Amount<NonNegative>,
+    chain_spec: &ChainSpec,
+    rng: &mut ChaCha20Rng,
+) -> orchard::Note {
+    let fvk = FullViewingKey::from(sk);
+    let outpoint = mint_spendable(tinycash, amount).await;
+    let anchor = tinycash.chain_state().await.commitment_tree_frontier.root();
+    let transaction = build_shielding_transaction(
+        outpoint,
+        amount,
+        fvk.address_at(0_usize, Scope::External),
+        anchor,
+        chain_spec,
+        rng,
+    );
+
+    let block = tinycash
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::IncludeTransaction {
+            transaction,
+            context: BlockContext::default(),
+        })
+        .await
+        .unwrap()
+        .block
+        .block;
+    commitments.append(&block);
+    block.transactions[1]
+        .orchard_actions()
+        .find_map(|action| crate::decrypt_note(action, &fvk.to_ivk(Scope::External)))
+        .expect("note was created for the key")
+        .0
 }
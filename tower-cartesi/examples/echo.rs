@@ -43,6 +43,9 @@ impl Service<Request> for EchoApp {
             Request::InspectState { payload } => {
                 println!("Received inspect state request {:?}", payload);
             }
+            Request::Rollback { input_index } => {
+                println!("Received rollback to before input {}", input_index);
+            }
         }
         async { Ok(tower_cartesi::Response::empty_accept()) }.boxed()
     }
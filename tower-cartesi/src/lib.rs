@@ -1,14 +1,17 @@
-use inputs_query::CompletionStatus;
 use thiserror::Error;
 use tokio::time::interval;
 use tower_service::Service;
+use tracker::{Action, InputTracker};
 
 mod messages;
 mod request;
 mod response;
+#[cfg(test)]
+mod test;
+mod tracker;
 
 pub use messages::{AdvanceStateMetadata, Output};
-pub use request::Request;
+pub use request::{InputParseError, Request};
 pub use response::Response;
 
 #[derive(Error, Debug)]
@@ -79,17 +82,22 @@ use graphql_client::GraphQLQuery;
 #[graphql(
     schema_path = "graphql/schema.graphql",
     query_path = "graphql/inputs_query.graphql",
-    response_derives = "Debug, PartialEq"
+    response_derives = "Debug, PartialEq, Clone"
 )]
 pub struct InputsQuery;
 
 /// Poll a graphql interface for new inputs
 /// This will NOT work inside the Cartesi machine
-/// It is indended to be used alongside a running machine to receive the same inputs
+/// It is indended to be used alongside a running machine to receive the same inputs.
+///
+/// The most recent `rollback_window` inputs are checked again on every poll. If the node reverted or
+/// re-executed any of them the service receives a [`Request::Rollback`] before they are processed again.
+/// Failing to roll back is fatal as the service would no longer follow the node
 pub async fn listen_graphql<S>(
     service: &mut S,
     host_uri: &str,
     page_size: usize,
+    rollback_window: usize,
     frequency: std::time::Duration,
 ) -> Result<(), Error<S::Error>>
 where
@@ -97,37 +105,40 @@ where
     S::Error: std::fmt::Debug,
 {
     let client = reqwest::Client::new();
-    let mut cursor = None;
+    let mut tracker = InputTracker::new(rollback_window);
 
     let mut interval = interval(frequency);
 
     loop {
-        let request_body = InputsQuery::build_query(inputs_query::Variables {
-            first: page_size as i64,
-            after: cursor.clone(),
-        });
+        let request_body = InputsQuery::build_query(tracker.next_query(page_size));
         let resp = client.post(host_uri).json(&request_body).send().await?;
         let response_body: graphql_client::Response<inputs_query::ResponseData> =
             resp.json().await?;
-        for edge in response_body
-            .data
-            .unwrap()
-            .inputs
-            .edges
-            .into_iter()
-            .filter(|edge| edge.node.status == CompletionStatus::ACCEPTED)
-        {
-            cursor = Some(edge.cursor);
-            match service
-                .call(edge.node.try_into().unwrap())
-                .await
-                .map_err(Error::ServiceError)
-            {
-                Ok(r) => {
-                    tracing::info!("Received response: {:?}", r);
+        for action in tracker.reconcile(response_body.data.unwrap().inputs.edges) {
+            match action {
+                Action::Rollback { input_index } => {
+                    tracing::info!(
+                        "Input {} and later inputs were reverted, rolling back",
+                        input_index
+                    );
+                    service
+                        .call(Request::Rollback { input_index })
+                        .await
+                        .map_err(Error::ServiceError)?;
                 }
-                Err(e) => {
-                    tracing::error!("{:?}", e);
+                Action::Process(input) => {
+                    match service
+                        .call(input.try_into().unwrap())
+                        .await
+                        .map_err(Error::ServiceError)
+                    {
+                        Ok(r) => {
+                            tracing::info!("Received response: {:?}", r);
+                        }
+                        Err(e) => {
+                            tracing::error!("{:?}", e);
+                        }
+                    }
                 }
             }
         }
@@ -10,6 +10,12 @@ pub enum Request {
     InspectState {
         payload: Vec<u8>,
     },
+    /// The input with this index and every input after it were reverted by the node, or are being
+    /// re-executed. The service must restore the state it had before processing that input.
+    /// Only sent by [`crate::listen_graphql`]
+    Rollback {
+        input_index: usize,
+    },
 }
 
 impl TryFrom<RollupRequest> for Request {
@@ -28,8 +34,17 @@ impl TryFrom<RollupRequest> for Request {
     }
 }
 
+/// Errors converting an input returned by the graphql API into a [`Request`]
+#[derive(thiserror::Error, Debug)]
+pub enum InputParseError {
+    #[error("invalid hex ({0})")]
+    Hex(#[from] hex::FromHexError),
+    #[error("invalid integer ({0})")]
+    Integer(#[from] std::num::ParseIntError),
+}
+
 impl TryFrom<crate::inputs_query::InputsQueryInputsEdgesNode> for Request {
-    type Error = hex::FromHexError;
+    type Error = InputParseError;
 
     fn try_from(
         value: crate::inputs_query::InputsQueryInputsEdgesNode,
@@ -41,8 +56,9 @@ impl TryFrom<crate::inputs_query::InputsQueryInputsEdgesNode> for Request {
                 )?),
                 epoch_index: 0, // TODO: not sure what to do here..
                 input_index: value.index as usize,
-                block_number: 0, // TODO: parse the BigInt
-                timestamp: 0,    // TODO: parse the BigInt
+                // the API returns these as decimal strings
+                block_number: value.block_number.parse()?,
+                timestamp: value.timestamp.parse()?,
             },
             payload: hex::decode(value.payload.trim_start_matches("0x"))?,
         })
@@ -0,0 +1,134 @@
+use crate::inputs_query::{CompletionStatus, InputsQueryInputsEdges, InputsQueryInputsEdgesNode};
+use crate::tracker::{Action, InputTracker};
+
+fn input(index: i64, status: CompletionStatus, payload: &str) -> InputsQueryInputsEdges {
+    InputsQueryInputsEdges {
+        cursor: format!("cursor-{}", index),
+        node: InputsQueryInputsEdgesNode {
+            index,
+            status,
+            msg_sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266".to_string(),
+            timestamp: "1710913648".to_string(),
+            payload: payload.to_string(),
+            block_number: "122".to_string(),
+        },
+    }
+}
+
+fn processed(actions: &[Action]) -> Vec<i64> {
+    actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Process(input) => Some(input.index),
+            Action::Rollback { .. } => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_new_inputs_are_processed_once() {
+    use CompletionStatus::*;
+    let mut tracker = InputTracker::new(10);
+    assert_eq!(tracker.next_query(5).first, 5);
+    assert_eq!(tracker.next_query(5).after, None);
+
+    // rejected inputs are skipped and nothing after an unprocessed input is processed yet
+    let actions = tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, REJECTED, "0x01"),
+        input(2, ACCEPTED, "0x02"),
+        input(3, UNPROCESSED, "0x03"),
+        input(4, UNPROCESSED, "0x04"),
+    ]);
+    assert_eq!(processed(&actions), vec![0, 2]);
+    // the inputs seen so far are queried again along with a page of new ones
+    assert_eq!(tracker.next_query(5).first, 8);
+
+    // seeing the same inputs again only processes those that are new
+    let actions = tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, REJECTED, "0x01"),
+        input(2, ACCEPTED, "0x02"),
+        input(3, ACCEPTED, "0x03"),
+        input(4, UNPROCESSED, "0x04"),
+    ]);
+    assert_eq!(
+        actions,
+        vec![Action::Process(input(3, ACCEPTED, "0x03").node)]
+    );
+}
+
+#[test]
+fn test_reverted_inputs_are_rolled_back_and_processed_again() {
+    use CompletionStatus::*;
+    let mut tracker = InputTracker::new(10);
+    tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, ACCEPTED, "0x01"),
+        input(2, ACCEPTED, "0x02"),
+    ]);
+
+    // the base layer reorganised and input 1 was replaced. Inputs 1 and 2 are undone before
+    // the new inputs are processed
+    let actions = tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, ACCEPTED, "0xff"),
+        input(2, REJECTED, "0x02"),
+        input(3, ACCEPTED, "0x03"),
+    ]);
+    assert_eq!(
+        actions,
+        vec![
+            Action::Rollback { input_index: 1 },
+            Action::Process(input(1, ACCEPTED, "0xff").node),
+            Action::Process(input(3, ACCEPTED, "0x03").node),
+        ]
+    );
+
+    // the node is re-executing from input 3 so it is rolled back and processed once the node is done
+    let actions = tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, ACCEPTED, "0xff"),
+        input(2, REJECTED, "0x02"),
+        input(3, UNPROCESSED, "0x03"),
+    ]);
+    assert_eq!(actions, vec![Action::Rollback { input_index: 3 }]);
+    let actions = tracker.reconcile(vec![
+        input(0, ACCEPTED, "0x00"),
+        input(1, ACCEPTED, "0xff"),
+        input(2, REJECTED, "0x02"),
+        input(3, ACCEPTED, "0x03"),
+    ]);
+    assert_eq!(processed(&actions), vec![3]);
+
+    // inputs that disappeared are rolled back too
+    let actions = tracker.reconcile(vec![input(0, ACCEPTED, "0x00")]);
+    assert_eq!(actions, vec![Action::Rollback { input_index: 1 }]);
+
+    // a reverted input that was rejected never changed the state so there is nothing to undo
+    let actions = tracker.reconcile(vec![input(0, ACCEPTED, "0x00"), input(1, REJECTED, "0x01")]);
+    assert!(actions.is_empty());
+    let actions = tracker.reconcile(vec![input(0, ACCEPTED, "0x00"), input(1, ACCEPTED, "0x01")]);
+    assert_eq!(processed(&actions), vec![1]);
+}
+
+#[test]
+fn test_only_inputs_in_the_window_are_checked() {
+    use CompletionStatus::*;
+    let mut tracker = InputTracker::new(2);
+    tracker.reconcile((0..5).map(|i| input(i, ACCEPTED, "0x00")).collect());
+
+    // queries start after the last input to leave the window
+    let query = tracker.next_query(10);
+    assert_eq!(query.after, Some("cursor-2".to_string()));
+    assert_eq!(query.first, 12);
+
+    let actions = tracker.reconcile(vec![input(3, ACCEPTED, "0x00"), input(4, ACCEPTED, "0xff")]);
+    assert_eq!(
+        actions,
+        vec![
+            Action::Rollback { input_index: 4 },
+            Action::Process(input(4, ACCEPTED, "0xff").node),
+        ]
+    );
+}
@@ -0,0 +1,91 @@
+//! Tracking of the inputs seen by [`crate::listen_graphql`].
+//!
+//! The node can revert inputs it has already reported, for example when the base layer reorganises and
+//! the InputBox holds different inputs, or re-execute them from an earlier epoch. The inputs within a window
+//! of the most recent ones are therefore queried again on every poll and compared to what was seen before.
+//! If any of them changed, the service is told to roll back to before the first one that did and the
+//! inputs are processed again from there.
+
+use std::collections::VecDeque;
+
+use crate::inputs_query::{
+    self, CompletionStatus, InputsQueryInputsEdges, InputsQueryInputsEdgesNode,
+};
+
+/// What the listener must do with the result of a query
+#[derive(Debug, PartialEq)]
+pub(crate) enum Action {
+    /// Restore the state from before the input with this index
+    Rollback { input_index: usize },
+    /// Pass a newly accepted input to the service
+    Process(InputsQueryInputsEdgesNode),
+}
+
+pub(crate) struct InputTracker {
+    window: usize,
+    // cursor of the last input to leave the window. Queries start after it
+    base_cursor: Option<String>,
+    // inputs the node has finished processing, oldest first, with their cursors
+    seen: VecDeque<(String, InputsQueryInputsEdgesNode)>,
+}
+
+impl InputTracker {
+    /// Track inputs so that any of the most recent `window` can be detected as reverted
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            base_cursor: None,
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// Query every input in the window and up to `page_size` new ones
+    pub fn next_query(&self, page_size: usize) -> inputs_query::Variables {
+        inputs_query::Variables {
+            first: (self.seen.len() + page_size) as i64,
+            after: self.base_cursor.clone(),
+        }
+    }
+
+    /// Compare the inputs returned by the query from [`InputTracker::next_query`] with those seen before
+    /// and return what the listener must do, in order
+    pub fn reconcile(&mut self, edges: Vec<InputsQueryInputsEdges>) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let mut edges = edges.into_iter().peekable();
+
+        // inputs that were seen before must be returned again unchanged
+        let unchanged = self
+            .seen
+            .iter()
+            .take_while(|(_, seen)| edges.next_if(|edge| edge.node == *seen).is_some())
+            .count();
+        if unchanged < self.seen.len() {
+            let reverted = self.seen.split_off(unchanged);
+            // rejected inputs never changed the state so there is nothing to undo unless one was accepted
+            if reverted
+                .iter()
+                .any(|(_, input)| input.status == CompletionStatus::ACCEPTED)
+            {
+                actions.push(Action::Rollback {
+                    input_index: reverted[0].1.index as usize,
+                });
+            }
+        }
+
+        for edge in edges {
+            // later inputs can't be processed until the node has decided on this one
+            if edge.node.status == CompletionStatus::UNPROCESSED {
+                break;
+            }
+            if edge.node.status == CompletionStatus::ACCEPTED {
+                actions.push(Action::Process(edge.node.clone()));
+            }
+            self.seen.push_back((edge.cursor, edge.node));
+        }
+
+        while self.seen.len() > self.window {
+            self.base_cursor = self.seen.pop_front().map(|(cursor, _)| cursor);
+        }
+        actions
+    }
+}